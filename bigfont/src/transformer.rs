@@ -1,33 +1,57 @@
+use std::sync::LazyLock;
+
 const FONT: &[u8] = include_bytes!("../Puffy.flf");
-pub(crate) fn letter_text(input: &str) -> Result<String, String> {
-    let font = figfont::FIGfont::read_from(FONT).map_err(|_| "Failed to read font".to_string())?;
-    let output = input
-        .chars()
-        .map(|c| c as i32)
-        .map(|c| {
-            let character = font.get(c);
-            let lines = character.lines();
-            let string_lines = lines
-                .iter()
-                .map(|line| {
-                    line.iter()
-                        .map(|c| c.to_string())
-                        .collect::<Vec<_>>()
-                        .join("")
-                })
-                .collect::<Vec<_>>();
-            string_lines
-        })
-        .fold(Vec::new(), |output, char_lines| {
-            let mut output = output;
-            for (i, line) in char_lines.iter().enumerate() {
-                if i >= output.len() {
-                    output.push(String::new());
-                }
-                output[i].push_str(&line);
+/// Parsed once, in `init` or on first use. Parsing the whole font on every call was most of the work of a call.
+pub(crate) static PARSED_FONT: LazyLock<Option<figfont::FIGfont>> =
+    LazyLock::new(|| figfont::FIGfont::read_from(FONT).ok());
+
+/// Render the input in big letters.
+///
+/// If `max_width` is set, the letters are wrapped into multiple rows so that no line is wider than `max_width` columns.
+/// A single letter that is wider than `max_width` still gets its own row.
+pub(crate) fn letter_text(input: &str, max_width: Option<usize>) -> Result<String, String> {
+    let font = PARSED_FONT
+        .as_ref()
+        .ok_or_else(|| "Failed to read font".to_string())?;
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    let mut row_width = 0;
+    for c in input.chars() {
+        let character = font.get(c as i32);
+        let char_lines = character
+            .lines()
+            .iter()
+            .map(|line| {
+                line.iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<_>>()
+                    .join("")
+            })
+            .collect::<Vec<_>>();
+        let char_width = char_lines
+            .first()
+            .map(|line| line.chars().count())
+            .unwrap_or(0);
+
+        let row_is_full =
+            max_width.is_some_and(|max_width| row_width > 0 && row_width + char_width > max_width);
+        if rows.is_empty() || row_is_full {
+            rows.push(Vec::new());
+            row_width = 0;
+        }
+        let output = rows.last_mut().unwrap();
+        for (i, line) in char_lines.iter().enumerate() {
+            if i >= output.len() {
+                output.push(String::new());
             }
-            output
-        })
+            output[i].push_str(line);
+        }
+        row_width += char_width;
+    }
+
+    let output = rows
+        .into_iter()
+        .map(|row| row.join("\n"))
+        .collect::<Vec<_>>()
         .join("\n");
 
     Ok(output)
@@ -39,10 +63,17 @@ mod tests {
 
     #[test]
     fn formats_hello_world() {
-        let result = letter_text("Hello, world!").unwrap();
+        let result = letter_text("Hello, world!", None).unwrap();
         assert_eq!(
             result,
             " _   _         _    _                                         _        _  _ \n( ) ( )       (_ ) (_ )                                      (_ )     ( )( )\n| |_| |   __   | |  | |    _          _   _   _    _    _ __  | |    _| || |\n|  _  | /'__`\\ | |  | |  /'_`\\       ( ) ( ) ( ) /'_`\\ ( '__) | |  /'_` || |\n| | | |(  ___/ | |  | | ( (_) ) _    | \\_/ \\_/ |( (_) )| |    | | ( (_| || |\n(_) (_)`\\____)(___)(___)`\\___/'( )   `\\___x___/'`\\___/'(_)   (___)`\\__,_)(_)\n                               |/                                        (_)\n                                                                            "
         );
     }
+
+    #[test]
+    fn wraps_at_the_maximum_width() {
+        let result = letter_text("Hello, world!", Some(30)).unwrap();
+        assert!(result.lines().all(|line| line.chars().count() <= 30));
+        assert!(result.lines().count() > letter_text("H", None).unwrap().lines().count());
+    }
 }
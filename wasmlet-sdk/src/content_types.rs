@@ -0,0 +1,31 @@
+/// Declare the content types the plugin accepts and produces in the `wasmlet-content-types` custom section. Invoke it once per plugin.
+///
+/// The host checks that every stage of a pipeline accepts what the stage before it produces, before any text is processed. Common types are `text/plain`, `text/x-ansi` for text with escape codes and `application/json`. Accepted types can be patterns like `text/*`, and plugins that accept `text/x-ansi` also get `text/plain`.
+///
+/// ```
+/// wasmlet_sdk::content_types!(accepts ["text/plain"], produces "text/x-ansi");
+/// ```
+#[macro_export]
+macro_rules! content_types {
+    (accepts [$($accepts:literal),+ $(,)?], produces $produces:literal) => {
+        const WASMLET_CONTENT_TYPES_TEXT: &str =
+            concat!("accepts=", $($accepts, ",",)+ "\nproduces=", $produces, "\n");
+        #[used]
+        #[cfg_attr(target_arch = "wasm32", unsafe(link_section = "wasmlet-content-types"))]
+        static WASMLET_CONTENT_TYPES: [u8; WASMLET_CONTENT_TYPES_TEXT.len()] =
+            $crate::metadata_bytes(WASMLET_CONTENT_TYPES_TEXT);
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    crate::content_types!(accepts ["text/plain", "text/x-ansi"], produces "text/x-ansi");
+
+    #[test]
+    fn embeds_the_content_types() {
+        assert_eq!(
+            std::str::from_utf8(&WASMLET_CONTENT_TYPES).unwrap(),
+            "accepts=text/plain,text/x-ansi,\nproduces=text/x-ansi\n"
+        );
+    }
+}
@@ -0,0 +1,79 @@
+//! # wasmlet-sdk
+//!
+//! Helpers for writing WASMlet plugins.
+//!
+//! ## Transformers
+//!
+//! Implement [`Transformer`] for your plugin and invoke [`export_transformer!`] once. It exports the functions of the WASMlet ABI and manages the shared buffers:
+//!
+//! ```
+//! struct Shout;
+//!
+//! impl wasmlet_sdk::Transformer for Shout {
+//!     fn transform(&self, input: &str) -> Result<String, String> {
+//!         Ok(input.to_uppercase())
+//!     }
+//! }
+//!
+//! wasmlet_sdk::export_transformer!(Shout);
+//! ```
+//!
+//! The exports work the same when the plugin is compiled natively, so `wasmlet test --differential` can compare the native and the wasm build.
+//!
+//! A plugin can offer more transforms with [`export_transform!`]. The host selects them by name, like `effects#gradient`.
+//!
+//! Plugins that work with bytes instead of text, like one that renders a banner to an image, implement [`BinaryTransformer`] and invoke [`export_binary_transformer!`] instead. They declare what they produce with [`content_types!`], so the host passes the output on without decoding it.
+//!
+//! ## Lifecycle
+//!
+//! Setup that should only happen once, like parsing a font, belongs into [`export_init!`]. The host calls it after instantiating the plugin, and `wasmlet preinit` can run it ahead of time. Host information like the [`terminal`] is available from then on.
+//!
+//! [`export_configure!`] receives the options of the plugin after `init` and can reject them before any text is processed. [`export_shutdown!`] runs before the host drops the plugin.
+//!
+//! ## Logging
+//!
+//! Call [`init_logger`] once and use the `log` macros reexported by this crate. The messages are forwarded to the host, which logs them under the target `wasmlet::plugin::<plugin name>`.
+//!
+//! ```
+//! wasmlet_sdk::init_logger();
+//! wasmlet_sdk::info!("Hello from the plugin");
+//! ```
+//!
+//! ## Panics
+//!
+//! Call [`install_panic_hook`] once to report panic messages and their location to the host. Without it, the host only sees an `unreachable` trap.
+//!
+//! ## Terminal
+//!
+//! [`terminal`] returns the size and color depth of the terminal, so plugins can adapt their output.
+//!
+//! ## Metadata
+//!
+//! Invoke [`metadata!`] once in your plugin to embed its name, version, description and ABI version. `wasmlet validate` warns about plugins without metadata.
+//!
+//! ## Content types
+//!
+//! Declare what the plugin accepts and produces with [`content_types!`], for example `text/plain` and `text/x-ansi` for a plugin that colors plain text. The host refuses to run pipelines where a stage gets content it does not accept.
+//!
+//! ## Options
+//!
+//! Declare every option that [`export_configure!`] understands with [`option!`]. The host checks options against the declarations before passing them to the plugin, and `wasmlet help <plugin>` shows them together with the description from the metadata.
+
+mod content_types;
+mod logging;
+mod metadata;
+mod options;
+mod panic;
+mod terminal;
+mod transformer;
+
+pub use log;
+pub use log::{debug, error, info, trace, warn};
+pub use logging::init_logger;
+pub use metadata::{ABI_VERSION, metadata_bytes};
+pub use options::is_option_type;
+pub use panic::install_panic_hook;
+pub use terminal::{ColorDepth, Terminal, terminal};
+#[doc(hidden)]
+pub use transformer::abi;
+pub use transformer::{BinaryTransformer, Transformer};
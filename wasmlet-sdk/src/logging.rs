@@ -0,0 +1,49 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+#[cfg(target_arch = "wasm32")]
+#[link(wasm_import_module = "wasmlet")]
+unsafe extern "C" {
+    /// Log a utf8 message with the given level. The levels are numbered like [`log::Level`], starting with 1 for errors.
+    #[link_name = "log"]
+    fn host_log(level: u32, message: *const u8, length: usize);
+}
+
+/// Send a log message to the host.
+#[cfg(target_arch = "wasm32")]
+fn log_to_host(level: Level, message: &str) {
+    // SAFETY: The host only reads `length` bytes from `message`.
+    unsafe { host_log(level as u32, message.as_ptr(), message.len()) }
+}
+
+/// There is no host when the plugin is compiled natively (for example in tests), so we print to stderr instead.
+#[cfg(not(target_arch = "wasm32"))]
+fn log_to_host(level: Level, message: &str) {
+    eprintln!("[{level}] {message}");
+}
+
+/// A logger that forwards all records to the host.
+struct HostLogger;
+
+impl Log for HostLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        // The host decides which messages are shown
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        log_to_host(record.level(), &record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: HostLogger = HostLogger;
+
+/// Forward messages from the `log` macros to the host.
+///
+/// Can be called multiple times, only the first call has an effect.
+pub fn init_logger() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Trace);
+    }
+}
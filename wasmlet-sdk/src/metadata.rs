@@ -0,0 +1,56 @@
+/// The version of the WASMlet ABI implemented by plugins built with this SDK.
+pub const ABI_VERSION: u32 = 1;
+
+/// Embed the metadata of the plugin in the `wasmlet-metadata` custom section.
+///
+/// The section contains `key=value` lines with the ABI version and the name, version and description of the crate that invokes the macro. `wasmlet validate` checks that it is present.
+///
+/// ```
+/// wasmlet_sdk::metadata!();
+/// ```
+#[macro_export]
+macro_rules! metadata {
+    () => {
+        const WASMLET_METADATA_TEXT: &str = concat!(
+            // Needs to be a literal for `concat!`, keep it in sync with `ABI_VERSION`
+            "abi=1\n",
+            "name=",
+            env!("CARGO_PKG_NAME"),
+            "\nversion=",
+            env!("CARGO_PKG_VERSION"),
+            "\ndescription=",
+            env!("CARGO_PKG_DESCRIPTION"),
+            "\n"
+        );
+        #[used]
+        #[cfg_attr(target_arch = "wasm32", unsafe(link_section = "wasmlet-metadata"))]
+        static WASMLET_METADATA: [u8; WASMLET_METADATA_TEXT.len()] =
+            $crate::metadata_bytes(WASMLET_METADATA_TEXT);
+    };
+}
+
+/// Copy a string into an array at compile time. Used by [`metadata!`].
+#[doc(hidden)]
+pub const fn metadata_bytes<const N: usize>(text: &str) -> [u8; N] {
+    let bytes = text.as_bytes();
+    let mut result = [0; N];
+    let mut index = 0;
+    while index < N {
+        result[index] = bytes[index];
+        index += 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    crate::metadata!();
+
+    #[test]
+    fn embeds_the_crate_metadata() {
+        let text = std::str::from_utf8(&WASMLET_METADATA).unwrap();
+        assert!(text.starts_with(&format!("abi={}\n", super::ABI_VERSION)));
+        assert!(text.contains("name=wasmlet-sdk\n"));
+        assert!(text.contains(&format!("version={}\n", env!("CARGO_PKG_VERSION"))));
+    }
+}
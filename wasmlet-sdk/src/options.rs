@@ -0,0 +1,40 @@
+/// Declare an option of the plugin in the `wasmlet-options` custom section.
+///
+/// The host checks the options against the declarations before it calls `configure`, so the plugin does not need to report typos or values of the wrong type itself. `wasmlet help <plugin>` lists them.
+///
+/// The name is followed by the type, which is `string`, `integer`, `number` or `boolean`. After that come an optional default after a `=`, the allowed values after `in` and a short help text:
+///
+/// ```
+/// wasmlet_sdk::option!("width": integer = "80", "The width of the banner");
+/// wasmlet_sdk::option!("color": string = "red" in ["red", "green", "blue"], "The color of the text");
+/// wasmlet_sdk::option!("shadow": boolean, "Draw a shadow below the text");
+/// ```
+#[macro_export]
+macro_rules! option {
+    ($name:literal: $type:ident $(= $default:literal)? $(in [$($value:literal),+ $(,)?])?, $help:literal) => {
+        const _: () = {
+            assert!(
+                $crate::is_option_type(stringify!($type)),
+                "The type of an option needs to be `string`, `integer`, `number` or `boolean`"
+            );
+            const TEXT: &str = concat!(
+                $name, ".type=", stringify!($type), "\n",
+                $($name, ".default=", $default, "\n",)?
+                $($name, ".values=", $($value, ",",)+ "\n",)?
+                $name, ".help=", $help, "\n",
+            );
+            #[used]
+            #[cfg_attr(target_arch = "wasm32", unsafe(link_section = "wasmlet-options"))]
+            static OPTION: [u8; TEXT.len()] = $crate::metadata_bytes(TEXT);
+        };
+    };
+}
+
+/// Whether the host knows the type. Used by [`option!`].
+#[doc(hidden)]
+pub const fn is_option_type(name: &str) -> bool {
+    matches!(
+        name.as_bytes(),
+        b"string" | b"integer" | b"number" | b"boolean"
+    )
+}
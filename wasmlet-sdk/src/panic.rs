@@ -0,0 +1,67 @@
+use std::panic::PanicHookInfo;
+
+#[cfg(target_arch = "wasm32")]
+#[link(wasm_import_module = "wasmlet")]
+unsafe extern "C" {
+    /// Report a panic message to the host before the guest traps.
+    #[link_name = "panic"]
+    fn host_panic(message: *const u8, length: usize);
+}
+
+#[cfg(target_arch = "wasm32")]
+fn report_panic(message: &str) {
+    // SAFETY: The host only reads `length` bytes from `message`.
+    unsafe { host_panic(message.as_ptr(), message.len()) }
+}
+
+/// There is no host when the plugin is compiled natively, so we keep the default hook.
+#[cfg(not(target_arch = "wasm32"))]
+fn report_panic(_message: &str) {}
+
+/// Format a panic like the default hook does, e.g. `something went wrong at src/lib.rs:3:5`.
+fn panic_message(info: &PanicHookInfo) -> String {
+    let payload = info
+        .payload()
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| info.payload().downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>");
+    match info.location() {
+        Some(location) => format!("{payload} at {location}"),
+        None => payload.to_string(),
+    }
+}
+
+/// Report panics to the host, so it can show the panic message instead of just an `unreachable` trap.
+///
+/// Can be called multiple times, only the first call has an effect.
+pub fn install_panic_hook() {
+    static INSTALLED: std::sync::Once = std::sync::Once::new();
+    INSTALLED.call_once(|| {
+        if cfg!(target_arch = "wasm32") {
+            std::panic::set_hook(Box::new(|info| report_panic(&panic_message(info))));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    #[test]
+    fn formats_message_and_location() {
+        static MESSAGE: Mutex<String> = Mutex::new(String::new());
+        let previous_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(|info| {
+            *MESSAGE.lock().unwrap() = panic_message(info);
+        }));
+        let result = std::panic::catch_unwind(|| panic!("oh no {}", 42));
+        std::panic::set_hook(previous_hook);
+
+        assert!(result.is_err());
+        let message = MESSAGE.lock().unwrap();
+        assert!(message.starts_with("oh no 42 at src/panic.rs:"), "{message}");
+    }
+}
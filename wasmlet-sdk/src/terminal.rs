@@ -0,0 +1,84 @@
+/// How many colors the terminal can display.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ColorDepth {
+    /// No colors at all, escape codes should not be used.
+    None,
+    /// The 16 basic ANSI colors.
+    Ansi16,
+    /// The 256 color palette.
+    Ansi256,
+    /// 24 bit colors.
+    TrueColor,
+}
+
+/// What the host knows about the terminal the output is written to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Terminal {
+    /// Width in columns, if known.
+    pub width: Option<u32>,
+    /// Height in rows, if known.
+    pub height: Option<u32>,
+    pub color_depth: ColorDepth,
+    /// Whether the output is written to a terminal.
+    pub is_tty: bool,
+}
+
+#[cfg(target_arch = "wasm32")]
+#[link(wasm_import_module = "wasmlet")]
+unsafe extern "C" {
+    fn terminal_width() -> u32;
+    fn terminal_height() -> u32;
+    fn terminal_color_depth() -> u32;
+    fn terminal_is_tty() -> u32;
+}
+
+impl Terminal {
+    /// Decode the numbers the host uses to describe the terminal.
+    pub(crate) fn from_host(width: u32, height: u32, color_depth: u32, is_tty: u32) -> Self {
+        Terminal {
+            width: (width != 0).then_some(width),
+            height: (height != 0).then_some(height),
+            color_depth: match color_depth {
+                0 => ColorDepth::None,
+                1 => ColorDepth::Ansi16,
+                2 => ColorDepth::Ansi256,
+                _ => ColorDepth::TrueColor,
+            },
+            is_tty: is_tty != 0,
+        }
+    }
+}
+
+/// Ask the host about the terminal.
+#[cfg(target_arch = "wasm32")]
+pub fn terminal() -> Terminal {
+    // SAFETY: The host functions take no arguments and only return numbers.
+    unsafe {
+        Terminal::from_host(
+            terminal_width(),
+            terminal_height(),
+            terminal_color_depth(),
+            terminal_is_tty(),
+        )
+    }
+}
+
+/// The terminal a host of a native build told us about with `wasmlet_set_terminal`.
+#[cfg(not(target_arch = "wasm32"))]
+static NATIVE_TERMINAL: std::sync::Mutex<Option<Terminal>> = std::sync::Mutex::new(None);
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn set_native_terminal(terminal: Terminal) {
+    *NATIVE_TERMINAL.lock().unwrap() = Some(terminal);
+}
+
+/// There is no host when the plugin is compiled natively, so nothing is known about the terminal unless the host of a native build set it.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn terminal() -> Terminal {
+    NATIVE_TERMINAL.lock().unwrap().unwrap_or(Terminal {
+        width: None,
+        height: None,
+        color_depth: ColorDepth::Ansi16,
+        is_tty: false,
+    })
+}
@@ -0,0 +1,476 @@
+use std::collections::HashMap;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::{Arc, LazyLock, Mutex};
+
+/// The text transformation a plugin implements.
+///
+/// [`export_transformer!`](crate::export_transformer) exports the functions of the WASMlet ABI for it. Because the trait is plain Rust, the same transformer can also be used natively, for example in tests or by `wasmlet test --differential`.
+pub trait Transformer {
+    /// Transform the input. The error is reported to the host as the message of a failed result.
+    fn transform(&self, input: &str) -> Result<String, String>;
+}
+
+/// A transformation of bytes, for plugins that take or produce content other than text, like images or compressed data.
+///
+/// [`export_binary_transformer!`](crate::export_binary_transformer) exports it. Declare what the plugin produces with [`content_types!`](crate::content_types), the host treats the output as text otherwise.
+pub trait BinaryTransformer {
+    /// Transform the input. The error is reported to the host as the message of a failed result.
+    fn transform(&self, input: &[u8]) -> Result<Vec<u8>, String>;
+}
+
+/// Export `allocate_shared_buffer`, `free_shared_buffer` and `process` for a [`Transformer`].
+///
+/// The exports use the same ABI when the plugin is compiled natively as a `cdylib`, so the host can load both builds.
+///
+/// ```
+/// struct Shout;
+///
+/// impl wasmlet_sdk::Transformer for Shout {
+///     fn transform(&self, input: &str) -> Result<String, String> {
+///         Ok(input.to_uppercase())
+///     }
+/// }
+///
+/// wasmlet_sdk::export_transformer!(Shout);
+/// ```
+#[macro_export]
+macro_rules! export_transformer {
+    ($transformer:expr) => {
+        $crate::export_buffer_functions!();
+
+        /// Transform the text in the input buffer and return a result buffer.
+        #[unsafe(no_mangle)]
+        pub extern "C" fn process(input_buffer: usize) -> usize {
+            $crate::abi::process(input_buffer, &$transformer)
+        }
+    };
+}
+
+/// Export `allocate_shared_buffer`, `free_shared_buffer` and `process` for a [`BinaryTransformer`].
+///
+/// The input is passed on as it is, it does not need to be utf8.
+///
+/// ```
+/// struct Compress;
+///
+/// impl wasmlet_sdk::BinaryTransformer for Compress {
+///     fn transform(&self, input: &[u8]) -> Result<Vec<u8>, String> {
+///         let mut output = Vec::new();
+///         for run in input.chunk_by(|a, b| a == b) {
+///             for chunk in run.chunks(255) {
+///                 output.extend([chunk.len() as u8, chunk[0]]);
+///             }
+///         }
+///         Ok(output)
+///     }
+/// }
+///
+/// wasmlet_sdk::content_types!(accepts ["*/*"], produces "application/x-run-length");
+/// wasmlet_sdk::export_binary_transformer!(Compress);
+/// ```
+#[macro_export]
+macro_rules! export_binary_transformer {
+    ($transformer:expr) => {
+        $crate::export_buffer_functions!();
+
+        /// Transform the bytes in the input buffer and return a result buffer.
+        #[unsafe(no_mangle)]
+        pub extern "C" fn process(input_buffer: usize) -> usize {
+            $crate::abi::process_binary(input_buffer, &$transformer)
+        }
+    };
+}
+
+/// Export the functions that every plugin needs, no matter what its `process` takes.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! export_buffer_functions {
+    () => {
+        /// Get a buffer that can be written to. It needs to be freed with `free_shared_buffer`.
+        #[unsafe(no_mangle)]
+        pub extern "C" fn allocate_shared_buffer(size: usize) -> usize {
+            $crate::abi::allocate_shared_buffer(size)
+        }
+
+        /// Free a buffer that was allocated with `allocate_shared_buffer` or returned by `process`.
+        #[unsafe(no_mangle)]
+        pub extern "C" fn free_shared_buffer(pointer: usize) -> bool {
+            $crate::abi::free_shared_buffer(pointer)
+        }
+
+        /// Tell a native build about the terminal. A wasm build asks the host instead.
+        #[cfg(not(target_arch = "wasm32"))]
+        #[unsafe(no_mangle)]
+        pub extern "C" fn wasmlet_set_terminal(
+            width: u32,
+            height: u32,
+            color_depth: u32,
+            is_tty: u32,
+        ) {
+            $crate::abi::set_terminal(width, height, color_depth, is_tty)
+        }
+    };
+}
+
+/// Export another transform of the plugin as `process_<name>` and list it with its description.
+///
+/// The host selects it with `#name` after the specifier, for example `wasmlet -p effects#gradient`. The buffer functions and the primary transform still come from [`export_transformer!`](crate::export_transformer). Without a primary transform, the host only picks a named one by default if it is the only one.
+///
+/// ```
+/// struct Shout;
+///
+/// impl wasmlet_sdk::Transformer for Shout {
+///     fn transform(&self, input: &str) -> Result<String, String> {
+///         Ok(input.to_uppercase())
+///     }
+/// }
+///
+/// struct Whisper;
+///
+/// impl wasmlet_sdk::Transformer for Whisper {
+///     fn transform(&self, input: &str) -> Result<String, String> {
+///         Ok(input.to_lowercase())
+///     }
+/// }
+///
+/// wasmlet_sdk::export_transformer!(Shout);
+/// wasmlet_sdk::export_transform!("whisper", "Turns the text into lowercase", Whisper);
+/// ```
+#[macro_export]
+macro_rules! export_transform {
+    ($name:literal, $description:literal, $transformer:expr) => {
+        const _: () = {
+            /// Transform the text in the input buffer and return a result buffer.
+            #[unsafe(export_name = concat!("process_", $name))]
+            pub extern "C" fn process(input_buffer: usize) -> usize {
+                $crate::abi::process(input_buffer, &$transformer)
+            }
+
+            const TEXT: &str = concat!($name, "=", $description, "\n");
+            #[used]
+            #[cfg_attr(target_arch = "wasm32", unsafe(link_section = "wasmlet-transforms"))]
+            static TRANSFORM: [u8; TEXT.len()] = $crate::metadata_bytes(TEXT);
+        };
+    };
+}
+
+/// Export `init`, which the host calls once after instantiating the plugin.
+///
+/// Use it for setup that every call would otherwise repeat, like parsing a font into a `LazyLock`. `wasmlet preinit` runs it at install time and stores the resulting memory in the module, so the work is not even repeated on load. The host does not know the terminal yet when preinitializing, so do not ask for it in `init`.
+///
+/// ```
+/// use std::sync::LazyLock;
+///
+/// static WORDS: LazyLock<Vec<&str>> = LazyLock::new(|| "a list of words".split(' ').collect());
+///
+/// wasmlet_sdk::export_init!(|| {
+///     LazyLock::force(&WORDS);
+/// });
+/// ```
+#[macro_export]
+macro_rules! export_init {
+    ($init:expr) => {
+        /// Prepare the plugin before the first call to `process`.
+        #[unsafe(no_mangle)]
+        pub extern "C" fn init() {
+            $crate::abi::init($init)
+        }
+    };
+}
+
+/// Export `configure`, which the host calls after `init` with the options of the plugin.
+///
+/// The options are `key=value` pairs. Return an error to reject them, the host then reports it before any text is processed. The host also calls `configure` without any options, so the plugin can check that it works with its defaults.
+///
+/// ```
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+///
+/// static WIDTH: AtomicUsize = AtomicUsize::new(80);
+///
+/// wasmlet_sdk::export_configure!(|options| {
+///     for (key, value) in options {
+///         match *key {
+///             "width" => {
+///                 let width = value.parse().map_err(|_| "`width` needs to be a number")?;
+///                 WIDTH.store(width, Ordering::Relaxed);
+///             }
+///             _ => return Err(format!("Unknown option `{key}`")),
+///         }
+///     }
+///     Ok(())
+/// });
+/// ```
+#[macro_export]
+macro_rules! export_configure {
+    ($configure:expr) => {
+        /// Check and apply the options in the buffer. Returns a result buffer, like `process`.
+        #[unsafe(no_mangle)]
+        pub extern "C" fn configure(options_buffer: usize) -> usize {
+            $crate::abi::configure(options_buffer, $configure)
+        }
+    };
+}
+
+/// Export `shutdown`, which the host calls before it drops an instance of the plugin.
+///
+/// ```
+/// wasmlet_sdk::export_shutdown!(|| wasmlet_sdk::debug!("Goodbye"));
+/// ```
+#[macro_export]
+macro_rules! export_shutdown {
+    ($shutdown:expr) => {
+        /// Clean up before the host drops the instance.
+        #[unsafe(no_mangle)]
+        pub extern "C" fn shutdown() {
+            $crate::abi::shutdown($shutdown)
+        }
+    };
+}
+
+/// The implementation of the functions exported by [`export_transformer!`](crate::export_transformer), [`export_binary_transformer!`](crate::export_binary_transformer) and the lifecycle macros.
+#[doc(hidden)]
+pub mod abi {
+    use super::*;
+
+    /// A shared buffer that can be accessed by the host.
+    ///
+    /// The guest holds a clone of the rc while using the buffer to make sure that the host does not free the buffer while the guest is still using it.
+    type SharedBuffer = Arc<Box<[u8]>>;
+    /// Keeps track of all shared buffers.
+    static SHARED_BUFFERS: LazyLock<Mutex<HashMap<usize, SharedBuffer>>> =
+        LazyLock::new(|| Mutex::new(HashMap::new()));
+
+    /// Get a buffer that can be written to.
+    ///
+    /// The buffer needs to be freed with `free_shared_buffer`. Will crash if the guest has no more memory available.
+    pub fn allocate_shared_buffer(size: usize) -> usize {
+        share_buffer(vec![0; size].into_boxed_slice()) as usize
+    }
+
+    /// Make a buffer available to the host. Returns the memory address of the buffer.
+    fn share_buffer(buffer: Box<[u8]>) -> *const u8 {
+        let buffer_in_arc = Arc::new(buffer);
+        let address = buffer_in_arc.as_ptr();
+        SHARED_BUFFERS
+            .lock()
+            .unwrap()
+            .insert(address as usize, buffer_in_arc);
+        address
+    }
+
+    /// Free a buffer that was allocated with `allocate_shared_buffer`.
+    ///
+    /// - Returns false if the buffer is not currently allocated or an error occurred.
+    /// - Returns true if the buffer was successfully freed.
+    ///
+    /// If the guest is currently using the buffer, it will also return true, but the buffer will be freed once the guest is done with it.
+    pub fn free_shared_buffer(pointer: usize) -> bool {
+        let buffer = SHARED_BUFFERS.lock().unwrap().remove(&{ pointer });
+        buffer.is_some()
+    }
+
+    /// Set up logging and panics, then run the initialization of the plugin.
+    pub fn init(init: impl FnOnce()) {
+        crate::init_logger();
+        crate::install_panic_hook();
+        init();
+    }
+
+    /// Parse the options in the buffer and pass them to the function. Returns a result buffer with an empty text or the error.
+    pub fn configure(
+        options_buffer: usize,
+        configure: impl FnOnce(&[(&str, &str)]) -> Result<(), String>,
+    ) -> usize {
+        call(options_buffer, |options| {
+            let options: Vec<_> = options
+                .lines()
+                .filter_map(|line| line.split_once('='))
+                .collect();
+            crate::debug!("Configuring with {} options", options.len());
+            configure(&options).map(|()| String::new())
+        })
+    }
+
+    pub fn shutdown(shutdown: impl FnOnce()) {
+        shutdown();
+    }
+
+    /// Process the input buffer and return a new buffer.
+    pub fn process(input_buffer: usize, transformer: &impl Transformer) -> usize {
+        call(input_buffer, |input| {
+            crate::debug!("Processing {} bytes of input", input.len());
+            transformer.transform(input)
+        })
+    }
+
+    /// Process the bytes in the input buffer and return a new buffer.
+    pub fn process_binary(input_buffer: usize, transformer: &impl BinaryTransformer) -> usize {
+        call_bytes(input_buffer, |input| {
+            crate::debug!("Processing {} bytes of input", input.len());
+            transformer.transform(input)
+        })
+    }
+
+    /// Run the function on the text in the input buffer and return a new buffer, like [`call_bytes`].
+    fn call(input_buffer: usize, function: impl FnOnce(&str) -> Result<String, String>) -> usize {
+        call_bytes(input_buffer, |input| {
+            let input = std::str::from_utf8(input).map_err(|e| e.to_string())?;
+            function(input).map(String::into_bytes)
+        })
+    }
+
+    /// Run the function on the input buffer and return a new buffer.
+    /// The new buffer needs to be freed with `free_shared_buffer`.
+    ///
+    /// The first byte of the returned buffer is a boolean indicating whether the operation was successful.
+    /// If false, the content is an error message, otherwise it is the result.
+    /// The next 4 bytes are the length of the content as a little endian `u32`, on every target.
+    /// Then the content follows.
+    fn call_bytes(
+        input_buffer: usize,
+        function: impl FnOnce(&[u8]) -> Result<Vec<u8>, String>,
+    ) -> usize {
+        crate::init_logger();
+        crate::install_panic_hook();
+
+        // Panics abort wasm builds, so they are only caught in native builds
+        let result = catch_unwind(AssertUnwindSafe(|| call_to_result(input_buffer, function)))
+            .unwrap_or_else(|_| Err("The plugin panicked".to_string()));
+        let (success, output) = match result {
+            Ok(output) if u32::try_from(output.len()).is_err() => {
+                (false, b"The output is larger than 4GiB".to_vec())
+            }
+            Ok(output) => (true, output),
+            Err(error) => (false, error.into_bytes()),
+        };
+
+        let mut return_bytes = Vec::<u8>::with_capacity(output.len() + size_of::<u32>() + 1);
+        return_bytes.push(success as u8);
+        return_bytes.extend_from_slice(&(output.len() as u32).to_le_bytes());
+        return_bytes.extend_from_slice(&output);
+
+        share_buffer(return_bytes.into_boxed_slice()) as usize
+    }
+
+    /// Look up the input buffer and return the result with String as the error type.
+    fn call_to_result(
+        input_buffer: usize,
+        function: impl FnOnce(&[u8]) -> Result<Vec<u8>, String>,
+    ) -> Result<Vec<u8>, String> {
+        let input = SHARED_BUFFERS
+            .lock()
+            .map_err(|e| e.to_string())?
+            .get(&{ input_buffer })
+            .ok_or(
+                "The input buffer does not exist. Use `allocate_shared_buffer` to allocate a buffer.",
+            )?
+            .clone();
+
+        function(&input)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn set_terminal(width: u32, height: u32, color_depth: u32, is_tty: u32) {
+        crate::terminal::set_native_terminal(crate::terminal::Terminal::from_host(
+            width,
+            height,
+            color_depth,
+            is_tty,
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::abi::*;
+    use super::*;
+
+    struct Shout;
+
+    impl Transformer for Shout {
+        fn transform(&self, input: &str) -> Result<String, String> {
+            match input {
+                "" => Err("Nothing to shout".to_string()),
+                _ => Ok(input.to_uppercase()),
+            }
+        }
+    }
+
+    struct Reverse;
+
+    impl BinaryTransformer for Reverse {
+        fn transform(&self, input: &[u8]) -> Result<Vec<u8>, String> {
+            Ok(input.iter().rev().copied().collect())
+        }
+    }
+
+    /// Run the input through `process` like the host would and return the success flag and text.
+    fn call(input: &str) -> (bool, String) {
+        call_text(input, |buffer| process(buffer, &Shout))
+    }
+
+    /// Pass the text to the function like [`call_function`] and read the result as text.
+    fn call_text(input: &str, function: impl FnOnce(usize) -> usize) -> (bool, String) {
+        let (success, output) = call_function(input.as_bytes(), function);
+        (success, String::from_utf8(output).unwrap())
+    }
+
+    /// Pass the input in a shared buffer to the function and read the result buffer it returns.
+    fn call_function(input_bytes: &[u8], function: impl FnOnce(usize) -> usize) -> (bool, Vec<u8>) {
+        let shared_pointer = allocate_shared_buffer(input_bytes.len());
+        let shared_buffer =
+            unsafe { std::slice::from_raw_parts_mut(shared_pointer as *mut u8, input_bytes.len()) };
+        shared_buffer.copy_from_slice(input_bytes);
+
+        let result = function(shared_pointer) as *const u8;
+        let success = unsafe { *result } != 0;
+        let length = unsafe { *(result.add(1) as *const [u8; 4]) };
+        let length = u32::from_le_bytes(length) as usize;
+        let output = unsafe { std::slice::from_raw_parts(result.add(5), length) }.to_vec();
+
+        assert!(free_shared_buffer(shared_pointer));
+        assert!(free_shared_buffer(result as usize));
+        (success, output)
+    }
+
+    #[test]
+    fn how_the_host_would_use_this() {
+        assert_eq!(call("Hello"), (true, "HELLO".to_string()));
+        assert_eq!(call(""), (false, "Nothing to shout".to_string()));
+    }
+
+    #[test]
+    fn binary_transformers_take_any_bytes() {
+        let input = [0xff, 0x00, 0x89];
+        assert_eq!(
+            call_function(&input, |buffer| process_binary(buffer, &Reverse)),
+            (true, vec![0x89, 0x00, 0xff])
+        );
+        assert!(!call_function(&input, |buffer| process(buffer, &Shout)).0);
+    }
+
+    #[test]
+    fn configure_receives_the_options() {
+        let configure = |options: &str| {
+            call_text(options, |buffer| {
+                configure(buffer, |options| match options {
+                    [] | [("loud", "true")] => Ok(()),
+                    _ => Err(format!("Unknown options {options:?}")),
+                })
+            })
+        };
+        assert_eq!(configure(""), (true, String::new()));
+        assert_eq!(configure("loud=true\n"), (true, String::new()));
+        assert_eq!(
+            configure("quiet=yes\n"),
+            (false, r#"Unknown options [("quiet", "yes")]"#.to_string())
+        );
+    }
+
+    #[test]
+    fn buffers_can_only_be_freed_once() {
+        let pointer = allocate_shared_buffer(16);
+        assert!(free_shared_buffer(pointer));
+        assert!(!free_shared_buffer(pointer));
+    }
+}
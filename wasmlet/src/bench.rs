@@ -0,0 +1,374 @@
+//! `wasmlet bench`: Measure how long every stage of a pipeline takes.
+
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use clap::{CommandFactory, error::ErrorKind};
+use serde::Serialize;
+
+use crate::compile::Compiler;
+use crate::content_types::Payload;
+use crate::error::{PluginError, StageError};
+use crate::plugin::{PluginConfig, load_pipeline, plugin_name};
+use crate::pool::PluginPool;
+use crate::report::milliseconds;
+use crate::runtime::Runtime;
+use crate::terminal::TerminalInfo;
+use crate::{Args, OutputFormat};
+
+#[derive(clap::Args, Debug)]
+pub struct BenchArgs {
+    /// Text that is used as input if no input files are given
+    text: Vec<String>,
+
+    /// WASM plugins that form the pipeline
+    #[arg(short, long, required = true)]
+    plugins: Vec<String>,
+
+    /// Plugins of a second pipeline to compare against, for example an older build. Needs as many stages as the pipeline
+    #[arg(short, long)]
+    baseline: Vec<String>,
+
+    /// Files that are used as input. Every file is processed as one text
+    #[arg(short, long)]
+    input: Vec<PathBuf>,
+
+    /// Runs over all inputs that are not measured
+    #[arg(long, default_value_t = 3)]
+    warmup: usize,
+
+    /// Measured runs over all inputs
+    #[arg(short = 'n', long, default_value_t = 100)]
+    iterations: usize,
+
+    /// The compiler for the plugins. Compare compilers by running the benchmark with each of them
+    #[arg(long, value_enum)]
+    compiler: Option<Compiler>,
+
+    /// The wasm runtime that runs the plugins
+    #[arg(long, value_enum, default_value_t)]
+    runtime: Runtime,
+
+    /// Run the benchmark on this many threads at once. Every stage has a pool with an instance per thread
+    #[arg(long, default_value_t = NonZeroUsize::MIN)]
+    threads: NonZeroUsize,
+
+    /// Restore every instance to its freshly instantiated state before each call
+    #[arg(long)]
+    reset: bool,
+
+    /// The output format
+    #[arg(long, value_enum, default_value_t)]
+    format: OutputFormat,
+}
+
+/// Mean and percentiles of a set of samples.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Summary {
+    pub mean_ms: f64,
+    pub p50_ms: f64,
+    pub p99_ms: f64,
+}
+
+impl Summary {
+    pub fn new(samples: &mut [Duration]) -> Self {
+        if samples.is_empty() {
+            return Summary::default();
+        }
+        samples.sort();
+        let total: Duration = samples.iter().sum();
+        Summary {
+            mean_ms: milliseconds(total) / samples.len() as f64,
+            p50_ms: milliseconds(percentile(samples, 50)),
+            p99_ms: milliseconds(percentile(samples, 99)),
+        }
+    }
+}
+
+/// The nearest-rank percentile of sorted samples.
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    let rank = (sorted.len() * percent).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+#[derive(Serialize)]
+struct StageSummary {
+    index: usize,
+    specifier: String,
+    plugin: String,
+    #[serde(flatten)]
+    summary: Summary,
+}
+
+/// The timings of one pipeline.
+#[derive(Serialize)]
+struct PipelineSummary {
+    stages: Vec<StageSummary>,
+    /// The time the whole pipeline took for one input.
+    total: Summary,
+}
+
+#[derive(Serialize)]
+struct BenchReport {
+    inputs: usize,
+    warmup: usize,
+    iterations: usize,
+    threads: usize,
+    reset: bool,
+    pipeline: PipelineSummary,
+    baseline: Option<PipelineSummary>,
+}
+
+/// The samples of one thread.
+struct Samples {
+    stages: Vec<Vec<Duration>>,
+    total: Vec<Duration>,
+}
+
+/// Run all inputs through the pipeline. Errors come with the index of the stage that failed.
+fn collect_samples(
+    pools: &[PluginPool],
+    inputs: &[String],
+    args: &BenchArgs,
+) -> Result<Samples, (usize, PluginError)> {
+    let mut samples = Samples {
+        stages: vec![Vec::new(); pools.len()],
+        total: Vec::new(),
+    };
+    for iteration in 0..args.warmup + args.iterations {
+        for input in inputs {
+            let mut payload = Payload::text(input.clone());
+            let mut times = Vec::with_capacity(pools.len());
+            for (index, pool) in pools.iter().enumerate() {
+                let mut plugin = pool.get().map_err(|error| (index, error))?;
+                let start = Instant::now();
+                payload = plugin
+                    .apply_payload(&payload)
+                    .map_err(|error| (index, error))?;
+                times.push(start.elapsed());
+            }
+            if iteration < args.warmup {
+                continue;
+            }
+            samples.total.push(times.iter().sum());
+            for (stage, time) in samples.stages.iter_mut().zip(times) {
+                stage.push(time);
+            }
+        }
+    }
+    Ok(samples)
+}
+
+/// Load the pipeline and run all inputs through it on every thread.
+fn measure(
+    specifiers: &[String],
+    inputs: &[String],
+    args: &BenchArgs,
+) -> Result<PipelineSummary, StageError> {
+    let stage_error = |index: usize, error| StageError {
+        stage: index,
+        specifier: specifiers[index].clone(),
+        error,
+    };
+    // Plugins see the same terminal in every benchmark, no matter where it runs
+    let config = PluginConfig {
+        compiler: args.compiler.or(Compiler::default_compiler()),
+        runtime: args.runtime,
+        reset: args.reset,
+        ..PluginConfig::new(TerminalInfo::STANDARD)
+    };
+    // The pools instantiate right away, so the first call is not slower than the others
+    let pools = load_pipeline(specifiers, &config)
+        .into_iter()
+        .enumerate()
+        .map(|(index, plugin)| {
+            plugin
+                .and_then(|plugin| PluginPool::new(&plugin, args.threads.get()))
+                .map_err(|error| stage_error(index, error))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let threads = std::thread::scope(|scope| {
+        let threads: Vec<_> = (0..args.threads.get())
+            .map(|_| scope.spawn(|| collect_samples(&pools, inputs, args)))
+            .collect();
+        threads
+            .into_iter()
+            .map(|thread| thread.join().expect("Benchmarks do not panic"))
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .map_err(|(index, error)| stage_error(index, error))?;
+
+    let mut stage_samples = vec![Vec::new(); pools.len()];
+    let mut total_samples = Vec::new();
+    for samples in threads {
+        total_samples.extend(samples.total);
+        for (stage, thread_samples) in stage_samples.iter_mut().zip(samples.stages) {
+            stage.extend(thread_samples);
+        }
+    }
+
+    Ok(PipelineSummary {
+        stages: stage_samples
+            .iter_mut()
+            .enumerate()
+            .map(|(index, samples)| StageSummary {
+                index,
+                specifier: specifiers[index].clone(),
+                plugin: plugin_name(&specifiers[index]).to_string(),
+                summary: Summary::new(samples),
+            })
+            .collect(),
+        total: Summary::new(&mut total_samples),
+    })
+}
+
+/// Read the inputs from the input files or use the text arguments.
+fn read_inputs(args: &BenchArgs) -> Vec<String> {
+    if args.input.is_empty() {
+        if args.text.is_empty() {
+            Args::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "Benchmarks need some text or at least one `--input` file",
+                )
+                .exit();
+        }
+        return vec![args.text.join(" ")];
+    }
+    args.input
+        .iter()
+        .map(|path| {
+            std::fs::read_to_string(path).unwrap_or_else(|error| {
+                Args::command()
+                    .error(
+                        ErrorKind::Io,
+                        format!("Failed to read input {}: {error}", path.display()),
+                    )
+                    .exit()
+            })
+        })
+        .collect()
+}
+
+/// Print the summaries as a table. With a baseline, its means and the relative change are added.
+fn print_table(pipeline: &PipelineSummary, baseline: Option<&PipelineSummary>) {
+    let mut header = format!(
+        "{:<5} {:<16} {:>10} {:>10} {:>10}",
+        "stage", "plugin", "mean", "p50", "p99"
+    );
+    if baseline.is_some() {
+        header.push_str(&format!(" {:>10} {:>8}", "baseline", "change"));
+    }
+    println!("{header}");
+
+    let rows = pipeline
+        .stages
+        .iter()
+        .map(|stage| {
+            (
+                stage.index.to_string(),
+                stage.plugin.as_str(),
+                stage.summary,
+            )
+        })
+        .chain([(String::new(), "total", pipeline.total)]);
+    let baseline_means = baseline.map(|baseline| {
+        baseline
+            .stages
+            .iter()
+            .map(|stage| stage.summary.mean_ms)
+            .chain([baseline.total.mean_ms])
+            .collect::<Vec<_>>()
+    });
+    for (row, (index, plugin, summary)) in rows.enumerate() {
+        let mut line = format!(
+            "{index:<5} {plugin:<16} {:>8.3}ms {:>8.3}ms {:>8.3}ms",
+            summary.mean_ms, summary.p50_ms, summary.p99_ms
+        );
+        if let Some(baseline_means) = &baseline_means {
+            let baseline_mean = baseline_means[row];
+            // Stages that take no measurable time have no relative change
+            let change = if baseline_mean == 0.0 {
+                "-".to_string()
+            } else {
+                format!(
+                    "{:+.1}%",
+                    (summary.mean_ms - baseline_mean) / baseline_mean * 100.0
+                )
+            };
+            line.push_str(&format!(" {baseline_mean:>8.3}ms {change:>8}"));
+        }
+        println!("{line}");
+    }
+}
+
+pub fn bench(args: BenchArgs) -> ExitCode {
+    if !args.baseline.is_empty() && args.baseline.len() != args.plugins.len() {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "The baseline needs as many plugins as the pipeline",
+            )
+            .exit();
+    }
+    let inputs = read_inputs(&args);
+
+    let result = measure(&args.plugins, &inputs, &args).and_then(|pipeline| {
+        let baseline = if args.baseline.is_empty() {
+            None
+        } else {
+            Some(measure(&args.baseline, &inputs, &args)?)
+        };
+        Ok(BenchReport {
+            inputs: inputs.len(),
+            warmup: args.warmup,
+            iterations: args.iterations,
+            threads: args.threads.get(),
+            reset: args.reset,
+            pipeline,
+            baseline,
+        })
+    });
+    let report = match result {
+        Ok(report) => report,
+        Err(err) => {
+            let exit_code = err.error.category().exit_code();
+            eprintln!("{:?}", miette::Report::new(err));
+            return exit_code.into();
+        }
+    };
+
+    match args.format {
+        OutputFormat::Text => print_table(&report.pipeline, report.baseline.as_ref()),
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&report).expect("The report can always be serialized")
+        ),
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_samples() {
+        let mut samples: Vec<Duration> = (1..=100).rev().map(Duration::from_millis).collect();
+        let summary = Summary::new(&mut samples);
+        assert_eq!(summary.mean_ms, 50.5);
+        assert_eq!(summary.p50_ms, 50.0);
+        assert_eq!(summary.p99_ms, 99.0);
+    }
+
+    #[test]
+    fn summarizes_a_single_sample() {
+        let summary = Summary::new(&mut [Duration::from_millis(3)]);
+        assert_eq!(summary.p50_ms, 3.0);
+        assert_eq!(summary.p99_ms, 3.0);
+        assert_eq!(Summary::new(&mut []), Summary::default());
+    }
+}
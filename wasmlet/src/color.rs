@@ -0,0 +1,252 @@
+//! Downgrade ANSI color escape codes in the output to what the terminal supports.
+
+use crate::terminal::ColorDepth;
+
+/// The 16 basic ANSI colors as rendered by xterm.
+const ANSI16_PALETTE: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+/// The levels of each channel in the 6x6x6 color cube of the 256 color palette.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// A color from an extended SGR parameter like `38;5;n` or `38;2;r;g;b`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Color {
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+/// Rewrite all SGR escape sequences (`ESC [ ... m`) in `text` so they only use colors available at `depth`.
+///
+/// With [`ColorDepth::None`] all SGR sequences are removed. Other escape sequences are left untouched.
+pub fn downgrade(text: &str, depth: ColorDepth) -> String {
+    if depth == ColorDepth::TrueColor || !text.contains('\x1b') {
+        return text.to_string();
+    }
+
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("\x1b[") {
+        output.push_str(&rest[..start]);
+        let sequence = &rest[start + 2..];
+        let parameters_length = sequence
+            .find(|c: char| !(c.is_ascii_digit() || c == ';' || c == ':'))
+            .unwrap_or(sequence.len());
+        if sequence[parameters_length..].starts_with('m') {
+            if depth != ColorDepth::None {
+                output.push_str(&downgrade_sgr(&sequence[..parameters_length], depth));
+            }
+            rest = &sequence[parameters_length + 1..];
+        } else {
+            output.push_str("\x1b[");
+            rest = sequence;
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Downgrade the parameters of a single SGR sequence and return the new sequence.
+fn downgrade_sgr(parameters: &str, depth: ColorDepth) -> String {
+    if parameters.is_empty() {
+        return "\x1b[m".to_string();
+    }
+
+    let mut result: Vec<String> = Vec::new();
+    let mut parameters = parameters.split(';');
+    while let Some(parameter) = parameters.next() {
+        let mut subparameters = parameter.split(':');
+        let code = subparameters.next().unwrap_or_default();
+        if !matches!(code, "38" | "48" | "58") {
+            result.push(parameter.to_string());
+            continue;
+        }
+        // Colons separate the color components in the same parameter, semicolons use the following parameters
+        let color = if parameter.contains(':') {
+            parse_color(&mut subparameters, true)
+        } else {
+            parse_color(&mut parameters, false)
+        };
+        match color {
+            Some(color) => result.extend(encode_color(code, color, depth)),
+            None => result.push(parameter.to_string()),
+        }
+    }
+
+    if result.is_empty() {
+        return String::new();
+    }
+    format!("\x1b[{}m", result.join(";"))
+}
+
+/// Parse the color after a `38`, `48` or `58` parameter.
+///
+/// The colon form may contain a color space id before the rgb components (`38:2::r:g:b`).
+fn parse_color<'a>(parts: &mut impl Iterator<Item = &'a str>, colon_form: bool) -> Option<Color> {
+    let number = |part: Option<&str>| part?.parse::<u8>().ok();
+    match parts.next()? {
+        "5" => Some(Color::Indexed(number(parts.next())?)),
+        "2" => {
+            let mut components: Vec<&str> = parts.take(if colon_form { 4 } else { 3 }).collect();
+            if colon_form && components.len() == 4 {
+                components.remove(0);
+            }
+            let [red, green, blue] = components[..] else {
+                return None;
+            };
+            Some(Color::Rgb(
+                number(Some(red))?,
+                number(Some(green))?,
+                number(Some(blue))?,
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Encode a color for a terminal with the given depth. Returns nothing if the color can not be represented.
+fn encode_color(code: &str, color: Color, depth: ColorDepth) -> Option<String> {
+    match (depth, color) {
+        (ColorDepth::None, _) => None,
+        (ColorDepth::TrueColor, Color::Rgb(red, green, blue)) => {
+            Some(format!("{code};2;{red};{green};{blue}"))
+        }
+        (ColorDepth::TrueColor | ColorDepth::Ansi256, Color::Indexed(index)) => {
+            Some(format!("{code};5;{index}"))
+        }
+        (ColorDepth::Ansi256, Color::Rgb(red, green, blue)) => {
+            Some(format!("{code};5;{}", nearest_ansi256(red, green, blue)))
+        }
+        (ColorDepth::Ansi16, color) => {
+            let index = match color {
+                Color::Indexed(index) if index < 16 => index,
+                Color::Indexed(index) => {
+                    let (red, green, blue) = ansi256_to_rgb(index);
+                    nearest_ansi16(red, green, blue)
+                }
+                Color::Rgb(red, green, blue) => nearest_ansi16(red, green, blue),
+            };
+            let base = match (code, index < 8) {
+                ("38", true) => 30,
+                ("38", false) => 90 - 8,
+                ("48", true) => 40,
+                ("48", false) => 100 - 8,
+                // Underline colors do not exist with 16 colors
+                _ => return None,
+            };
+            Some((base + index).to_string())
+        }
+    }
+}
+
+fn distance((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> u32 {
+    let square = |a: u8, b: u8| (a as i32 - b as i32).pow(2) as u32;
+    square(r1, r2) + square(g1, g2) + square(b1, b2)
+}
+
+fn ansi256_to_rgb(index: u8) -> (u8, u8, u8) {
+    match index {
+        0..16 => ANSI16_PALETTE[index as usize],
+        16..232 => {
+            let index = index - 16;
+            (
+                CUBE_LEVELS[(index / 36) as usize],
+                CUBE_LEVELS[(index / 6 % 6) as usize],
+                CUBE_LEVELS[(index % 6) as usize],
+            )
+        }
+        232.. => {
+            let gray = 8 + 10 * (index - 232);
+            (gray, gray, gray)
+        }
+    }
+}
+
+fn nearest_ansi16(red: u8, green: u8, blue: u8) -> u8 {
+    (0..16)
+        .min_by_key(|index| distance(ANSI16_PALETTE[*index as usize], (red, green, blue)))
+        .unwrap()
+}
+
+/// Find the closest color in the color cube or the grayscale ramp of the 256 color palette.
+fn nearest_ansi256(red: u8, green: u8, blue: u8) -> u8 {
+    (16..=255)
+        .min_by_key(|index| distance(ansi256_to_rgb(*index), (red, green, blue)))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAINBOW: &str = "\x1b[31mH\x1b[33mi\x1b[0m";
+
+    #[test]
+    fn strips_colors_without_color_support() {
+        assert_eq!(downgrade(RAINBOW, ColorDepth::None), "Hi");
+        assert_eq!(
+            downgrade("\x1b[38;2;1;2;3mHi\x1b[m", ColorDepth::None),
+            "Hi"
+        );
+    }
+
+    #[test]
+    fn keeps_basic_colors() {
+        assert_eq!(downgrade(RAINBOW, ColorDepth::Ansi16), RAINBOW);
+        assert_eq!(downgrade(RAINBOW, ColorDepth::Ansi256), RAINBOW);
+    }
+
+    #[test]
+    fn downgrades_truecolor() {
+        let text = "\x1b[1;38;2;255;0;0;48;2;0;0;238mHi";
+        assert_eq!(
+            downgrade(text, ColorDepth::Ansi256),
+            "\x1b[1;38;5;196;48;5;21mHi"
+        );
+        assert_eq!(downgrade(text, ColorDepth::Ansi16), "\x1b[1;91;44mHi");
+    }
+
+    #[test]
+    fn downgrades_256_colors() {
+        assert_eq!(
+            downgrade("\x1b[38;5;9mHi", ColorDepth::Ansi16),
+            "\x1b[91mHi"
+        );
+        assert_eq!(
+            downgrade("\x1b[48;5;231mHi", ColorDepth::Ansi16),
+            "\x1b[107mHi"
+        );
+    }
+
+    #[test]
+    fn understands_the_colon_form() {
+        assert_eq!(
+            downgrade("\x1b[38:2::255:0:0mHi", ColorDepth::Ansi256),
+            "\x1b[38;5;196mHi"
+        );
+    }
+
+    #[test]
+    fn keeps_other_escape_sequences() {
+        assert_eq!(
+            downgrade("\x1b[2J\x1b[31mHi", ColorDepth::None),
+            "\x1b[2JHi"
+        );
+    }
+}
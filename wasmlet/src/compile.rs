@@ -0,0 +1,164 @@
+//! Compiler backends and `wasmlet compile`: Precompile plugins for builds without a compiler.
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::ValueEnum;
+use clap::builder::PossibleValue;
+
+use crate::error::{BackendError, PluginError};
+use crate::plugin::{PluginConfig, load_plugin_source, plugin_name};
+use crate::preinit;
+use crate::terminal::TerminalInfo;
+use crate::wasmer_runtime;
+
+#[cfg(feature = "compiler")]
+use std::sync::Arc;
+#[cfg(feature = "compiler")]
+use wasmer::sys::EngineBuilder;
+#[cfg(feature = "compiler")]
+use wasmer::wasmparser::Operator;
+#[cfg(feature = "compiler")]
+use wasmer::{CompilerConfig, Engine};
+#[cfg(feature = "compiler")]
+use wasmer_middlewares::Metering;
+
+/// The compilers wasmer can use. Each of them needs the cargo feature of the same name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compiler {
+    /// Compiles quickly and generates fast code. The default.
+    #[cfg(feature = "cranelift")]
+    Cranelift,
+    /// Compiles in linear time, which protects against modules that take long to compile. Generates slower code.
+    #[cfg(feature = "singlepass")]
+    Singlepass,
+    /// Compiles slowly and generates the fastest code.
+    #[cfg(feature = "llvm")]
+    Llvm,
+}
+
+impl Compiler {
+    /// The compilers this build of WASMlet includes, the default first.
+    const AVAILABLE: &'static [Compiler] = &[
+        #[cfg(feature = "cranelift")]
+        Compiler::Cranelift,
+        #[cfg(feature = "singlepass")]
+        Compiler::Singlepass,
+        #[cfg(feature = "llvm")]
+        Compiler::Llvm,
+    ];
+
+    /// The compiler that is used if none is selected. Nothing in a headless build.
+    pub fn default_compiler() -> Option<Compiler> {
+        Compiler::AVAILABLE.first().copied()
+    }
+
+    /// Create an engine that compiles with this compiler.
+    ///
+    /// With metering every executed operator costs one point of fuel. The fuel limit is only the initial value, the host sets the fuel before every call.
+    #[cfg(feature = "compiler")]
+    pub fn engine(self, metering: bool, fuel_limit: Option<u64>) -> Engine {
+        fn build(mut compiler: impl CompilerConfig + 'static, metering: Option<u64>) -> Engine {
+            if let Some(limit) = metering {
+                compiler.push_middleware(Arc::new(Metering::new(limit, |_: &Operator| 1)));
+            }
+            EngineBuilder::new(compiler).into()
+        }
+
+        let metering = metering.then(|| fuel_limit.unwrap_or(u64::MAX));
+        match self {
+            #[cfg(feature = "cranelift")]
+            Compiler::Cranelift => build(wasmer_compiler_cranelift::Cranelift::default(), metering),
+            #[cfg(feature = "singlepass")]
+            Compiler::Singlepass => {
+                build(wasmer_compiler_singlepass::Singlepass::default(), metering)
+            }
+            #[cfg(feature = "llvm")]
+            Compiler::Llvm => build(wasmer_compiler_llvm::LLVM::default(), metering),
+        }
+    }
+}
+
+// Implemented by hand, so only the compilers of this build are offered
+impl ValueEnum for Compiler {
+    fn value_variants<'a>() -> &'a [Self] {
+        Compiler::AVAILABLE
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match *self {
+            #[cfg(feature = "cranelift")]
+            Compiler::Cranelift => Some(PossibleValue::new("cranelift")),
+            #[cfg(feature = "singlepass")]
+            Compiler::Singlepass => Some(PossibleValue::new("singlepass")),
+            #[cfg(feature = "llvm")]
+            Compiler::Llvm => Some(PossibleValue::new("llvm")),
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CompileArgs {
+    /// The plugin to compile. It is resolved like the plugins of a pipeline
+    plugin: String,
+
+    /// Where to write the precompiled plugin. Defaults to the name of the plugin with the extension `.wasmu`
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// The compiler to use. Defaults to the first one this build includes
+    #[arg(long, value_enum)]
+    compiler: Option<Compiler>,
+
+    /// Count the executed operators, so the precompiled plugin can be used with `--stats` and fuel limits
+    #[arg(long)]
+    metering: bool,
+}
+
+/// Compile the plugin and serialize the compiled module.
+fn precompile(args: &CompileArgs) -> Result<Vec<u8>, PluginError> {
+    let config = PluginConfig {
+        compiler: args.compiler.or(Compiler::default_compiler()),
+        metering: args.metering,
+        ..PluginConfig::new(TerminalInfo::STANDARD)
+    };
+    if config.compiler.is_none() {
+        return Err(BackendError::NoCompiler.into());
+    }
+    let source = load_plugin_source(&args.plugin)?;
+    log::debug!("Compiling {}", source.location);
+    // Precompiled plugins can not be rewritten later, so they are prepared for `reset` right away
+    let bytes = if source.precompiled {
+        source.bytes
+    } else {
+        preinit::export_mutable_globals(&source.bytes)?
+    };
+    let module = wasmer_runtime::compile(
+        &wasmer_runtime::create_engine(&config),
+        &bytes,
+        source.precompiled,
+        &config,
+    )?;
+    let artifact = module.serialize().map_err(BackendError::Serialize)?;
+    Ok(artifact.to_vec())
+}
+
+pub fn compile(args: CompileArgs) -> ExitCode {
+    let artifact = match precompile(&args) {
+        Ok(artifact) => artifact,
+        Err(error) => {
+            let exit_code = error.category().exit_code();
+            eprintln!("{:?}", miette::Report::new(error));
+            return exit_code.into();
+        }
+    };
+    let output = args
+        .output
+        .unwrap_or_else(|| PathBuf::from(format!("{}.wasmu", plugin_name(&args.plugin))));
+    if let Err(error) = std::fs::write(&output, artifact) {
+        eprintln!("Failed to write {}: {error}", output.display());
+        return ExitCode::FAILURE;
+    }
+    println!("Compiled {} to {}", args.plugin, output.display());
+    ExitCode::SUCCESS
+}
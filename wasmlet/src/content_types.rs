@@ -0,0 +1,214 @@
+//! Content types that plugins declare in the `wasmlet-content-types` custom section.
+//!
+//! The section contains an `accepts` line with the comma separated types the plugin takes and a `produces` line with the type it returns, like `text/plain`, `text/x-ansi` for text with escape codes or `application/json`. The `content_types!` macro of the SDK creates it. Before a pipeline runs, every stage is checked to accept what the stage before it produces, so incompatible plugins fail before any text is processed.
+//!
+//! Plugins that declare nothing are text plugins: they accept [`TEXT_ACCEPTS`] and produce [`TEXT_PLAIN`], so their output needs to be utf8. A pipeline that passes them binary content fails before it runs. Plugins that produce a type outside of `text/`, like `image/png`, can return any bytes, which are passed on as a [`Payload`].
+
+use serde::Serialize;
+
+use crate::error::{LinkError, StageError};
+use crate::plugin::Plugin;
+
+/// The name of the custom section.
+pub const SECTION: &str = "wasmlet-content-types";
+
+/// Text without escape codes. The input of a pipeline has this type.
+pub const TEXT_PLAIN: &str = "text/plain";
+
+/// Text with ANSI escape codes.
+pub const TEXT_ANSI: &str = "text/x-ansi";
+
+/// What plugins accept if they do not declare it.
+pub const TEXT_ACCEPTS: &str = "text/*";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ContentTypes {
+    /// The types the plugin accepts. Patterns like `text/*` and `*/*` are allowed. [`TEXT_ACCEPTS`] if empty.
+    pub accepts: Vec<String>,
+    /// [`TEXT_PLAIN`] if missing.
+    pub produces: Option<String>,
+}
+
+impl ContentTypes {
+    /// Parse the content of a content types section. Unknown keys are ignored.
+    pub fn parse(section: &[u8]) -> Self {
+        let mut content_types = ContentTypes::default();
+        for line in String::from_utf8_lossy(section).lines() {
+            match line.split_once('=') {
+                Some(("accepts", types)) => content_types.accepts.extend(
+                    types
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|content_type| !content_type.is_empty())
+                        .map(str::to_string),
+                ),
+                Some(("produces", content_type)) if !content_type.is_empty() => {
+                    content_types.produces = Some(content_type.to_string())
+                }
+                _ => {}
+            }
+        }
+        content_types
+    }
+
+    /// Whether the plugin accepts the type. Without declared types it accepts text.
+    pub fn accepts(&self, content_type: &str) -> bool {
+        match self.accepts.as_slice() {
+            [] => matches(TEXT_ACCEPTS, content_type),
+            accepts => accepts
+                .iter()
+                .any(|accepted| matches(accepted, content_type)),
+        }
+    }
+
+    /// The type of the output of the plugin.
+    pub fn produces(&self) -> &str {
+        self.produces.as_deref().unwrap_or(TEXT_PLAIN)
+    }
+}
+
+/// Content that flows through a pipeline, together with its type.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Payload {
+    pub bytes: Vec<u8>,
+    pub content_type: String,
+}
+
+impl Payload {
+    pub fn new(bytes: Vec<u8>, content_type: impl Into<String>) -> Self {
+        Payload {
+            bytes,
+            content_type: content_type.into(),
+        }
+    }
+
+    /// A [`TEXT_PLAIN`] payload, like the input of a pipeline.
+    pub fn text(text: impl Into<String>) -> Self {
+        Payload::new(text.into().into_bytes(), TEXT_PLAIN)
+    }
+
+    /// The content as text. Returns nothing for binary types or if the content is not utf8.
+    pub fn as_text(&self) -> Option<&str> {
+        is_text(&self.content_type)
+            .then(|| std::str::from_utf8(&self.bytes).ok())
+            .flatten()
+    }
+}
+
+/// Whether the type is text, like `text/plain` or `text/x-ansi`. Plugins that declare no types are expected to work with text.
+pub fn is_text(content_type: &str) -> bool {
+    content_type.starts_with("text/")
+}
+
+/// Whether the type matches the accepted type or pattern. Plain text is valid text with escape codes, so it matches `text/x-ansi`.
+fn matches(accepted: &str, content_type: &str) -> bool {
+    let main_type = content_type
+        .split_once('/')
+        .map_or(content_type, |(main_type, _)| main_type);
+    accepted == "*/*"
+        || accepted == content_type
+        || accepted.strip_suffix("/*") == Some(main_type)
+        || (accepted == TEXT_ANSI && content_type == TEXT_PLAIN)
+}
+
+/// Check that every plugin accepts what the one before it produces. The first one receives [`TEXT_PLAIN`].
+pub fn check_pipeline(specifiers: &[String], plugins: &[Plugin]) -> Result<(), StageError> {
+    check_stages(
+        specifiers,
+        plugins
+            .iter()
+            .map(|plugin| plugin.content_types().cloned().unwrap_or_default()),
+    )
+}
+
+/// Check the content types of the stages of a pipeline. Plugins that declare nothing are text plugins.
+fn check_stages(
+    specifiers: &[String],
+    stages: impl IntoIterator<Item = ContentTypes>,
+) -> Result<(), StageError> {
+    let mut produced = TEXT_PLAIN.to_string();
+    let mut producer = "the input of the pipeline".to_string();
+    for (index, (specifier, content_types)) in specifiers.iter().zip(stages).enumerate() {
+        if !content_types.accepts(&produced) {
+            let accepted = match content_types.accepts.as_slice() {
+                [] => TEXT_ACCEPTS.to_string(),
+                accepts => accepts.join("`, `"),
+            };
+            return Err(StageError {
+                stage: index,
+                specifier: specifier.clone(),
+                error: LinkError::IncompatibleContentType {
+                    produced,
+                    producer,
+                    accepted,
+                }
+                .into(),
+            });
+        }
+        produced = content_types.produces().to_string();
+        producer = format!("stage {index} (`{specifier}`)");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_content_types_section() {
+        assert_eq!(
+            ContentTypes::parse(b"accepts=text/plain, text/x-ansi\nproduces=application/json\n"),
+            ContentTypes {
+                accepts: vec![TEXT_PLAIN.to_string(), TEXT_ANSI.to_string()],
+                produces: Some("application/json".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn matches_accepted_types() {
+        let rainbow = ContentTypes::parse(b"accepts=text/plain\nproduces=text/x-ansi\n");
+        assert!(rainbow.accepts(TEXT_PLAIN));
+        assert!(!rainbow.accepts(TEXT_ANSI));
+
+        let color = ContentTypes::parse(b"accepts=text/x-ansi\n");
+        assert!(color.accepts(TEXT_PLAIN));
+        assert!(color.accepts(TEXT_ANSI));
+        assert!(!color.accepts("application/json"));
+
+        let any_text = ContentTypes::parse(b"accepts=text/*\n");
+        assert!(any_text.accepts(TEXT_ANSI));
+        assert!(!any_text.accepts("image/png"));
+
+        let undeclared = ContentTypes::default();
+        assert!(undeclared.accepts(TEXT_ANSI));
+        assert!(!undeclared.accepts("image/png"));
+    }
+
+    #[test]
+    fn text_plugins_can_not_follow_binary_stages() {
+        let specifiers = [
+            "bigfont".to_string(),
+            "render".to_string(),
+            "rainbow".to_string(),
+        ];
+        let render = ContentTypes::parse(b"accepts=text/*\nproduces=image/png\n");
+        let stages = [ContentTypes::default(), render, ContentTypes::default()];
+        let error = check_stages(&specifiers, stages.clone()).unwrap_err();
+        assert_eq!(error.stage, 2);
+        assert_eq!(error.error.kind(), "IncompatibleContentType");
+        assert!(check_stages(&specifiers[..2], stages).is_ok());
+    }
+
+    #[test]
+    fn only_text_payloads_are_text() {
+        assert_eq!(Payload::text("Hello").as_text(), Some("Hello"));
+        assert_eq!(
+            Payload::new(b"Hello".to_vec(), TEXT_ANSI).as_text(),
+            Some("Hello")
+        );
+        assert_eq!(Payload::new(vec![0xff], TEXT_PLAIN).as_text(), None);
+        assert_eq!(Payload::new(b"Hello".to_vec(), "image/png").as_text(), None);
+    }
+}
@@ -0,0 +1,528 @@
+//! Errors that can occur while loading and running plugins.
+
+use std::fmt;
+
+use miette::Diagnostic;
+use serde::Serialize;
+use thiserror::Error;
+use wasmer::{DeserializeError, SerializeError};
+
+use crate::runtime::{Runtime, Trap};
+
+/// An error reported by the wasm runtime. Every runtime has its own error types.
+pub type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Broad classes of errors. Each of them has its own exit code.
+///
+/// The codes start at 3, because clap exits with 2 for invalid arguments and 1 is left for other failures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    /// The plugin could not be found or downloaded.
+    Resolution,
+    /// The plugin is not a valid wasm module.
+    Compile,
+    /// The plugin could not be instantiated or does not export what we need.
+    Instantiate,
+    /// The plugin crashed or violated the ABI.
+    Trap,
+    /// The plugin reported an error.
+    Guest,
+    /// The plugin exceeded a resource limit.
+    LimitExceeded,
+}
+
+impl ErrorCategory {
+    pub fn exit_code(self) -> u8 {
+        match self {
+            ErrorCategory::Resolution => 3,
+            ErrorCategory::Compile => 4,
+            ErrorCategory::Instantiate => 5,
+            ErrorCategory::Trap => 6,
+            ErrorCategory::Guest => 7,
+            ErrorCategory::LimitExceeded => 8,
+        }
+    }
+}
+
+/// An error in one stage of the pipeline.
+#[derive(Error, Debug, Diagnostic)]
+#[error("Stage {stage} (`{specifier}`) failed")]
+pub struct StageError {
+    /// The index of the stage in the pipeline.
+    pub stage: usize,
+    /// The specifier the plugin was loaded with.
+    pub specifier: String,
+    #[source]
+    #[diagnostic_source]
+    pub error: PluginError,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+pub enum PluginError {
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Resolution(#[from] ResolutionError),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Download(#[from] DownloadError),
+    #[error("Failed to compile plugin: {0}")]
+    #[diagnostic(
+        code(wasmlet::compile),
+        help("Make sure the plugin is a valid WebAssembly module")
+    )]
+    Compile(#[source] BoxedError),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Backend(#[from] BackendError),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Preinit(#[from] PreinitError),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Link(#[from] LinkError),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Lifecycle(#[from] LifecycleError),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Execution(#[from] ExecutionError),
+}
+
+impl PluginError {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            PluginError::Resolution(_) | PluginError::Download(_) => ErrorCategory::Resolution,
+            PluginError::Compile(_) | PluginError::Backend(_) | PluginError::Preinit(_) => {
+                ErrorCategory::Compile
+            }
+            PluginError::Link(_)
+            | PluginError::Lifecycle(
+                LifecycleError::NotConfigurable
+                | LifecycleError::UnknownOption { .. }
+                | LifecycleError::MalformedOption { .. }
+                | LifecycleError::InvalidOptionValue { .. },
+            ) => ErrorCategory::Instantiate,
+            PluginError::Lifecycle(LifecycleError::OptionsRejected(_)) => ErrorCategory::Guest,
+            PluginError::Execution(ExecutionError::GuestError(_)) => ErrorCategory::Guest,
+            PluginError::Execution(ExecutionError::Trap { source, .. })
+                if source.stack_overflow =>
+            {
+                ErrorCategory::LimitExceeded
+            }
+            PluginError::Execution(
+                ExecutionError::OutputTooLarge { .. } | ExecutionError::FuelExhausted { .. },
+            ) => ErrorCategory::LimitExceeded,
+            PluginError::Execution(_) => ErrorCategory::Trap,
+        }
+    }
+
+    /// The name of the error variant.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PluginError::Resolution(ResolutionError::NotFound { .. }) => "NotFound",
+            PluginError::Resolution(ResolutionError::MissingExtismFunctionName { .. }) => {
+                "MissingExtismFunctionName"
+            }
+            PluginError::Download(DownloadError::HttpStatus { .. }) => "HttpStatus",
+            PluginError::Download(DownloadError::WrongContentType { .. }) => "WrongContentType",
+            PluginError::Download(DownloadError::Transport { .. }) => "Transport",
+            PluginError::Compile(_) => "Compile",
+            PluginError::Backend(BackendError::NoCompiler) => "NoCompiler",
+            PluginError::Backend(BackendError::Deserialize(_)) => "Deserialize",
+            PluginError::Backend(BackendError::UntrustedArtifact) => "UntrustedArtifact",
+            PluginError::Backend(BackendError::Serialize(_)) => "Serialize",
+            PluginError::Backend(BackendError::MissingMetering) => "MissingMetering",
+            PluginError::Backend(BackendError::HiddenGlobals) => "HiddenGlobals",
+            PluginError::Backend(BackendError::Unsupported { .. }) => "Unsupported",
+            PluginError::Preinit(PreinitError::MissingInit) => "MissingInit",
+            PluginError::Preinit(PreinitError::Precompiled) => "Precompiled",
+            PluginError::Preinit(PreinitError::Memories(_)) => "Memories",
+            PluginError::Preinit(PreinitError::Parse(_)) => "Parse",
+            PluginError::Preinit(PreinitError::Rewrite(_)) => "Rewrite",
+            PluginError::Link(LinkError::Instantiation(_)) => "Instantiation",
+            PluginError::Link(LinkError::MissingFunction { .. }) => "MissingFunction",
+            PluginError::Link(LinkError::MissingMemory(_)) => "MissingMemory",
+            PluginError::Link(LinkError::NativeLibrary { .. }) => "NativeLibrary",
+            PluginError::Link(LinkError::UnknownTransform { .. }) => "UnknownTransform",
+            PluginError::Link(LinkError::AmbiguousTransform { .. }) => "AmbiguousTransform",
+            PluginError::Link(LinkError::IncompatibleContentType { .. }) => {
+                "IncompatibleContentType"
+            }
+            PluginError::Lifecycle(LifecycleError::NotConfigurable) => "NotConfigurable",
+            PluginError::Lifecycle(LifecycleError::OptionsRejected(_)) => "OptionsRejected",
+            PluginError::Lifecycle(LifecycleError::UnknownOption { .. }) => "UnknownOption",
+            PluginError::Lifecycle(LifecycleError::MalformedOption { .. }) => "MalformedOption",
+            PluginError::Lifecycle(LifecycleError::InvalidOptionValue { .. }) => {
+                "InvalidOptionValue"
+            }
+            PluginError::Execution(ExecutionError::Trap { .. }) => "Trap",
+            PluginError::Execution(ExecutionError::Panicked { .. }) => "Panicked",
+            PluginError::Execution(ExecutionError::GuestError(_)) => "GuestError",
+            PluginError::Execution(ExecutionError::FailedToFreeSharedBuffer) => {
+                "FailedToFreeSharedBuffer"
+            }
+            PluginError::Execution(ExecutionError::NullResult) => "NullResult",
+            PluginError::Execution(ExecutionError::ResultOutOfBounds { .. }) => "ResultOutOfBounds",
+            PluginError::Execution(ExecutionError::InvalidSuccessFlag(_)) => "InvalidSuccessFlag",
+            PluginError::Execution(ExecutionError::OutputTooLarge { .. }) => "OutputTooLarge",
+            PluginError::Execution(ExecutionError::FuelExhausted { .. }) => "FuelExhausted",
+            PluginError::Execution(ExecutionError::ResultLengthOutOfBounds { .. }) => {
+                "ResultLengthOutOfBounds"
+            }
+            PluginError::Execution(ExecutionError::ResultOverlapsInput { .. }) => {
+                "ResultOverlapsInput"
+            }
+            PluginError::Execution(ExecutionError::InvalidInputBuffer(_)) => "InvalidInputBuffer",
+            PluginError::Execution(ExecutionError::OutputIsNotUtf8(_)) => "OutputIsNotUtf8",
+        }
+    }
+
+    /// The message the plugin reported, if the error originated in the plugin.
+    pub fn guest_message(&self) -> Option<&str> {
+        match self {
+            PluginError::Execution(
+                ExecutionError::GuestError(message) | ExecutionError::Panicked { message, .. },
+            ) => Some(message),
+            _ => None,
+        }
+    }
+}
+
+/// The plugin could not be found.
+#[derive(Error, Debug, Diagnostic)]
+pub enum ResolutionError {
+    #[error("Could not find plugin `{specifier}`")]
+    #[diagnostic(code(wasmlet::resolution::not_found))]
+    NotFound {
+        specifier: String,
+        /// Explains where we looked for the plugin.
+        #[help]
+        help: String,
+    },
+    #[error("Extism plugins need a function name")]
+    #[diagnostic(
+        code(wasmlet::resolution::missing_extism_function_name),
+        help("Add the function after a `#`, for example `extism:{specifier}#greet`")
+    )]
+    MissingExtismFunctionName { specifier: String },
+}
+
+/// The plugin could not be downloaded.
+#[derive(Error, Debug, Diagnostic)]
+pub enum DownloadError {
+    #[error("Failed to download plugin from {url}: The server responded with HTTP status {status}")]
+    #[diagnostic(code(wasmlet::download::http_status))]
+    HttpStatus { url: String, status: u16 },
+    #[error(
+        "Failed to download plugin from {url}: The server provided `{content_type}` instead of `application/wasm`"
+    )]
+    #[diagnostic(
+        code(wasmlet::download::wrong_content_type),
+        help(
+            "The server needs to send the plugin with the header `Content-Type: application/wasm`"
+        )
+    )]
+    WrongContentType { url: String, content_type: String },
+    #[error("Failed to download plugin from {url}: {source}")]
+    #[diagnostic(code(wasmlet::download::transport))]
+    Transport {
+        url: String,
+        // Boxed to keep the error small, see https://rust-lang.github.io/rust-clippy/master/index.html#result_large_err
+        #[source]
+        source: Box<ureq::Error>,
+    },
+}
+
+/// The compiler backend cannot handle the plugin, see `wasmlet compile`.
+#[derive(Error, Debug, Diagnostic)]
+pub enum BackendError {
+    #[error("This build of WASMlet has no compiler and can only load precompiled plugins")]
+    #[diagnostic(
+        code(wasmlet::backend::no_compiler),
+        help(
+            "Precompile the plugin with `wasmlet compile plugin.wasm -o plugin.wasmu` on a build with a compiler"
+        )
+    )]
+    NoCompiler,
+    #[error("Failed to load precompiled plugin: {0}")]
+    #[diagnostic(
+        code(wasmlet::backend::deserialize),
+        help(
+            "Precompiled plugins only work with the WASMlet version and target that compiled them. Compile the plugin again"
+        )
+    )]
+    Deserialize(#[source] DeserializeError),
+    #[error("The plugin is precompiled, but does not come from a local `.wasmu` file")]
+    #[diagnostic(
+        code(wasmlet::backend::untrusted_artifact),
+        help(
+            "Precompiled plugins contain machine code that runs outside of the sandbox, so only local files are loaded. Download the wasm module instead"
+        )
+    )]
+    UntrustedArtifact,
+    #[error("Failed to serialize the compiled plugin: {0}")]
+    #[diagnostic(code(wasmlet::backend::serialize))]
+    Serialize(#[source] SerializeError),
+    #[error("The precompiled plugin was compiled without metering")]
+    #[diagnostic(
+        code(wasmlet::backend::missing_metering),
+        help(
+            "Compile the plugin with `wasmlet compile --metering` to use it with `--stats` or a fuel limit"
+        )
+    )]
+    MissingMetering,
+    #[error(
+        "The precompiled plugin can not be reset, because it does not export all of its mutable globals"
+    )]
+    #[diagnostic(
+        code(wasmlet::backend::hidden_globals),
+        help(
+            "Compile the plugin again with `wasmlet compile`, which exports the globals, to use it with `--reset`"
+        )
+    )]
+    HiddenGlobals,
+    #[error("The {runtime} runtime does not support {feature}")]
+    #[diagnostic(
+        code(wasmlet::backend::unsupported),
+        help("Use the default runtime with `--runtime wasmer`")
+    )]
+    Unsupported {
+        runtime: Runtime,
+        feature: &'static str,
+    },
+}
+
+/// The plugin can not be pre-initialized, see `wasmlet preinit`.
+#[derive(Error, Debug, Diagnostic)]
+pub enum PreinitError {
+    #[error("The plugin does not export `init` as a function without parameters and results")]
+    #[diagnostic(
+        code(wasmlet::preinit::missing_init),
+        help("Export the initialization of the plugin with `wasmlet_sdk::export_init!`")
+    )]
+    MissingInit,
+    #[error("Cannot pre-initialize a precompiled plugin")]
+    #[diagnostic(
+        code(wasmlet::preinit::precompiled),
+        help("Pre-initialize the wasm module and compile the result with `wasmlet compile`")
+    )]
+    Precompiled,
+    #[error("The plugin has {0} memories, but pre-initialization needs exactly one")]
+    #[diagnostic(code(wasmlet::preinit::memories))]
+    Memories(usize),
+    #[error("Failed to read the plugin: {0}")]
+    #[diagnostic(
+        code(wasmlet::preinit::parse),
+        help("Only wasm and wat modules can be pre-initialized")
+    )]
+    Parse(#[from] wasmparser::BinaryReaderError),
+    #[error("Failed to rewrite the plugin: {0}")]
+    #[diagnostic(code(wasmlet::preinit::rewrite))]
+    Rewrite(#[from] wasm_encoder::reencode::Error),
+}
+
+/// The plugin could not be instantiated or does not provide the exports we need.
+#[derive(Error, Debug, Diagnostic)]
+pub enum LinkError {
+    // Clippy recommended that we box the error and I agree with the reasoning
+    // https://rust-lang.github.io/rust-clippy/master/index.html#result_large_err
+    #[error("Failed to instantiate plugin: {0}")]
+    #[diagnostic(
+        code(wasmlet::link::instantiation),
+        help("Plugins can only import the functions WASMlet provides in the `wasmlet` module")
+    )]
+    Instantiation(#[source] BoxedError),
+    #[error("The plugin does not provide the required function `{name}` in its exports ({source})")]
+    #[diagnostic(
+        code(wasmlet::link::missing_function),
+        help(
+            "WASMlet plugins need to export `allocate_shared_buffer`, `free_shared_buffer` and `process`"
+        )
+    )]
+    MissingFunction {
+        name: String,
+        #[source]
+        source: BoxedError,
+    },
+    #[error("The plugin does not export memory: `memory`")]
+    #[diagnostic(code(wasmlet::link::missing_memory))]
+    MissingMemory(#[source] BoxedError),
+    #[error("Failed to load the native build of the plugin from {path}: {source}")]
+    #[diagnostic(
+        code(wasmlet::link::native_library),
+        help(
+            "Native builds need to export the functions of `wasmlet_sdk::export_transformer!` and be built for the host target"
+        )
+    )]
+    NativeLibrary {
+        path: String,
+        #[source]
+        source: libloading::Error,
+    },
+    #[error("The plugin has no transform named `{transform}`")]
+    #[diagnostic(code(wasmlet::link::unknown_transform), help("{available}"))]
+    UnknownTransform {
+        transform: String,
+        available: String,
+    },
+    #[error("The plugin has several transforms and none of them is the primary one")]
+    #[diagnostic(
+        code(wasmlet::link::ambiguous_transform),
+        help("{available}. Select one with `#name` after the specifier")
+    )]
+    AmbiguousTransform { available: String },
+    #[error("The plugin does not accept `{produced}`, which {producer} produces")]
+    #[diagnostic(
+        code(wasmlet::link::incompatible_content_type),
+        help(
+            "It accepts `{accepted}`. Reorder the pipeline or add a stage in between that converts the content"
+        )
+    )]
+    IncompatibleContentType {
+        produced: String,
+        producer: String,
+        accepted: String,
+    },
+}
+
+/// A lifecycle export of the plugin failed. Traps in them are [`ExecutionError`]s like in `process`.
+#[derive(Error, Debug, Diagnostic)]
+pub enum LifecycleError {
+    #[error("The plugin does not take options")]
+    #[diagnostic(
+        code(wasmlet::lifecycle::not_configurable),
+        help("Only WASMlet plugins that export `configure` take options")
+    )]
+    NotConfigurable,
+    #[error("The plugin rejected its options: {0}")]
+    #[diagnostic(code(wasmlet::lifecycle::options_rejected))]
+    OptionsRejected(String),
+    #[error("The plugin has no option `{option}`")]
+    #[diagnostic(code(wasmlet::lifecycle::unknown_option))]
+    UnknownOption {
+        option: String,
+        /// Lists the options the plugin takes.
+        #[help]
+        help: String,
+    },
+    #[error("Option {option:?} can not be passed to the plugin")]
+    #[diagnostic(
+        code(wasmlet::lifecycle::malformed_option),
+        help(
+            "Options are passed as `key=value` lines, so keys can not contain `=` or a newline and values no newline"
+        )
+    )]
+    MalformedOption { option: String },
+    #[error("Option `{option}` can not be `{value}`")]
+    #[diagnostic(code(wasmlet::lifecycle::invalid_option_value))]
+    InvalidOptionValue {
+        option: String,
+        value: String,
+        /// Explains which values the option takes.
+        #[help]
+        help: String,
+    },
+}
+
+/// What the host asked the plugin to do when it crashed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    AllocatingBuffer,
+    FreeingBuffer,
+    Processing,
+    Initializing,
+    Configuring,
+    ShuttingDown,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::AllocatingBuffer => write!(f, "allocating a buffer"),
+            Operation::FreeingBuffer => write!(f, "freeing a buffer"),
+            Operation::Processing => write!(f, "processing your input"),
+            Operation::Initializing => write!(f, "initializing"),
+            Operation::Configuring => write!(f, "applying its options"),
+            Operation::ShuttingDown => write!(f, "shutting down"),
+        }
+    }
+}
+
+/// Something went wrong while the plugin was running.
+#[derive(Error, Debug, Diagnostic)]
+pub enum ExecutionError {
+    #[error("The plugin crashed while {operation}: {source}")]
+    #[diagnostic(code(wasmlet::execution::trap))]
+    Trap {
+        operation: Operation,
+        #[source]
+        source: Trap,
+    },
+    #[error("The plugin panicked: {message}\n\nBacktrace:\n{backtrace}")]
+    #[diagnostic(code(wasmlet::execution::panicked))]
+    Panicked {
+        message: String,
+        backtrace: String,
+        #[source]
+        source: Trap,
+    },
+    #[error("The plugin did not finish within {limit} instructions")]
+    #[diagnostic(
+        code(wasmlet::execution::fuel_exhausted),
+        help("The plugin might be stuck in an endless loop")
+    )]
+    FuelExhausted { limit: u64 },
+    #[error("The plugin failed to process the input: {0}")]
+    #[diagnostic(code(wasmlet::execution::guest_error))]
+    GuestError(String),
+    #[error("Failed to free a shared buffer")]
+    #[diagnostic(code(wasmlet::execution::failed_to_free_shared_buffer))]
+    FailedToFreeSharedBuffer,
+    #[error("`process` returned a null pointer")]
+    #[diagnostic(
+        code(wasmlet::execution::null_result),
+        help(
+            "`process` needs to return a pointer to a result buffer (1 byte success flag, 4 byte length, length bytes utf8-formatted string)"
+        )
+    )]
+    NullResult,
+    #[error(
+        "`process` returned the pointer {pointer:#x}, but the result header does not fit into the {memory_size} bytes of guest memory"
+    )]
+    #[diagnostic(code(wasmlet::execution::result_out_of_bounds))]
+    ResultOutOfBounds { pointer: u32, memory_size: u64 },
+    #[error("The result buffer has the success flag {0}, but only 0 and 1 are valid")]
+    #[diagnostic(code(wasmlet::execution::invalid_success_flag))]
+    InvalidSuccessFlag(u8),
+    #[error("The plugin produced {length} bytes of output, but the limit is {limit} bytes")]
+    #[diagnostic(
+        code(wasmlet::execution::output_too_large),
+        help("Use `--max-output` to allow larger outputs")
+    )]
+    OutputTooLarge { length: u64, limit: u64 },
+    #[error(
+        "The result buffer at {pointer:#x} claims to contain {length} bytes, but that is beyond the {memory_size} bytes of guest memory"
+    )]
+    #[diagnostic(code(wasmlet::execution::result_length_out_of_bounds))]
+    ResultLengthOutOfBounds {
+        pointer: u32,
+        length: u32,
+        memory_size: u64,
+    },
+    #[error("The result buffer at {pointer:#x} overlaps the input buffer at {input:#x}")]
+    #[diagnostic(
+        code(wasmlet::execution::result_overlaps_input),
+        help("`process` needs to return a new buffer, because the host frees both buffers")
+    )]
+    ResultOverlapsInput { pointer: u32, input: u32 },
+    #[error("The plugin failed to allocate a valid buffer for the input.")]
+    #[diagnostic(code(wasmlet::execution::invalid_input_buffer))]
+    InvalidInputBuffer(#[source] BoxedError),
+    #[error("The plugin produced output that is not valid utf8: {0}")]
+    #[diagnostic(
+        code(wasmlet::execution::output_is_not_utf8),
+        help("Use `--lossy-utf8` to replace invalid sequences instead")
+    )]
+    OutputIsNotUtf8(#[source] std::string::FromUtf8Error),
+}
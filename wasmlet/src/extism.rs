@@ -0,0 +1,589 @@
+//! Compatibility layer for plugins written against the [Extism PDK](https://extism.org).
+//!
+//! Extism plugins do not manage shared buffers themselves. Instead they import a small kernel from
+//! `extism:host/env` that owns a separate memory and provides functions to allocate blocks in it,
+//! read the input and set the output. We implement that kernel on the host side.
+
+use std::collections::{BTreeSet, HashMap};
+
+use wasmer::{
+    Function, FunctionEnv, FunctionEnvMut, Instance, Module, RuntimeError, Store, TypedFunction,
+    imports,
+};
+
+use crate::{
+    error::{ExecutionError, LinkError, Operation, PluginError},
+    host::log_target,
+    plugin::PluginConfig,
+    wasmer_runtime,
+};
+
+/// Log levels as Extism numbers them.
+const EXTISM_LOG_LEVEL_TRACE: i32 = 0;
+const EXTISM_LOG_LEVEL_DEBUG: i32 = 1;
+const EXTISM_LOG_LEVEL_INFO: i32 = 2;
+const EXTISM_LOG_LEVEL_WARN: i32 = 3;
+const EXTISM_LOG_LEVEL_ERROR: i32 = 4;
+const EXTISM_LOG_LEVEL_OFF: i32 = i32::MAX;
+
+/// The host side of the Extism kernel.
+///
+/// All blocks live in a single byte vector. Offset 0 is reserved, because Extism uses it to signal that there is no block.
+/// Freed blocks are handed out again by later allocations that fit into them, and all memory is reclaimed when the kernel is reset before the next call.
+pub struct ExtismKernel {
+    /// The log target for messages from this plugin.
+    target: String,
+    /// The maximum output of a call. The memory lives on the host, so it is limited to the input and this much more.
+    max_output: u64,
+    memory: Vec<u8>,
+    /// Maps the offset of every live block to its length.
+    blocks: HashMap<u64, u64>,
+    /// The length and offset of every freed block, so the smallest one that fits is found first.
+    free_blocks: BTreeSet<(u64, u64)>,
+    /// The total length of the live blocks. Only they count toward the limit.
+    live_bytes: u64,
+    input: Option<(u64, u64)>,
+    output: Option<(u64, u64)>,
+    error: Option<u64>,
+}
+
+impl ExtismKernel {
+    fn new(plugin_name: &str, max_output: u64) -> Self {
+        ExtismKernel {
+            target: log_target(plugin_name),
+            max_output,
+            memory: Vec::new(),
+            blocks: HashMap::new(),
+            free_blocks: BTreeSet::new(),
+            live_bytes: 0,
+            input: None,
+            output: None,
+            error: None,
+        }
+    }
+
+    /// Forget all blocks and prepare the kernel for a new call with the given input.
+    fn reset(&mut self, input: &[u8]) {
+        self.memory.clear();
+        self.memory.push(0);
+        self.blocks.clear();
+        self.free_blocks.clear();
+        self.live_bytes = 0;
+        self.output = None;
+        self.error = None;
+        let offset = self.allocate(input.len() as u64);
+        self.memory[offset as usize..].copy_from_slice(input);
+        self.input = Some((offset, input.len() as u64));
+    }
+
+    /// Allocate a zeroed block. The smallest freed block that fits is reused, the rest of it stays free.
+    fn allocate(&mut self, length: u64) -> u64 {
+        let reused = self.free_blocks.range((length.max(1), 0)..).next().copied();
+        let offset = match reused {
+            Some((free_length, offset)) => {
+                self.free_blocks.remove(&(free_length, offset));
+                if free_length > length {
+                    self.free_blocks
+                        .insert((free_length - length, offset + length));
+                }
+                self.memory[offset as usize..(offset + length) as usize].fill(0);
+                offset
+            }
+            None => {
+                let offset = self.memory.len() as u64;
+                self.memory.resize(self.memory.len() + length as usize, 0);
+                offset
+            }
+        };
+        self.blocks.insert(offset, length);
+        self.live_bytes += length;
+        offset
+    }
+
+    /// Free a block, so its memory can be allocated again. The input, output and error are only reused after the call, because the host still reads them.
+    fn free(&mut self, offset: u64) {
+        let Some(length) = self.blocks.remove(&offset) else {
+            return;
+        };
+        self.live_bytes -= length;
+        let in_use = [
+            self.input.map(|(input, _)| input),
+            self.output.map(|(output, _)| output),
+            self.error,
+        ]
+        .contains(&Some(offset));
+        if length > 0 && !in_use {
+            self.free_blocks.insert((length, offset));
+        }
+    }
+
+    /// Get the bytes in the kernel memory from `offset` to `offset + length`.
+    fn bytes(&self, offset: u64, length: u64) -> Result<&[u8], RuntimeError> {
+        let end = offset
+            .checked_add(length)
+            .filter(|end| *end <= self.memory.len() as u64)
+            .ok_or_else(|| {
+                RuntimeError::new(format!(
+                    "Extism memory access out of bounds ({length} bytes at offset {offset})"
+                ))
+            })?;
+        Ok(&self.memory[offset as usize..end as usize])
+    }
+
+    fn bytes_mut(&mut self, offset: u64, length: u64) -> Result<&mut [u8], RuntimeError> {
+        self.bytes(offset, length)?;
+        Ok(&mut self.memory[offset as usize..(offset + length) as usize])
+    }
+
+    /// Get the content of the block starting at `offset`.
+    fn block(&self, offset: u64) -> Result<&[u8], RuntimeError> {
+        let length = self.blocks.get(&offset).ok_or_else(|| {
+            RuntimeError::new(format!(
+                "There is no Extism memory block at offset {offset}"
+            ))
+        })?;
+        self.bytes(offset, *length)
+    }
+
+    fn block_string(&self, offset: u64) -> Result<String, RuntimeError> {
+        Ok(String::from_utf8_lossy(self.block(offset)?).into_owned())
+    }
+}
+
+fn alloc(mut env: FunctionEnvMut<ExtismKernel>, length: u64) -> Result<u64, RuntimeError> {
+    let kernel = env.data_mut();
+    let input_length = kernel.input.map(|(_, length)| length).unwrap_or(0);
+    let limit = input_length
+        .saturating_add(kernel.max_output)
+        .min(u32::MAX as u64);
+    if kernel.live_bytes.saturating_add(length) > limit {
+        return Err(RuntimeError::new(format!(
+            "The plugin tried to allocate {length} bytes of Extism memory, but only {limit} bytes are available. Use `--max-output` to allow more"
+        )));
+    }
+    Ok(kernel.allocate(length))
+}
+
+fn free(mut env: FunctionEnvMut<ExtismKernel>, offset: u64) {
+    env.data_mut().free(offset);
+}
+
+fn length(env: FunctionEnvMut<ExtismKernel>, offset: u64) -> u64 {
+    env.data().blocks.get(&offset).copied().unwrap_or(0)
+}
+
+fn load_u8(env: FunctionEnvMut<ExtismKernel>, offset: u64) -> Result<u32, RuntimeError> {
+    Ok(env.data().bytes(offset, 1)?[0] as u32)
+}
+
+fn load_u64(env: FunctionEnvMut<ExtismKernel>, offset: u64) -> Result<u64, RuntimeError> {
+    let bytes = env.data().bytes(offset, 8)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn store_u8(
+    mut env: FunctionEnvMut<ExtismKernel>,
+    offset: u64,
+    value: u32,
+) -> Result<(), RuntimeError> {
+    env.data_mut().bytes_mut(offset, 1)?[0] = value as u8;
+    Ok(())
+}
+
+fn store_u64(
+    mut env: FunctionEnvMut<ExtismKernel>,
+    offset: u64,
+    value: u64,
+) -> Result<(), RuntimeError> {
+    env.data_mut()
+        .bytes_mut(offset, 8)?
+        .copy_from_slice(&value.to_le_bytes());
+    Ok(())
+}
+
+fn input_offset(env: FunctionEnvMut<ExtismKernel>) -> u64 {
+    env.data().input.map(|(offset, _)| offset).unwrap_or(0)
+}
+
+fn input_length(env: FunctionEnvMut<ExtismKernel>) -> u64 {
+    env.data().input.map(|(_, length)| length).unwrap_or(0)
+}
+
+fn input_load_u8(env: FunctionEnvMut<ExtismKernel>, offset: u64) -> Result<u32, RuntimeError> {
+    let offset = input_offset_from(&env, offset, 1)?;
+    load_u8(env, offset)
+}
+
+fn input_load_u64(env: FunctionEnvMut<ExtismKernel>, offset: u64) -> Result<u64, RuntimeError> {
+    let offset = input_offset_from(&env, offset, 8)?;
+    load_u64(env, offset)
+}
+
+/// Translate an offset relative to the start of the input into an absolute offset. All `size` bytes from there need to be part of the input.
+fn input_offset_from(
+    env: &FunctionEnvMut<ExtismKernel>,
+    offset: u64,
+    size: u64,
+) -> Result<u64, RuntimeError> {
+    let (start, length) = env.data().input.unwrap_or((0, 0));
+    if offset.checked_add(size).is_none_or(|end| end > length) {
+        return Err(RuntimeError::new(format!(
+            "The plugin tried to read past the end of the input (offset {offset}, length {length})"
+        )));
+    }
+    Ok(start + offset)
+}
+
+fn output_set(
+    mut env: FunctionEnvMut<ExtismKernel>,
+    offset: u64,
+    length: u64,
+) -> Result<(), RuntimeError> {
+    let kernel = env.data_mut();
+    kernel.bytes(offset, length)?;
+    kernel.output = Some((offset, length));
+    Ok(())
+}
+
+fn error_set(mut env: FunctionEnvMut<ExtismKernel>, offset: u64) {
+    env.data_mut().error = (offset != 0).then_some(offset);
+}
+
+fn error_get(env: FunctionEnvMut<ExtismKernel>) -> u64 {
+    env.data().error.unwrap_or(0)
+}
+
+/// WASMlet has no plugin configuration or variables, so every lookup misses.
+fn config_get(_env: FunctionEnvMut<ExtismKernel>, _key: u64) -> u64 {
+    0
+}
+
+fn var_get(_env: FunctionEnvMut<ExtismKernel>, _key: u64) -> u64 {
+    0
+}
+
+fn var_set(_env: FunctionEnvMut<ExtismKernel>, _key: u64, _value: u64) {}
+
+/// Plugins are isolated and must not access the network.
+fn http_request(
+    _env: FunctionEnvMut<ExtismKernel>,
+    _request: u64,
+    _body: u64,
+) -> Result<u64, RuntimeError> {
+    Err(RuntimeError::new(
+        "Extism plugins are not allowed to make HTTP requests in WASMlet",
+    ))
+}
+
+fn http_status_code(_env: FunctionEnvMut<ExtismKernel>) -> i32 {
+    0
+}
+
+fn http_headers(_env: FunctionEnvMut<ExtismKernel>) -> u64 {
+    0
+}
+
+fn get_log_level(_env: FunctionEnvMut<ExtismKernel>) -> i32 {
+    match log::max_level() {
+        log::LevelFilter::Off => EXTISM_LOG_LEVEL_OFF,
+        log::LevelFilter::Error => EXTISM_LOG_LEVEL_ERROR,
+        log::LevelFilter::Warn => EXTISM_LOG_LEVEL_WARN,
+        log::LevelFilter::Info => EXTISM_LOG_LEVEL_INFO,
+        log::LevelFilter::Debug => EXTISM_LOG_LEVEL_DEBUG,
+        log::LevelFilter::Trace => EXTISM_LOG_LEVEL_TRACE,
+    }
+}
+
+fn log_message(
+    env: FunctionEnvMut<ExtismKernel>,
+    level: log::Level,
+    offset: u64,
+) -> Result<(), RuntimeError> {
+    let kernel = env.data();
+    log::log!(target: kernel.target.as_str(), level, "{}", kernel.block_string(offset)?);
+    Ok(())
+}
+
+fn log_trace(env: FunctionEnvMut<ExtismKernel>, offset: u64) -> Result<(), RuntimeError> {
+    log_message(env, log::Level::Trace, offset)
+}
+
+fn log_debug(env: FunctionEnvMut<ExtismKernel>, offset: u64) -> Result<(), RuntimeError> {
+    log_message(env, log::Level::Debug, offset)
+}
+
+fn log_info(env: FunctionEnvMut<ExtismKernel>, offset: u64) -> Result<(), RuntimeError> {
+    log_message(env, log::Level::Info, offset)
+}
+
+fn log_warn(env: FunctionEnvMut<ExtismKernel>, offset: u64) -> Result<(), RuntimeError> {
+    log_message(env, log::Level::Warn, offset)
+}
+
+fn log_error(env: FunctionEnvMut<ExtismKernel>, offset: u64) -> Result<(), RuntimeError> {
+    log_message(env, log::Level::Error, offset)
+}
+
+fn memory_bytes(env: FunctionEnvMut<ExtismKernel>) -> u64 {
+    env.data().memory.len() as u64
+}
+
+/// Free all blocks except for the input.
+fn reset(mut env: FunctionEnvMut<ExtismKernel>) {
+    let kernel = env.data_mut();
+    let input = kernel.input.map(|(offset, _)| offset);
+    let blocks: Vec<u64> = kernel
+        .blocks
+        .keys()
+        .copied()
+        .filter(|offset| Some(*offset) != input)
+        .collect();
+    for offset in blocks {
+        kernel.free(offset);
+    }
+}
+
+fn processing_trap(source: RuntimeError) -> ExecutionError {
+    ExecutionError::Trap {
+        operation: Operation::Processing,
+        source: wasmer_runtime::trap(source),
+    }
+}
+
+/// A plugin that uses the Extism ABI. Only a single exported function is used.
+pub struct ExtismPlugin {
+    function: TypedFunction<(), i32>,
+    kernel: FunctionEnv<ExtismKernel>,
+    store: Store,
+    instance: Instance,
+    max_output: u64,
+}
+
+impl ExtismPlugin {
+    /// Instantiate the plugin from a module compiled for `store` and look up the function `function_name`.
+    ///
+    /// The name is used as the log target for messages from the plugin.
+    pub fn new(
+        name: &str,
+        mut store: Store,
+        module: &Module,
+        function_name: &str,
+        config: &PluginConfig,
+    ) -> Result<Self, PluginError> {
+        let kernel = FunctionEnv::new(&mut store, ExtismKernel::new(name, config.max_output));
+        let imports = imports! {
+            "extism:host/env" => {
+                "alloc" => Function::new_typed_with_env(&mut store, &kernel, alloc),
+                "free" => Function::new_typed_with_env(&mut store, &kernel, free),
+                "length" => Function::new_typed_with_env(&mut store, &kernel, length),
+                "length_unsafe" => Function::new_typed_with_env(&mut store, &kernel, length),
+                "load_u8" => Function::new_typed_with_env(&mut store, &kernel, load_u8),
+                "load_u64" => Function::new_typed_with_env(&mut store, &kernel, load_u64),
+                "store_u8" => Function::new_typed_with_env(&mut store, &kernel, store_u8),
+                "store_u64" => Function::new_typed_with_env(&mut store, &kernel, store_u64),
+                "input_offset" => Function::new_typed_with_env(&mut store, &kernel, input_offset),
+                "input_length" => Function::new_typed_with_env(&mut store, &kernel, input_length),
+                "input_load_u8" => Function::new_typed_with_env(&mut store, &kernel, input_load_u8),
+                "input_load_u64" => Function::new_typed_with_env(&mut store, &kernel, input_load_u64),
+                "output_set" => Function::new_typed_with_env(&mut store, &kernel, output_set),
+                "error_set" => Function::new_typed_with_env(&mut store, &kernel, error_set),
+                "error_get" => Function::new_typed_with_env(&mut store, &kernel, error_get),
+                "config_get" => Function::new_typed_with_env(&mut store, &kernel, config_get),
+                "var_get" => Function::new_typed_with_env(&mut store, &kernel, var_get),
+                "var_set" => Function::new_typed_with_env(&mut store, &kernel, var_set),
+                "http_request" => Function::new_typed_with_env(&mut store, &kernel, http_request),
+                "http_status_code" => Function::new_typed_with_env(&mut store, &kernel, http_status_code),
+                "http_headers" => Function::new_typed_with_env(&mut store, &kernel, http_headers),
+                "get_log_level" => Function::new_typed_with_env(&mut store, &kernel, get_log_level),
+                "log_trace" => Function::new_typed_with_env(&mut store, &kernel, log_trace),
+                "log_debug" => Function::new_typed_with_env(&mut store, &kernel, log_debug),
+                "log_info" => Function::new_typed_with_env(&mut store, &kernel, log_info),
+                "log_warn" => Function::new_typed_with_env(&mut store, &kernel, log_warn),
+                "log_error" => Function::new_typed_with_env(&mut store, &kernel, log_error),
+                "memory_bytes" => Function::new_typed_with_env(&mut store, &kernel, memory_bytes),
+                "reset" => Function::new_typed_with_env(&mut store, &kernel, reset),
+            }
+        };
+        let instance = Instance::new(&mut store, module, &imports)
+            .map_err(|e| LinkError::Instantiation(Box::new(e)))?;
+
+        let function = instance
+            .exports
+            .get_typed_function::<(), i32>(&store, function_name)
+            .map_err(|e| LinkError::MissingFunction {
+                name: function_name.to_string(),
+                source: e.into(),
+            })?;
+
+        Ok(ExtismPlugin {
+            function,
+            kernel,
+            store,
+            instance,
+            max_output: config.max_output,
+        })
+    }
+
+    pub fn store_and_instance(&mut self) -> (&mut Store, &Instance) {
+        (&mut self.store, &self.instance)
+    }
+
+    /// Call the plugin function with the input as Extism input and return its output.
+    pub fn apply(&mut self, input: &[u8]) -> Result<Vec<u8>, PluginError> {
+        self.kernel.as_mut(&mut self.store).reset(input);
+
+        let exit_code = self
+            .function
+            .call(&mut self.store)
+            .map_err(processing_trap)?;
+
+        let kernel = self.kernel.as_ref(&self.store);
+        if let Some(error) = kernel.error {
+            let message = kernel.block_string(error).map_err(processing_trap)?;
+            return Err(ExecutionError::GuestError(message).into());
+        }
+        if exit_code != 0 {
+            return Err(ExecutionError::GuestError(format!(
+                "The plugin returned the exit code {exit_code}"
+            ))
+            .into());
+        }
+
+        let output = match kernel.output {
+            Some((_, length)) if length > self.max_output => {
+                return Err(ExecutionError::OutputTooLarge {
+                    length,
+                    limit: self.max_output,
+                }
+                .into());
+            }
+            Some((offset, length)) => kernel
+                .bytes(offset, length)
+                .map_err(processing_trap)?
+                .to_vec(),
+            None => Vec::new(),
+        };
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terminal::TerminalInfo;
+
+    /// Copies the input into a new block byte by byte and sets it as the output.
+    const ECHO: &str = "
+        (local.set $block (call $alloc (call $input_length)))
+        (block $done (loop $copy
+            (br_if $done (i64.eq (local.get $index) (call $input_length)))
+            (call $store_u8
+                (i64.add (local.get $block) (local.get $index))
+                (call $input_load_u8 (local.get $index)))
+            (local.set $index (i64.add (local.get $index) (i64.const 1)))
+            (br $copy)))
+        (call $output_set (local.get $block) (call $input_length))
+        (i32.const 0)";
+
+    /// Instantiate an Extism plugin whose function `run` has the given body.
+    fn plugin(body: &str, config: &PluginConfig) -> ExtismPlugin {
+        let wat = format!(
+            r#"(module
+                (import "extism:host/env" "alloc" (func $alloc (param i64) (result i64)))
+                (import "extism:host/env" "free" (func $free (param i64)))
+                (import "extism:host/env" "store_u8" (func $store_u8 (param i64 i32)))
+                (import "extism:host/env" "load_u64" (func $load_u64 (param i64) (result i64)))
+                (import "extism:host/env" "input_offset" (func $input_offset (result i64)))
+                (import "extism:host/env" "input_length" (func $input_length (result i64)))
+                (import "extism:host/env" "input_load_u8" (func $input_load_u8 (param i64) (result i32)))
+                (import "extism:host/env" "input_load_u64" (func $input_load_u64 (param i64) (result i64)))
+                (import "extism:host/env" "output_set" (func $output_set (param i64 i64)))
+                (import "extism:host/env" "error_set" (func $error_set (param i64)))
+                (func (export "run") (result i32) (local $block i64) (local $index i64) {body}))"#
+        );
+        let engine = wasmer_runtime::create_engine(config);
+        let module = wasmer_runtime::compile(&engine, wat.as_bytes(), false, config).unwrap();
+        ExtismPlugin::new("extism", Store::new(engine), &module, "run", config).unwrap()
+    }
+
+    #[test]
+    fn passes_input_and_output_through_the_kernel() {
+        let config = PluginConfig::new(TerminalInfo::STANDARD);
+        let mut echo = plugin(ECHO, &config);
+        assert_eq!(echo.apply(b"Hello").unwrap(), b"Hello");
+        // The kernel starts over for every call
+        assert_eq!(echo.apply(b"").unwrap(), b"");
+
+        let mut in_bounds = plugin(
+            "(call $output_set (i64.const 0) (i64.const 0))
+            (drop (call $input_load_u64 (i64.sub (call $input_length) (i64.const 8))))
+            (i32.const 0)",
+            &config,
+        );
+        assert_eq!(in_bounds.apply(b"Hello World").unwrap(), b"");
+    }
+
+    #[test]
+    fn reports_errors_of_the_plugin() {
+        let config = PluginConfig::new(TerminalInfo::STANDARD);
+        let mut failing = plugin(
+            "(local.set $block (call $alloc (i64.const 2)))
+            (call $store_u8 (local.get $block) (i32.const 110))
+            (call $store_u8 (i64.add (local.get $block) (i64.const 1)) (i32.const 111))
+            (call $error_set (local.get $block))
+            (i32.const 1)",
+            &config,
+        );
+        assert!(matches!(
+            failing.apply(b"Hello"),
+            Err(PluginError::Execution(ExecutionError::GuestError(message))) if message == "no"
+        ));
+
+        let mut exit_code = plugin("(i32.const 3)", &config);
+        assert_eq!(exit_code.apply(b"Hello").unwrap_err().kind(), "GuestError");
+    }
+
+    #[test]
+    fn rejects_accesses_outside_of_the_kernel_memory() {
+        let config = PluginConfig {
+            max_output: 1024,
+            ..PluginConfig::new(TerminalInfo::STANDARD)
+        };
+        let out_of_bounds = [
+            // Reads one byte past the input into the block after it
+            "(drop (call $alloc (i64.const 16)))
+            (drop (call $input_load_u64 (i64.sub (call $input_length) (i64.const 7))))
+            (i32.const 0)",
+            "(drop (call $input_load_u8 (call $input_length))) (i32.const 0)",
+            "(drop (call $load_u64 (i64.const 1000))) (i32.const 0)",
+            "(call $output_set (i64.const 0) (i64.const 1000)) (i32.const 0)",
+            // More than the input and the maximum output
+            "(drop (call $alloc (i64.const 2048))) (i32.const 0)",
+            "(drop (call $alloc (i64.const -1))) (i32.const 0)",
+        ];
+        for body in out_of_bounds {
+            let error = plugin(body, &config).apply(b"Hello World").unwrap_err();
+            assert_eq!(error.kind(), "Trap", "{body}: {error}");
+        }
+
+        // Freed blocks do not count toward the limit
+        let mut reusing = plugin(
+            "(block $done (loop $again
+                (br_if $done (i64.eq (local.get $index) (i64.const 10)))
+                (call $free (call $alloc (i64.const 1000)))
+                (local.set $index (i64.add (local.get $index) (i64.const 1)))
+                (br $again)))
+            (call $output_set (i64.const 0) (i64.const 0))
+            (i32.const 0)",
+            &config,
+        );
+        assert_eq!(reusing.apply(b"Hello World").unwrap(), b"");
+
+        let mut input_as_output = plugin(
+            "(call $output_set (call $input_offset) (call $input_length)) (i32.const 0)",
+            &config,
+        );
+        let error = input_as_output.apply(&[b'a'; 1025]).unwrap_err();
+        assert_eq!(error.kind(), "OutputTooLarge");
+    }
+}
@@ -0,0 +1,672 @@
+//! `wasmlet fuzz`: Throw random and mutated inputs at a plugin and keep the ones that break it.
+//!
+//! Every input is run through two instances of the plugin, so results that depend on hidden state show up as nondeterminism. Failing inputs are minimized and saved, so they can be replayed with `--replay`.
+//!
+//! With `--host` the mutated modules of [`crate::malicious`] are fed to the host instead, to find modules that crash WASMlet itself.
+
+use std::collections::HashMap;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::{CommandFactory, error::ErrorKind};
+use sha2::{Digest, Sha256};
+
+use crate::Args;
+use crate::content_types::{Payload, TEXT_PLAIN};
+use crate::error::{ExecutionError, PluginError};
+use crate::leaks;
+use crate::malicious::malicious_modules;
+use crate::plugin::{Plugin, PluginConfig, plugin_name};
+use crate::terminal::TerminalInfo;
+
+#[derive(clap::Args, Debug)]
+pub struct FuzzArgs {
+    /// The plugin to fuzz. Resolved like the plugins of a pipeline
+    #[arg(short, long, required_unless_present = "host")]
+    plugin: Option<String>,
+
+    /// How many inputs to try
+    #[arg(short = 'n', long, default_value_t = 1000)]
+    iterations: usize,
+
+    /// Seed for the random inputs. A random seed is used and printed if none is given
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// The number of wasm operators a single call may execute before it counts as a timeout
+    #[arg(long, default_value_t = 1_000_000_000)]
+    max_fuel: u64,
+
+    /// Directory with inputs that are mutated in addition to the generated ones
+    #[arg(long)]
+    corpus: Option<PathBuf>,
+
+    /// Directory the failing inputs are written to
+    #[arg(short, long, default_value = "fuzz-findings")]
+    output: PathBuf,
+
+    /// Run a single saved input instead of fuzzing
+    #[arg(long, value_name = "FILE")]
+    replay: Option<PathBuf>,
+
+    /// Fuzz the host with mutated malicious modules instead of a plugin
+    #[arg(long, conflicts_with = "plugin")]
+    host: bool,
+}
+
+/// How often a failing input is run while minimizing it.
+const MINIMIZE_ATTEMPTS: usize = 200;
+
+/// How often the plugin is called with the same input at the end, to look for memory that grows with every call.
+const LEAK_CHECK_CALLS: usize = 100;
+
+/// The fuel limit for the small modules of `--host`. They either finish quickly or loop forever.
+const HOST_FUEL_LIMIT: u64 = 1_000_000;
+
+/// A small and fast random number generator (SplitMix64). Good enough to pick inputs, and reproducible from a seed.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..bound`. `bound` must not be 0.
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+
+    pub fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+/// Characters that are easy to get wrong: multi-byte, zero width, combining, bidi overrides and control characters.
+const UNICODE_FRAGMENTS: [&str; 12] = [
+    "é",
+    "e\u{301}",
+    "世界",
+    "🦀",
+    "👩\u{200d}💻",
+    "\u{200b}",
+    "\u{feff}",
+    "\u{202e}",
+    "\u{fffd}",
+    "\0",
+    "\t",
+    "\r\n",
+];
+
+/// Escape sequences, including incomplete and absurd ones.
+const ESCAPE_FRAGMENTS: [&str; 8] = [
+    "\x1b[0m",
+    "\x1b[1;31m",
+    "\x1b[38;2;255;0;0m",
+    "\x1b[38;5;300m",
+    "\x1b[99999999999999999999m",
+    "\x1b]8;;https://example.com\x1b\\",
+    "\x1b[",
+    "\x1b",
+];
+
+/// Byte sequences that are not valid utf8: lone continuation bytes, overlong encodings, surrogates, code points beyond U+10FFFF and truncated sequences.
+const INVALID_UTF8: [&[u8]; 6] = [
+    &[0xff],
+    &[0x80],
+    &[0xc0, 0x80],
+    &[0xed, 0xa0, 0x80],
+    &[0xf4, 0x90, 0x80, 0x80],
+    &[0xe2, 0x82],
+];
+
+/// Lengths around the page size of wasm and typical allocator size classes.
+const EDGE_LENGTHS: [usize; 8] = [0, 1, 4095, 4096, 4097, 65535, 65536, 65537];
+
+/// A fragment of any of the interesting kinds.
+fn fragment(rng: &mut Rng) -> Vec<u8> {
+    match rng.below(4) {
+        0 => rng.pick(&UNICODE_FRAGMENTS).as_bytes().to_vec(),
+        1 => rng.pick(&ESCAPE_FRAGMENTS).as_bytes().to_vec(),
+        2 => rng.pick(&INVALID_UTF8).to_vec(),
+        _ => vec![b' ' + rng.below(95) as u8],
+    }
+}
+
+/// Create a new input, either from scratch or by mutating an entry of the corpus.
+pub fn generate(rng: &mut Rng, corpus: &[Vec<u8>]) -> Vec<u8> {
+    match rng.below(6) {
+        0 => (0..rng.below(256))
+            .map(|_| b' ' + rng.below(95) as u8)
+            .collect(),
+        1 => (0..rng.below(64)).flat_map(|_| fragment(rng)).collect(),
+        2 => (0..rng.below(256)).map(|_| rng.next_u64() as u8).collect(),
+        3 => {
+            let filler = *rng.pick(&[&b"a"[..], "é".as_bytes(), b"\x1b[0m"]);
+            let length = *rng.pick(&EDGE_LENGTHS);
+            filler.iter().copied().cycle().take(length).collect()
+        }
+        _ if !corpus.is_empty() => {
+            let input = rng.pick(corpus).clone();
+            mutate(rng, input, corpus)
+        }
+        _ => {
+            let input = (0..rng.below(32)).flat_map(|_| fragment(rng)).collect();
+            mutate(rng, input, corpus)
+        }
+    }
+}
+
+/// Apply a few random mutations to an input.
+pub fn mutate(rng: &mut Rng, mut input: Vec<u8>, corpus: &[Vec<u8>]) -> Vec<u8> {
+    for _ in 0..=rng.below(4) {
+        let position = rng.below(input.len() + 1);
+        match rng.below(7) {
+            0 if !input.is_empty() => {
+                let index = position.min(input.len() - 1);
+                input[index] ^= 1 << rng.below(8);
+            }
+            1 if !input.is_empty() => {
+                let index = position.min(input.len() - 1);
+                input[index] = rng.next_u64() as u8;
+            }
+            2 => {
+                let end = position + rng.below(input.len() - position + 1);
+                input.drain(position..end);
+            }
+            3 => {
+                let end = position + rng.below(input.len() - position + 1);
+                let copy = input[position..end].to_vec();
+                input.splice(position..position, copy);
+            }
+            4 => input.truncate(position),
+            5 if !corpus.is_empty() => {
+                let other = rng.pick(corpus);
+                let start = rng.below(other.len() + 1);
+                input.splice(position..position, other[start..].iter().copied());
+            }
+            _ => {
+                let fragment = fragment(rng);
+                input.splice(position..position, fragment);
+            }
+        }
+    }
+    input
+}
+
+/// Remove as much of the input as possible while it still fails.
+///
+/// Tries to remove chunks of decreasing size, down to single bytes, like delta debugging. Gives up after `attempts` runs of `fails`.
+pub fn minimize(input: &[u8], mut fails: impl FnMut(&[u8]) -> bool, attempts: usize) -> Vec<u8> {
+    let mut current = input.to_vec();
+    let mut remaining = attempts;
+    let mut chunk = current.len().div_ceil(2).max(1);
+    while !current.is_empty() && remaining > 0 {
+        let mut reduced = false;
+        let mut start = 0;
+        while start < current.len() && remaining > 0 {
+            let end = (start + chunk).min(current.len());
+            let candidate = [&current[..start], &current[end..]].concat();
+            remaining -= 1;
+            if fails(&candidate) {
+                current = candidate;
+                reduced = true;
+            } else {
+                start = end;
+            }
+        }
+        if !reduced {
+            if chunk == 1 {
+                break;
+            }
+            chunk /= 2;
+        }
+    }
+    current
+}
+
+/// What is wrong with a plugin.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum FindingKind {
+    Trap,
+    Timeout,
+    MalformedResult,
+    InvalidUtf8,
+    Leak,
+    Nondeterminism,
+}
+
+impl FindingKind {
+    fn name(self) -> &'static str {
+        match self {
+            FindingKind::Trap => "trap",
+            FindingKind::Timeout => "timeout",
+            FindingKind::MalformedResult => "malformed-result",
+            FindingKind::InvalidUtf8 => "invalid-utf8",
+            FindingKind::Leak => "leak",
+            FindingKind::Nondeterminism => "nondeterminism",
+        }
+    }
+}
+
+struct Finding {
+    kind: FindingKind,
+    message: String,
+}
+
+/// Decide whether the result of a call is a bug in the plugin.
+///
+/// Plugins may reject inputs with an error and produce too much output, but must not crash, loop forever or break the ABI. Invalid utf8 in the output is only a bug if the input was valid utf8.
+fn classify(result: &Result<Payload, PluginError>, input: &[u8]) -> Option<FindingKind> {
+    let Err(PluginError::Execution(error)) = result else {
+        return None;
+    };
+    match error {
+        ExecutionError::GuestError(_) | ExecutionError::OutputTooLarge { .. } => None,
+        ExecutionError::Trap { .. } | ExecutionError::Panicked { .. } => Some(FindingKind::Trap),
+        ExecutionError::FuelExhausted { .. } => Some(FindingKind::Timeout),
+        ExecutionError::OutputIsNotUtf8(_) if std::str::from_utf8(input).is_ok() => {
+            Some(FindingKind::InvalidUtf8)
+        }
+        ExecutionError::OutputIsNotUtf8(_) => None,
+        ExecutionError::FailedToFreeSharedBuffer
+        | ExecutionError::NullResult
+        | ExecutionError::ResultOutOfBounds { .. }
+        | ExecutionError::InvalidSuccessFlag(_)
+        | ExecutionError::ResultLengthOutOfBounds { .. }
+        | ExecutionError::ResultOverlapsInput { .. }
+        | ExecutionError::InvalidInputBuffer(_) => Some(FindingKind::MalformedResult),
+    }
+}
+
+/// Whether two calls had the same outcome.
+fn same_outcome(a: &Result<Payload, PluginError>, b: &Result<Payload, PluginError>) -> bool {
+    match (a, b) {
+        (Ok(a), Ok(b)) => a == b,
+        (Err(a), Err(b)) => a.kind() == b.kind(),
+        _ => false,
+    }
+}
+
+/// The instances an input is run through.
+struct Target {
+    primary: Plugin,
+    /// Gets the same inputs as the primary instance. Its results should be identical.
+    shadow: Plugin,
+}
+
+impl Target {
+    /// Fresh instances of the compiled plugin, so it is only resolved and compiled once.
+    fn new(plugin: &Plugin) -> Self {
+        Target {
+            primary: plugin.duplicate(),
+            shadow: plugin.duplicate(),
+        }
+    }
+
+    fn run(&mut self, input: &[u8]) -> Option<Finding> {
+        // Plugins that produce binary content may return anything, the others need to return utf8
+        let input = Payload::new(input.to_vec(), TEXT_PLAIN);
+        let result = self.primary.apply_payload(&input);
+        if let Some(kind) = classify(&result, &input.bytes) {
+            let message = result.err().map(|error| error.to_string());
+            return Some(Finding {
+                kind,
+                message: message.unwrap_or_default(),
+            });
+        }
+        if let Some(buffers) = self
+            .primary
+            .outstanding_buffers()
+            .filter(|buffers| *buffers > 0)
+        {
+            return Some(Finding {
+                kind: FindingKind::Leak,
+                message: format!("{buffers} shared buffers were not freed"),
+            });
+        }
+        let shadow_result = self.shadow.apply_payload(&input);
+        if !same_outcome(&result, &shadow_result) {
+            let describe = |result: &Result<Payload, PluginError>| match result {
+                Ok(output) => match output.as_text() {
+                    Some(text) => format!("{text:?}"),
+                    None => format!("{:?}", output.bytes),
+                },
+                Err(error) => error.kind().to_string(),
+            };
+            return Some(Finding {
+                kind: FindingKind::Nondeterminism,
+                message: format!(
+                    "Two instances returned {} and {}",
+                    describe(&result),
+                    describe(&shadow_result)
+                ),
+            });
+        }
+        None
+    }
+}
+
+/// Read all files of the corpus directory.
+fn read_corpus(directory: &Path) -> Vec<Vec<u8>> {
+    let entries = std::fs::read_dir(directory).unwrap_or_else(|error| {
+        Args::command()
+            .error(
+                ErrorKind::Io,
+                format!("Failed to read corpus {}: {error}", directory.display()),
+            )
+            .exit()
+    });
+    entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().is_file())
+        .filter_map(|entry| std::fs::read(entry.path()).ok())
+        .collect()
+}
+
+/// Write a finding to the output directory. The name contains a hash of the content, so the same input is only saved once.
+fn save(output: &Path, name: &str, kind: &str, extension: &str, content: &[u8]) -> PathBuf {
+    let hash = format!("{:x}", Sha256::digest(content));
+    let path = output.join(format!("{name}-{kind}-{}.{extension}", &hash[..12]));
+    if let Err(error) = std::fs::create_dir_all(output).and_then(|_| std::fs::write(&path, content))
+    {
+        eprintln!("Failed to save {}: {error}", path.display());
+    }
+    path
+}
+
+fn read_file(path: &Path) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|error| {
+        Args::command()
+            .error(
+                ErrorKind::Io,
+                format!("Failed to read {}: {error}", path.display()),
+            )
+            .exit()
+    })
+}
+
+fn exit_with(error: PluginError) -> ExitCode {
+    let exit_code = error.category().exit_code();
+    eprintln!("{:?}", miette::Report::new(error));
+    exit_code.into()
+}
+
+/// Run a saved input once with fresh instances.
+fn replay(specifier: &str, path: &Path, config: &PluginConfig) -> ExitCode {
+    let input = read_file(path);
+    let plugin = match Plugin::new(specifier, config) {
+        Ok(plugin) => plugin,
+        Err(error) => return exit_with(error),
+    };
+    match Target::new(&plugin).run(&input) {
+        Some(finding) => {
+            println!("{}: {}", finding.kind.name(), finding.message);
+            ExitCode::FAILURE
+        }
+        None => {
+            println!("No problems with {}", path.display());
+            ExitCode::SUCCESS
+        }
+    }
+}
+
+fn fuzz_plugin(args: &FuzzArgs, specifier: &str, rng: &mut Rng) -> ExitCode {
+    let config = PluginConfig {
+        fuel_limit: Some(args.max_fuel),
+        ..PluginConfig::new(TerminalInfo::STANDARD)
+    };
+    if let Some(path) = &args.replay {
+        return replay(specifier, path, &config);
+    }
+    let corpus = args.corpus.as_deref().map(read_corpus).unwrap_or_default();
+    let name = plugin_name(specifier);
+    let plugin = match Plugin::new(specifier, &config) {
+        Ok(plugin) => plugin,
+        Err(error) => return exit_with(error),
+    };
+    let mut target = Target::new(&plugin);
+
+    // Only the first input of every kind is saved, the others are counted
+    let mut findings: HashMap<FindingKind, usize> = HashMap::new();
+    let mut report = |kind: FindingKind, message: &str, input: &[u8], minimized: &[u8]| {
+        let count = findings.entry(kind).or_default();
+        *count += 1;
+        if *count > 1 {
+            return;
+        }
+        let path = save(&args.output, name, kind.name(), "bin", minimized);
+        println!("Found {}: {message}", kind.name());
+        println!(
+            "  Saved to {} ({} bytes, minimized from {})",
+            path.display(),
+            minimized.len(),
+            input.len()
+        );
+        println!(
+            "  Replay with `wasmlet fuzz -p {specifier} --replay {}`",
+            path.display()
+        );
+    };
+
+    for _ in 0..args.iterations {
+        let input = generate(rng, &corpus);
+        let Some(finding) = target.run(&input) else {
+            continue;
+        };
+        let minimized = minimize(
+            &input,
+            |candidate| {
+                Target::new(&plugin)
+                    .run(candidate)
+                    .is_some_and(|other| other.kind == finding.kind)
+            },
+            MINIMIZE_ATTEMPTS,
+        );
+        report(finding.kind, &finding.message, &input, &minimized);
+        // The instances might be broken after a trap, so every finding starts over with fresh ones
+        target = Target::new(&plugin);
+    }
+
+    let input = "Hello World";
+    if let Ok(leaks) = leaks::check_leaks(&mut target.primary, input.as_bytes(), LEAK_CHECK_CALLS) {
+        if leaks.growth_per_call > 0.0 {
+            report(
+                FindingKind::Leak,
+                &format!(
+                    "The memory grows by {:.2} pages per call",
+                    leaks.growth_per_call
+                ),
+                input.as_bytes(),
+                input.as_bytes(),
+            );
+        }
+    }
+
+    let total: usize = findings.values().sum();
+    println!(
+        "Tried {} inputs, found {} problems ({total} failing inputs)",
+        args.iterations,
+        findings.len()
+    );
+    if findings.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Run a module with an input and turn a panic of the host into an error message.
+fn run_module(wasm: &[u8], input: &[u8], config: &PluginConfig) -> Result<(), String> {
+    catch_unwind(AssertUnwindSafe(|| {
+        let _ = Plugin::from_wasm("fuzz", wasm, config)
+            .and_then(|mut plugin| plugin.apply_bytes(input));
+    }))
+    .map_err(|payload| {
+        payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "Unknown panic".to_string())
+    })
+}
+
+fn fuzz_host(args: &FuzzArgs, rng: &mut Rng) -> ExitCode {
+    let config = PluginConfig {
+        fuel_limit: Some(HOST_FUEL_LIMIT),
+        ..PluginConfig::new(TerminalInfo::STANDARD)
+    };
+    if let Some(path) = &args.replay {
+        let input = std::fs::read(path.with_extension("bin")).unwrap_or_default();
+        return match run_module(&read_file(path), &input, &config) {
+            Ok(()) => {
+                println!("The host handled {}", path.display());
+                ExitCode::SUCCESS
+            }
+            Err(message) => {
+                println!("The host panicked: {message}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let mut modules = Vec::new();
+    let mut failures = 0;
+    for module in malicious_modules() {
+        let result = Plugin::from_wasm(module.name, module.wat.as_bytes(), &config)
+            .and_then(|mut plugin| plugin.apply("Hello"));
+        let kind = result.as_ref().err().map(|error| error.kind());
+        if kind != module.expected {
+            println!(
+                "The module `{}` should fail with {:?}, but got {kind:?}",
+                module.name, module.expected
+            );
+            failures += 1;
+        }
+        match wasmer::wat2wasm(module.wat.as_bytes()) {
+            Ok(wasm) => modules.push(wasm.into_owned()),
+            Err(error) => println!("The module `{}` is not valid: {error}", module.name),
+        }
+    }
+
+    // Panics are reported as findings, the default hook would print every one of them
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let mut panics = HashMap::new();
+    for _ in 0..args.iterations {
+        let module = rng.pick(&modules).clone();
+        let wasm = mutate_module(rng, module);
+        let input = generate(rng, &[]);
+        let Err(message) = run_module(&wasm, &input, &config) else {
+            continue;
+        };
+        let count = panics.entry(message.clone()).or_insert(0);
+        *count += 1;
+        if *count == 1 {
+            let path = save(&args.output, "host", "panic", "wasm", &wasm);
+            let _ = std::fs::write(path.with_extension("bin"), &input);
+            println!("The host panicked: {message}");
+            println!(
+                "  Saved to {}, replay with `wasmlet fuzz --host --replay {}`",
+                path.display(),
+                path.display()
+            );
+        }
+    }
+    std::panic::set_hook(hook);
+
+    println!(
+        "Tried {} modules, the host panicked {} times",
+        args.iterations,
+        panics.values().sum::<usize>()
+    );
+    if failures == 0 && panics.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Change a few bytes of a module. Most mutations make the module invalid, which exercises the error paths of the compiler. The rest change constants and offsets.
+fn mutate_module(rng: &mut Rng, mut wasm: Vec<u8>) -> Vec<u8> {
+    // Keep the magic number and version, otherwise the module is rejected right away
+    const HEADER: usize = 8;
+    for _ in 0..=rng.below(3) {
+        let index = HEADER + rng.below(wasm.len() - HEADER);
+        match rng.below(3) {
+            0 => wasm[index] ^= 1 << rng.below(8),
+            1 => wasm[index] = *rng.pick(&[0x00, 0x01, 0x7f, 0x80, 0xff]),
+            _ => wasm[index] = rng.next_u64() as u8,
+        }
+    }
+    wasm
+}
+
+pub fn fuzz(args: FuzzArgs) -> ExitCode {
+    let seed = args.seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64)
+    });
+    println!("Fuzzing with seed {seed}");
+    let mut rng = Rng::new(seed);
+    match &args.plugin {
+        Some(specifier) => fuzz_plugin(&args, specifier, &mut rng),
+        None => fuzz_host(&args, &mut rng),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minimizes_to_the_failing_bytes() {
+        let input = b"Hello \xff World, this is a long input";
+        let minimized = minimize(input, |candidate| candidate.contains(&0xff), 1000);
+        assert_eq!(minimized, [0xff]);
+
+        let minimized = minimize(
+            b"xxaxxxbxx",
+            |candidate| candidate.contains(&b'a') && candidate.contains(&b'b'),
+            1000,
+        );
+        assert_eq!(minimized, b"ab");
+    }
+
+    #[test]
+    fn minimizing_stops_after_the_attempts() {
+        let mut runs = 0;
+        minimize(
+            &[0; 100],
+            |_| {
+                runs += 1;
+                false
+            },
+            10,
+        );
+        assert_eq!(runs, 10);
+    }
+
+    #[test]
+    fn the_same_seed_generates_the_same_inputs() {
+        let corpus = vec![b"Hello World".to_vec()];
+        let inputs = |seed| {
+            let mut rng = Rng::new(seed);
+            (0..100)
+                .map(|_| generate(&mut rng, &corpus))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(inputs(42), inputs(42));
+        assert_ne!(inputs(42), inputs(43));
+    }
+}
@@ -0,0 +1,511 @@
+//! `wasmlet test`: Run golden file tests through the real host.
+//!
+//! A test directory contains cases named `<name>.in`. The expected output of a case is in `<name>.out`, or `<name>.err` if the pipeline is expected to fail. `<name>.options` can change the settings for a case with `key=value` lines.
+//!
+//! The pipeline is given with `-p` or listed in a `pipeline` file in the test directory, one specifier per line. Without directories, the `tests` directory next to the plugin or the directories with a `pipeline` file are used, see [`discover_directories`].
+//!
+//! With `--differential` every case is also run through the native builds of the plugins, which have to produce the same result as the wasm builds.
+
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use glob::glob;
+
+use crate::color;
+use crate::content_types::{self, Payload};
+use crate::diff::format_diff;
+use crate::error::StageError;
+use crate::native::{NativePlugin, find_native_library};
+use crate::options;
+use crate::plugin::{PluginConfig, load_pipeline, plugin_name, split_fragment};
+use crate::terminal::{ColorDepth, TerminalInfo};
+
+#[derive(clap::Args, Debug)]
+pub struct TestArgs {
+    /// Directories with test cases, or the `pipeline` files in them. Defaults to the `tests` directory next to the plugin given with `-p`, or to every `tests` directory with a `pipeline` file in the current directory or a crate next to this project
+    directories: Vec<PathBuf>,
+
+    /// WASM plugins that form the pipeline. Defaults to the plugins listed in the `pipeline` file of each test directory
+    #[arg(short, long)]
+    plugins: Vec<String>,
+
+    /// Write the actual results to the expectation files instead of comparing them
+    #[arg(long)]
+    bless: bool,
+
+    /// Also run the native builds of the plugins and fail if their results differ from the wasm builds
+    #[arg(long, conflicts_with = "bless")]
+    differential: bool,
+
+    /// Native builds of the plugins for `--differential`, in the order of the pipeline. Defaults to the native build in the crate of each plugin
+    #[arg(long, requires = "differential")]
+    native: Vec<PathBuf>,
+}
+
+/// Apply the `key=value` lines of an options file to the default settings.
+///
+/// Supported keys are `width`, `height`, `color` (`none`, `16`, `256` or `truecolor`), `tty`, `max-output` and `lossy-utf8`.
+pub fn parse_options(options: &str, mut config: PluginConfig) -> Result<PluginConfig, String> {
+    for line in options.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("Expected `key=value`, got `{line}`"))?;
+        let (key, value) = (key.trim(), value.trim());
+        let invalid = || format!("Invalid value `{value}` for `{key}`");
+        let boolean = || match value {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            _ => Err(invalid()),
+        };
+        match key {
+            "width" => config.terminal.width = Some(value.parse().map_err(|_| invalid())?),
+            "height" => config.terminal.height = Some(value.parse().map_err(|_| invalid())?),
+            "color" => {
+                config.terminal.color_depth = match value {
+                    "none" => ColorDepth::None,
+                    "16" => ColorDepth::Ansi16,
+                    "256" => ColorDepth::Ansi256,
+                    "truecolor" => ColorDepth::TrueColor,
+                    _ => return Err(invalid()),
+                }
+            }
+            "tty" => config.terminal.is_tty = boolean()?,
+            "max-output" => config.max_output = value.parse().map_err(|_| invalid())?,
+            "lossy-utf8" => config.lossy_utf8 = boolean()?,
+            _ => return Err(format!("Unknown option `{key}`")),
+        }
+    }
+    Ok(config)
+}
+
+/// Run the input through the pipeline like `wasmlet` would and return the content of the expectation file.
+///
+/// The second value is the extension of the expectation file, `out` or `err`.
+fn run_case(specifiers: &[String], input: &str, config: &PluginConfig) -> (String, &'static str) {
+    format_result(run_pipeline(specifiers, input, config), config)
+}
+
+/// Load the stages, check that their content types fit and pass the input through them, like `wasmlet` does.
+fn run_pipeline(
+    specifiers: &[String],
+    input: &str,
+    config: &PluginConfig,
+) -> Result<Payload, StageError> {
+    let stage_error = |index: usize| {
+        move |error| StageError {
+            stage: index,
+            specifier: specifiers[index].clone(),
+            error,
+        }
+    };
+    let mut plugins = Vec::new();
+    for (index, plugin) in load_pipeline(specifiers, config).into_iter().enumerate() {
+        plugins.push(plugin.map_err(stage_error(index))?);
+    }
+    content_types::check_pipeline(specifiers, &plugins)?;
+
+    let mut payload = Payload::text(input);
+    for (index, plugin) in plugins.iter_mut().enumerate() {
+        payload = plugin.apply_payload(&payload).map_err(stage_error(index))?;
+    }
+    for (index, plugin) in plugins.iter_mut().enumerate() {
+        plugin.shutdown().map_err(stage_error(index))?;
+    }
+    Ok(payload)
+}
+
+/// Run the input through the native builds of the plugins. The result is formatted like the one of [`run_case`].
+fn run_native_case(
+    specifiers: &[String],
+    libraries: &[PathBuf],
+    input: &str,
+    config: &PluginConfig,
+) -> (String, &'static str) {
+    let result =
+        specifiers
+            .iter()
+            .enumerate()
+            .try_fold(input.to_string(), |text, (index, specifier)| {
+                NativePlugin::new(&libraries[index], specifier, config)
+                    .and_then(|mut plugin| plugin.apply(text.as_bytes()))
+                    .map_err(|error| StageError {
+                        stage: index,
+                        specifier: specifier.clone(),
+                        error,
+                    })
+            });
+    format_result(result.map(Payload::text), config)
+}
+
+/// Format the result of a pipeline like an expectation file.
+fn format_result(
+    result: Result<Payload, StageError>,
+    config: &PluginConfig,
+) -> (String, &'static str) {
+    match result {
+        Ok(payload) => {
+            let output = match payload.as_text() {
+                Some(text) => color::downgrade(text, config.terminal.color_depth),
+                None => String::from_utf8_lossy(&payload.bytes).into_owned(),
+            };
+            (format!("{output}\n"), "out")
+        }
+        Err(error) => (
+            format!(
+                "stage {}: {}\n{}\n",
+                error.stage,
+                error.error.kind(),
+                error.error
+            ),
+            "err",
+        ),
+    }
+}
+
+/// Read a file if it exists.
+fn read_optional(path: &Path) -> Result<Option<String>, String> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(format!("Failed to read {}: {error}", path.display())),
+    }
+}
+
+/// The result of a single test case.
+enum Outcome {
+    Passed,
+    Blessed,
+    Failed(String),
+}
+
+/// Run a single case. With `native` libraries, the native builds have to produce the same result.
+fn test_case(
+    input_path: &Path,
+    specifiers: &[String],
+    native: Option<&[PathBuf]>,
+    bless: bool,
+) -> Result<Outcome, String> {
+    let input = std::fs::read_to_string(input_path)
+        .map_err(|error| format!("Failed to read {}: {error}", input_path.display()))?;
+    // Editors add a newline at the end of files, but the text given on the command line has none
+    let input = input.strip_suffix('\n').unwrap_or(&input);
+
+    let default_config = PluginConfig::new(TerminalInfo::STANDARD);
+    let config = match read_optional(&input_path.with_extension("options"))? {
+        Some(options) => parse_options(&options, default_config)?,
+        None => default_config,
+    };
+
+    let (actual, extension) = run_case(specifiers, input, &config);
+    if let Some(libraries) = native {
+        let (native_actual, _) = run_native_case(specifiers, libraries, input, &config);
+        if native_actual != actual {
+            return Ok(Outcome::Failed(format!(
+                "The native build (+) differs from the wasm build (-):\n{}",
+                format_diff(&actual, &native_actual)
+            )));
+        }
+    }
+    let expectation_path = input_path.with_extension(extension);
+    let other_path = input_path.with_extension(if extension == "out" { "err" } else { "out" });
+
+    if bless {
+        std::fs::write(&expectation_path, &actual)
+            .map_err(|error| format!("Failed to write {}: {error}", expectation_path.display()))?;
+        if other_path.exists() {
+            std::fs::remove_file(&other_path)
+                .map_err(|error| format!("Failed to remove {}: {error}", other_path.display()))?;
+        }
+        return Ok(Outcome::Blessed);
+    }
+
+    let expected = match read_optional(&expectation_path)? {
+        Some(expected) => expected,
+        None if other_path.exists() => {
+            let expected = read_optional(&other_path)?.unwrap_or_default();
+            return Ok(Outcome::Failed(format_diff(&expected, &actual)));
+        }
+        None => {
+            return Ok(Outcome::Failed(format!(
+                "{} is missing, run with `--bless` to create it\n",
+                expectation_path.display()
+            )));
+        }
+    };
+    let trim = |text: &str| text.strip_suffix('\n').unwrap_or(text).to_string();
+    if trim(&expected) == trim(&actual) {
+        Ok(Outcome::Passed)
+    } else {
+        Ok(Outcome::Failed(format_diff(&expected, &actual)))
+    }
+}
+
+/// Read the plugins from the `pipeline` file of a test directory.
+fn read_pipeline(directory: &Path) -> Result<Vec<String>, String> {
+    let path = directory.join("pipeline");
+    let pipeline = read_optional(&path)?.ok_or_else(|| {
+        format!(
+            "No plugins given with `-p` and {} does not exist",
+            path.display()
+        )
+    })?;
+    Ok(pipeline
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// The `tests` directory next to a plugin: Next to the file if the specifier is a path, otherwise in the rust crate next to this project, like step 5 of the plugin resolution.
+fn plugin_tests_directory(specifier: &str) -> PathBuf {
+    let (path, _) = split_fragment(options::split(specifier).0);
+    let path = Path::new(path);
+    match path.parent() {
+        Some(parent) if path.is_file() => parent.join("tests"),
+        _ => PathBuf::from(format!("../{}/tests", plugin_name(specifier))),
+    }
+}
+
+/// Find the test directories if none are given.
+///
+/// A single plugin given with `-p` is tested with the cases next to it. A pipeline of several plugins has no such place, so its directory has to be given. Without `-p`, every `tests` directory with a `pipeline` file is used, in the current directory and in the crates next to this project.
+fn discover_directories(plugins: &[String]) -> Result<Vec<PathBuf>, String> {
+    match plugins {
+        [plugin] => Ok(vec![plugin_tests_directory(plugin)]),
+        [] => {
+            let patterns = ["tests/pipeline", "../*/tests/pipeline"];
+            let directories: Vec<PathBuf> = patterns
+                .iter()
+                .filter_map(|pattern| glob(pattern).ok())
+                .flat_map(|paths| paths.filter_map(Result::ok))
+                .filter_map(|pipeline| pipeline.parent().map(Path::to_path_buf))
+                .collect();
+            if directories.is_empty() {
+                return Err(format!(
+                    "No plugins given with `-p` and no test directory found at `{}`",
+                    patterns.join("`, `")
+                ));
+            }
+            Ok(directories)
+        }
+        _ => Err("Give the test directory of a pipeline with several plugins".to_string()),
+    }
+}
+
+/// Find the native builds for `--differential`. Paths given with `--native` take precedence.
+fn native_libraries(specifiers: &[String], native: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    if native.is_empty() {
+        return specifiers
+            .iter()
+            .map(|specifier| find_native_library(specifier).map_err(|error| error.to_string()))
+            .collect();
+    }
+    if native.len() != specifiers.len() {
+        return Err(format!(
+            "The pipeline has {} plugins, but {} native builds were given",
+            specifiers.len(),
+            native.len()
+        ));
+    }
+    Ok(native.to_vec())
+}
+
+pub fn test(args: TestArgs) -> ExitCode {
+    let directories = if args.directories.is_empty() {
+        match discover_directories(&args.plugins) {
+            Ok(directories) => directories,
+            Err(message) => {
+                eprintln!("{message}");
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        // A `pipeline` file stands for the directory it is in
+        args.directories
+            .iter()
+            .map(|path| match path.parent() {
+                Some(parent) if path.is_file() && path.ends_with("pipeline") => {
+                    parent.to_path_buf()
+                }
+                _ => path.clone(),
+            })
+            .collect()
+    };
+
+    let (mut passed, mut failed, mut blessed) = (0, 0, 0);
+    for directory in &directories {
+        if !directory.is_dir() {
+            eprintln!("{} is not a directory", directory.display());
+            failed += 1;
+            continue;
+        }
+        let specifiers = if args.plugins.is_empty() {
+            match read_pipeline(directory) {
+                Ok(specifiers) => specifiers,
+                Err(message) => {
+                    eprintln!("{message}");
+                    failed += 1;
+                    continue;
+                }
+            }
+        } else {
+            args.plugins.clone()
+        };
+        let libraries = if args.differential {
+            match native_libraries(&specifiers, &args.native) {
+                Ok(libraries) => Some(libraries),
+                Err(message) => {
+                    eprintln!("{message}");
+                    failed += 1;
+                    continue;
+                }
+            }
+        } else {
+            None
+        };
+
+        let pattern = directory.join("*.in");
+        let mut cases: Vec<PathBuf> = glob(&pattern.to_string_lossy())
+            .map(|paths| paths.filter_map(Result::ok).collect())
+            .unwrap_or_default();
+        cases.sort();
+        // A directory without cases is most likely a typo, which should not look like a passing run
+        if cases.is_empty() {
+            eprintln!("No test cases found in {}", directory.display());
+            failed += 1;
+        }
+
+        for case in cases {
+            let name = case.with_extension("");
+            match test_case(&case, &specifiers, libraries.as_deref(), args.bless) {
+                Ok(Outcome::Passed) => {
+                    println!("{} ... ok", name.display());
+                    passed += 1;
+                }
+                Ok(Outcome::Blessed) => {
+                    println!("{} ... blessed", name.display());
+                    blessed += 1;
+                }
+                Ok(Outcome::Failed(diff)) => {
+                    println!("{} ... FAILED\n{diff}", name.display());
+                    failed += 1;
+                }
+                Err(message) => {
+                    println!("{} ... FAILED\n{message}\n", name.display());
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    if args.bless {
+        println!("{blessed} blessed, {failed} failed");
+    } else {
+        println!("{passed} passed, {failed} failed");
+    }
+    if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::native::build_example;
+
+    #[test]
+    fn parses_options() {
+        let config = parse_options(
+            "# A narrow terminal\nwidth=40\ncolor = 256\ntty=false\nlossy-utf8=true\n",
+            PluginConfig::new(TerminalInfo::STANDARD),
+        )
+        .unwrap();
+        assert_eq!(config.terminal.width, Some(40));
+        assert_eq!(config.terminal.height, TerminalInfo::STANDARD.height);
+        assert_eq!(config.terminal.color_depth, ColorDepth::Ansi256);
+        assert!(!config.terminal.is_tty);
+        assert!(config.lossy_utf8);
+    }
+
+    #[test]
+    fn finds_the_tests_next_to_a_plugin() {
+        assert_eq!(
+            plugin_tests_directory("rainbow"),
+            Path::new("../rainbow/tests")
+        );
+        assert_eq!(
+            plugin_tests_directory("effects#gradient:speed=2"),
+            Path::new("../effects/tests")
+        );
+        assert_eq!(
+            plugin_tests_directory("src/main.rs"),
+            Path::new("src/tests")
+        );
+        assert!(discover_directories(&["a".to_string(), "b".to_string()]).is_err());
+    }
+
+    #[test]
+    fn cases_of_the_example_plugins_are_complete() {
+        let directories = discover_directories(&[]).unwrap();
+        for plugin in ["rainbow", "bigfont"] {
+            assert!(
+                directories.contains(&plugin_tests_directory(plugin)),
+                "{plugin}"
+            );
+        }
+        for directory in directories {
+            assert!(!read_pipeline(&directory).unwrap().is_empty());
+            for case in glob(&directory.join("*.in").to_string_lossy()).unwrap() {
+                let case = case.unwrap();
+                let expectations =
+                    ["out", "err"].map(|extension| case.with_extension(extension).exists());
+                assert_eq!(
+                    expectations.iter().filter(|&&exists| exists).count(),
+                    1,
+                    "{}",
+                    case.display()
+                );
+                if let Some(options) = read_optional(&case.with_extension("options")).unwrap() {
+                    parse_options(&options, PluginConfig::new(TerminalInfo::STANDARD)).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "builds ../rainbow with cargo"]
+    fn native_builds_pass_the_cases_of_rainbow() {
+        let library = build_example("rainbow");
+        let directory = plugin_tests_directory("rainbow");
+        let specifiers = read_pipeline(&directory).unwrap();
+        for case in glob(&directory.join("*.in").to_string_lossy()).unwrap() {
+            let case = case.unwrap();
+            match test_case(
+                &case,
+                &specifiers,
+                Some(std::slice::from_ref(&library)),
+                false,
+            )
+            .unwrap()
+            {
+                Outcome::Passed => {}
+                Outcome::Blessed => unreachable!("The case is not blessed"),
+                Outcome::Failed(diff) => panic!("{} failed:\n{diff}", case.display()),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_options() {
+        let config = PluginConfig::new(TerminalInfo::STANDARD);
+        assert!(parse_options("colour=none", config).is_err());
+        assert!(parse_options("tty=yes", config).is_err());
+        assert!(parse_options("width", config).is_err());
+    }
+}
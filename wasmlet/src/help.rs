@@ -0,0 +1,165 @@
+//! `wasmlet help <plugin>`: Show what a plugin does, which transforms and options it takes and which content types it works with.
+//!
+//! Everything comes from the custom sections of the module, so the plugin is not run.
+
+use std::process::ExitCode;
+
+use serde::Serialize;
+
+use crate::OutputFormat;
+use crate::content_types::{self, ContentTypes};
+use crate::error::PluginError;
+use crate::interface::ModuleInterface;
+use crate::metadata::{self, Metadata};
+use crate::options::{self, OptionSpec};
+use crate::plugin::{load_plugin_source, plugin_name, split_fragment};
+use crate::runtime::CONFIGURE;
+use crate::transforms::{self, Transform};
+
+#[derive(clap::Args, Debug)]
+pub struct HelpArgs {
+    /// The plugin to describe, resolved like the plugins of a pipeline. Shows the help of WASMlet or one of its commands instead if it names none
+    plugin: Option<String>,
+
+    /// The output format
+    #[arg(long, value_enum, default_value_t)]
+    format: OutputFormat,
+}
+
+#[derive(Serialize)]
+struct PluginHelp {
+    plugin: String,
+    /// The url or path the plugin was loaded from.
+    location: String,
+    metadata: Option<Metadata>,
+    transforms: Vec<Transform>,
+    content_types: Option<ContentTypes>,
+    /// Whether the plugin exports `configure`.
+    configurable: bool,
+    /// The options the plugin declares.
+    options: Option<Vec<OptionSpec>>,
+}
+
+/// Load the plugin and read its description, transforms and options.
+fn describe(specifier: &str) -> Result<PluginHelp, PluginError> {
+    let (source, _) = split_fragment(options::split(specifier).0);
+    let source = load_plugin_source(source)?;
+    let interface = ModuleInterface::load(&source)?;
+    Ok(PluginHelp {
+        plugin: plugin_name(specifier).to_string(),
+        location: source.location,
+        metadata: interface
+            .custom_sections(metadata::SECTION)
+            .first()
+            .map(|section| Metadata::parse(section)),
+        transforms: transforms::from_sections(&interface.custom_sections(transforms::SECTION)),
+        content_types: interface
+            .custom_sections(content_types::SECTION)
+            .first()
+            .map(|section| ContentTypes::parse(section)),
+        configurable: interface.export(CONFIGURE).is_some(),
+        options: options::from_sections(&interface.custom_sections(options::SECTION)),
+    })
+}
+
+/// The placeholder for the value of an option, like `<integer>` or `red|green`.
+fn placeholder(option: &OptionSpec) -> String {
+    match option.values.as_slice() {
+        [] => format!("<{}>", option.option_type),
+        values => values.join("|"),
+    }
+}
+
+fn print_help(help: &PluginHelp) {
+    let metadata = help.metadata.clone().unwrap_or_default();
+    match (&metadata.name, &metadata.version) {
+        (Some(name), Some(version)) => println!("{name} {version} ({})", help.location),
+        _ => println!("{} ({})", help.plugin, help.location),
+    }
+    match &metadata.description {
+        Some(description) => println!("{description}"),
+        None => println!("The plugin has no description"),
+    }
+
+    if let Some(content_types) = &help.content_types {
+        let accepts = match content_types.accepts.as_slice() {
+            [] => content_types::TEXT_ACCEPTS.to_string(),
+            accepts => accepts.join(", "),
+        };
+        println!("\nAccepts: {accepts}");
+        println!("Produces: {}", content_types.produces());
+    }
+
+    if !help.transforms.is_empty() {
+        println!("\nTransforms:");
+        let width = help
+            .transforms
+            .iter()
+            .map(|transform| help.plugin.len() + 1 + transform.name.len())
+            .max()
+            .unwrap_or_default();
+        for transform in &help.transforms {
+            let specifier = format!("{}#{}", help.plugin, transform.name);
+            println!("  {specifier:width$}  {}", transform.description);
+        }
+    }
+
+    match (&help.options, help.configurable) {
+        (Some(declared), _) if !declared.is_empty() => {
+            println!("\nOptions:");
+            let usages: Vec<_> = declared
+                .iter()
+                .map(|option| format!("{}={}", option.name, placeholder(option)))
+                .collect();
+            let width = usages.iter().map(String::len).max().unwrap_or_default();
+            for (option, usage) in declared.iter().zip(&usages) {
+                let text = match (&option.help, &option.default) {
+                    (Some(help), Some(default)) => format!("{help} [default: {default}]"),
+                    (Some(help), None) => help.clone(),
+                    (None, Some(default)) => format!("[default: {default}]"),
+                    (None, None) => String::new(),
+                };
+                println!("  {usage:width$}  {text}");
+            }
+            println!(
+                "\nPass options after the specifier: wasmlet -p {}:{}",
+                help.plugin, usages[0]
+            );
+        }
+        (Some(_), _) | (None, false) => println!("\nThe plugin takes no options"),
+        (None, true) => println!("\nThe plugin takes options, but does not declare them"),
+    }
+}
+
+/// Show the help of a plugin. `command` is the command line interface of WASMlet, which is shown without a plugin or for the name of one of its commands.
+pub fn help(args: HelpArgs, mut command: clap::Command) -> ExitCode {
+    let Some(specifier) = args.plugin else {
+        command
+            .print_help()
+            .expect("The help can be written to stdout");
+        return ExitCode::SUCCESS;
+    };
+    if let Some(subcommand) = command.find_subcommand_mut(&specifier) {
+        subcommand
+            .print_help()
+            .expect("The help can be written to stdout");
+        return ExitCode::SUCCESS;
+    }
+
+    let help = match describe(&specifier) {
+        Ok(help) => help,
+        Err(error) => {
+            let exit_code = error.category().exit_code();
+            eprintln!("{:?}", miette::Report::new(error));
+            return exit_code.into();
+        }
+    };
+    match args.format {
+        OutputFormat::Text => print_help(&help),
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&help).expect("The help can always be serialized")
+        ),
+    }
+    ExitCode::SUCCESS
+}
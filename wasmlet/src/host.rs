@@ -0,0 +1,119 @@
+//! Functions the host provides to WASMlet plugins in the `wasmlet` import module.
+//!
+//! The functions are implemented here once. Every runtime only reads the arguments from the guest memory and forwards them.
+
+use crate::terminal::TerminalInfo;
+
+/// The module the host functions are imported from.
+pub const IMPORT_MODULE: &str = "wasmlet";
+
+/// The names of all host functions.
+pub const HOST_FUNCTIONS: [&str; 6] = [
+    "log",
+    "panic",
+    "terminal_width",
+    "terminal_height",
+    "terminal_color_depth",
+    "terminal_is_tty",
+];
+
+/// State that the host functions need to access.
+pub struct HostEnv {
+    /// The log target for messages from this plugin.
+    pub target: String,
+    /// The message of the last panic reported by the plugin.
+    pub panic_message: Option<String>,
+    pub terminal: TerminalInfo,
+}
+
+impl HostEnv {
+    pub fn new(plugin_name: &str, terminal: TerminalInfo) -> Self {
+        HostEnv {
+            target: log_target(plugin_name),
+            terminal,
+            panic_message: None,
+        }
+    }
+
+    /// `log(level, pointer, length)`: Log a message. The levels are numbered like `log::Level`, starting with 1 for errors.
+    ///
+    /// The message is only read from the guest memory if the level is enabled.
+    pub fn log(
+        &self,
+        level: u32,
+        read_message: impl FnOnce() -> Result<String, String>,
+    ) -> Result<(), String> {
+        let level = match level {
+            1 => log::Level::Error,
+            2 => log::Level::Warn,
+            3 => log::Level::Info,
+            4 => log::Level::Debug,
+            5 => log::Level::Trace,
+            _ => {
+                return Err(format!(
+                    "The plugin tried to log with the invalid level {level}"
+                ));
+            }
+        };
+        let target = self.target.as_str();
+        if !log::log_enabled!(target: target, level) {
+            return Ok(());
+        }
+        let message = read_message()?;
+        log::log!(target: target, level, "{}", message);
+        Ok(())
+    }
+
+    /// `panic(pointer, length)`: Report a panic message. The plugin traps afterwards.
+    pub fn panic(&mut self, message: String) {
+        log::debug!(target: self.target.as_str(), "Plugin panicked: {}", message);
+        self.panic_message = Some(message);
+    }
+
+    /// `terminal_width()`: The width of the terminal in columns or 0 if it is unknown.
+    pub fn terminal_width(&self) -> u32 {
+        self.terminal.width.unwrap_or(0) as u32
+    }
+
+    /// `terminal_height()`: The height of the terminal in rows or 0 if it is unknown.
+    pub fn terminal_height(&self) -> u32 {
+        self.terminal.height.unwrap_or(0) as u32
+    }
+
+    /// `terminal_color_depth()`: 0 for no colors, 1 for 16 colors, 2 for 256 colors and 3 for truecolor.
+    pub fn terminal_color_depth(&self) -> u32 {
+        self.terminal.color_depth as u32
+    }
+
+    /// `terminal_is_tty()`: 1 if the output is written to a terminal, 0 otherwise.
+    pub fn terminal_is_tty(&self) -> u32 {
+        self.terminal.is_tty as u32
+    }
+}
+
+/// The log target for a plugin, so `RUST_LOG=wasmlet::plugin::<name>=debug` shows its messages.
+pub fn log_target(plugin_name: &str) -> String {
+    format!("wasmlet::plugin::{plugin_name}")
+}
+
+/// Turn the bytes of a string the plugin passed into a string. Invalid utf8 is replaced.
+pub fn guest_string(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+/// Format one frame of a trap for a backtrace.
+///
+/// Function names come from the name section of the module and are demangled if they are Rust symbols.
+pub fn format_frame(
+    index: usize,
+    function_name: Option<&str>,
+    function_index: u32,
+    module_name: &str,
+    module_offset: usize,
+) -> String {
+    let function = match function_name {
+        Some(name) => rustc_demangle::demangle(name).to_string(),
+        None => format!("<wasm function {function_index}>"),
+    };
+    format!("{index:>4}: {function}\n          at {module_name}:0x{module_offset:x}")
+}
@@ -0,0 +1,192 @@
+//! The interface of a plugin: what it imports and exports and the custom sections that describe it.
+//!
+//! Commands that only look at a plugin, like `wasmlet help` and the checks of `wasmlet validate`, read the module with wasmparser instead of compiling it. That is faster and also works in builds without a compiler.
+
+use wasmer::{ExternType, FunctionType, Module, Type};
+use wasmparser::{CompositeInnerType, ExternalKind, Parser, Payload, TypeRef, ValType};
+
+use crate::error::PluginError;
+use crate::plugin::{PluginConfig, PluginSource};
+use crate::terminal::TerminalInfo;
+use crate::{content_types, metadata, options, preinit, transforms, wasmer_runtime};
+
+/// The custom sections of WASMlet. Precompiled plugins can only be asked for sections by name.
+const SECTIONS: [&str; 5] = [
+    metadata::SECTION,
+    options::SECTION,
+    content_types::SECTION,
+    transforms::SECTION,
+    preinit::EXPORTED_GLOBALS_SECTION,
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExportKind {
+    Function(FunctionType),
+    Memory,
+    /// A global, table or tag.
+    Other,
+}
+
+#[derive(Clone, Debug)]
+pub struct Export {
+    pub name: String,
+    pub kind: ExportKind,
+}
+
+#[derive(Clone, Debug)]
+pub struct Import {
+    pub module: String,
+    pub name: String,
+}
+
+#[derive(Default)]
+pub struct ModuleInterface {
+    pub imports: Vec<Import>,
+    pub exports: Vec<Export>,
+    custom_sections: Vec<(String, Vec<u8>)>,
+}
+
+impl ModuleInterface {
+    /// Read the interface of a plugin. Wasm and wat modules are parsed, precompiled plugins are deserialized, which needs no compiler either.
+    pub fn load(source: &PluginSource) -> Result<Self, PluginError> {
+        if source.precompiled {
+            let config = PluginConfig::new(TerminalInfo::STANDARD);
+            let module = wasmer_runtime::compile(
+                &wasmer_runtime::create_engine(&config),
+                &source.bytes,
+                true,
+                &config,
+            )?;
+            return Ok(ModuleInterface::from_precompiled(&module));
+        }
+        let wasm = wasmer::wat2wasm(&source.bytes).map_err(|e| PluginError::Compile(e.into()))?;
+        ModuleInterface::parse(&wasm).map_err(|e| PluginError::Compile(e.into()))
+    }
+
+    /// Read the interface of a wasm module.
+    pub fn parse(wasm: &[u8]) -> Result<Self, wasmparser::BinaryReaderError> {
+        let mut interface = ModuleInterface::default();
+        // The signature of each type, if it is a function type
+        let mut types = Vec::new();
+        // The type of every function
+        let mut functions = Vec::new();
+        for payload in Parser::new(0).parse_all(wasm) {
+            match payload? {
+                Payload::TypeSection(section) => {
+                    for group in section {
+                        for ty in group?.into_types() {
+                            types.push(match &ty.composite_type.inner {
+                                CompositeInnerType::Func(function) => Some(FunctionType::new(
+                                    function.params().iter().map(value_type).collect::<Vec<_>>(),
+                                    function
+                                        .results()
+                                        .iter()
+                                        .map(value_type)
+                                        .collect::<Vec<_>>(),
+                                )),
+                                _ => None,
+                            });
+                        }
+                    }
+                }
+                Payload::ImportSection(section) => {
+                    for import in section {
+                        let import = import?;
+                        if let TypeRef::Func(ty) = import.ty {
+                            functions.push(ty);
+                        }
+                        interface.imports.push(Import {
+                            module: import.module.to_string(),
+                            name: import.name.to_string(),
+                        });
+                    }
+                }
+                Payload::FunctionSection(section) => {
+                    for ty in section {
+                        functions.push(ty?);
+                    }
+                }
+                Payload::ExportSection(section) => {
+                    for export in section {
+                        let export = export?;
+                        let kind = match export.kind {
+                            ExternalKind::Func => functions
+                                .get(export.index as usize)
+                                .and_then(|&ty| types.get(ty as usize).cloned().flatten())
+                                .map_or(ExportKind::Other, ExportKind::Function),
+                            ExternalKind::Memory => ExportKind::Memory,
+                            _ => ExportKind::Other,
+                        };
+                        interface.exports.push(Export {
+                            name: export.name.to_string(),
+                            kind,
+                        });
+                    }
+                }
+                Payload::CustomSection(section) if section.name().starts_with("wasmlet-") => {
+                    interface
+                        .custom_sections
+                        .push((section.name().to_string(), section.data().to_vec()));
+                }
+                _ => {}
+            }
+        }
+        Ok(interface)
+    }
+
+    fn from_precompiled(module: &Module) -> Self {
+        ModuleInterface {
+            imports: module
+                .imports()
+                .map(|import| Import {
+                    module: import.module().to_string(),
+                    name: import.name().to_string(),
+                })
+                .collect(),
+            exports: module
+                .exports()
+                .map(|export| Export {
+                    name: export.name().to_string(),
+                    kind: match export.ty() {
+                        ExternType::Function(function) => ExportKind::Function(function.clone()),
+                        ExternType::Memory(_) => ExportKind::Memory,
+                        _ => ExportKind::Other,
+                    },
+                })
+                .collect(),
+            custom_sections: SECTIONS
+                .iter()
+                .flat_map(|&name| {
+                    module
+                        .custom_sections(name)
+                        .map(move |section| (name.to_string(), section.to_vec()))
+                })
+                .collect(),
+        }
+    }
+
+    pub fn export(&self, name: &str) -> Option<&Export> {
+        self.exports.iter().find(|export| export.name == name)
+    }
+
+    /// The contents of the custom sections with the given name.
+    pub fn custom_sections(&self, name: &str) -> Vec<&[u8]> {
+        self.custom_sections
+            .iter()
+            .filter(|(section, _)| section == name)
+            .map(|(_, data)| data.as_slice())
+            .collect()
+    }
+}
+
+fn value_type(ty: &ValType) -> Type {
+    match ty {
+        ValType::I32 => Type::I32,
+        ValType::I64 => Type::I64,
+        ValType::F32 => Type::F32,
+        ValType::F64 => Type::F64,
+        ValType::V128 => Type::V128,
+        ValType::Ref(ty) if ty.is_func_ref() => Type::FuncRef,
+        ValType::Ref(_) => Type::ExternRef,
+    }
+}
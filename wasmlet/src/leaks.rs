@@ -0,0 +1,108 @@
+//! Leak detection for `--check-leaks`.
+//!
+//! A plugin that leaks memory on every call grows its memory a little with every call. Running a stage repeatedly with the same input makes that visible.
+
+use serde::Serialize;
+
+use crate::error::PluginError;
+use crate::plugin::Plugin;
+use crate::report::StageReport;
+
+/// How the memory of a stage developed over repeated calls.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LeakReport {
+    /// Number of repeated calls.
+    pub calls: usize,
+    /// Memory size in 64KiB pages after each call.
+    pub memory_pages: Vec<u32>,
+    /// Average growth in pages per call. The first call is ignored, because allocators often grow once to reach a steady state.
+    pub growth_per_call: f64,
+    /// Buffers that the host allocated or received and did not free. Missing for Extism plugins.
+    pub outstanding_buffers: Option<usize>,
+}
+
+impl LeakReport {
+    pub fn new(memory_pages: Vec<u32>, outstanding_buffers: Option<usize>) -> Self {
+        let growth_per_call = match (memory_pages.get(1), memory_pages.last()) {
+            (Some(first), Some(last)) => {
+                (*last as f64 - *first as f64) / (memory_pages.len() - 2).max(1) as f64
+            }
+            _ => 0.0,
+        };
+        LeakReport {
+            calls: memory_pages.len(),
+            memory_pages,
+            growth_per_call,
+            outstanding_buffers,
+        }
+    }
+
+    /// Whether the stage looks like it is leaking.
+    pub fn is_leaking(&self) -> bool {
+        self.growth_per_call > 0.0 || self.outstanding_buffers.is_some_and(|buffers| buffers > 0)
+    }
+}
+
+/// Call the plugin `calls` times with the same input and record its memory size after every call.
+pub fn check_leaks(
+    plugin: &mut Plugin,
+    input: &[u8],
+    calls: usize,
+) -> Result<LeakReport, PluginError> {
+    let mut memory_pages = Vec::with_capacity(calls);
+    for _ in 0..calls {
+        plugin.apply_binary(input)?;
+        memory_pages.push(plugin.memory_pages().unwrap_or(0));
+    }
+    Ok(LeakReport::new(memory_pages, plugin.outstanding_buffers()))
+}
+
+/// Print the leak reports of all stages to stderr.
+pub fn print_leaks(stages: &[StageReport]) {
+    for stage in stages {
+        let Some(leaks) = &stage.leaks else {
+            continue;
+        };
+        let verdict = if leaks.is_leaking() {
+            "possible leak"
+        } else {
+            "ok"
+        };
+        let buffers = leaks
+            .outstanding_buffers
+            .map_or("-".to_string(), |buffers| buffers.to_string());
+        eprintln!(
+            "Stage {} ({}): {verdict}. {} calls, memory {} -> {} pages ({:+.2} per call), {buffers} outstanding buffers",
+            stage.index,
+            stage.plugin,
+            leaks.calls,
+            leaks.memory_pages.first().unwrap_or(&0),
+            leaks.memory_pages.last().unwrap_or(&0),
+            leaks.growth_per_call,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignores_growth_in_the_first_call() {
+        let report = LeakReport::new(vec![17, 18, 18, 18], Some(0));
+        assert_eq!(report.growth_per_call, 0.0);
+        assert!(!report.is_leaking());
+    }
+
+    #[test]
+    fn detects_steady_growth() {
+        let report = LeakReport::new(vec![17, 18, 19, 20, 21], None);
+        assert_eq!(report.growth_per_call, 1.0);
+        assert!(report.is_leaking());
+    }
+
+    #[test]
+    fn detects_outstanding_buffers() {
+        assert!(LeakReport::new(vec![17, 17], Some(1)).is_leaking());
+    }
+}
@@ -40,6 +40,222 @@
 //! wasmlet -p https://0x0.st/8XIj.wasm Hello World!
 //! ```
 //!
+//! ## Extism plugins
+//!
+//! WASMlet can also run plugins written with the [Extism PDK](https://extism.org). Prefix the specifier with `extism:` and name the function that should be called after a `#`:
+//!
+//! ```sh
+//! wasmlet -p extism:count_vowels.wasm#count_vowels Hello World!
+//! ```
+//!
+//! The Extism host functions are provided by WASMlet. Plugins can not use configuration, variables or HTTP requests and must not depend on WASI.
+//!
+//! ## Logging
+//!
+//! Plugins can log messages through the host. Plugins written in Rust can use the `wasmlet-sdk` crate, which forwards the `log` macros. The messages are logged under the target `wasmlet::plugin::<plugin name>`, so you can enable them per plugin:
+//!
+//! ```sh
+//! RUST_LOG=wasmlet::plugin::rainbow=debug wasmlet -p bigfont -p rainbow WASMlet
+//! ```
+//!
+//! ## Colors
+//!
+//! WASMlet reduces the color escape codes in the output to what your terminal supports. Colors are removed completely if the output is not a terminal or `NO_COLOR` is set. Use `--color=always` or `--color=never` to override the detection.
+//!
+//! ## Machine readable output
+//!
+//! With `--format json` WASMlet prints a JSON report instead of the text. It contains the result, the resolved location, sha256 hash and timings of every stage and a structured error if a stage failed.
+//!
+//! The exit code tells you what went wrong: `2` if the arguments are invalid, `3` if a plugin could not be found, `4` if it could not be compiled, `5` if it could not be instantiated, `6` if it crashed, `7` if it reported an error and `8` if it exceeded a resource limit.
+//!
+//! ## Untrusted plugins
+//!
+//! WASMlet does not trust the buffers plugins return. Results that point outside of the guest memory, overlap the input or claim more bytes than the memory has are rejected with a specific error. Plugins can return at most 16MiB per call, use `--max-output` to change the limit. Text output that is not valid utf8 is an error unless you pass `--lossy-utf8`.
+//!
+//! ## Profiling
+//!
+//! `--stats` prints a table to stderr that shows where the time of every stage went: resolving, compiling, instantiating and processing. It also shows the size of the plugin, the number of wasm operators it executed (fuel), its peak memory in 64KiB pages and the size of its input and output. With `--format json` the same data is added to each stage in the report.
+//!
+//! Counting fuel requires instrumenting the plugin, so `--stats` makes plugins a bit slower.
+//!
+//! All plugins of a pipeline are resolved and compiled in parallel, so the pipeline starts in about the time of its slowest plugin. A plugin is only instantiated right before its first call, which is included in its load time.
+//!
+//! ## Leak detection
+//!
+//! `--check-leaks` calls every stage 20 more times with the same input (use `--check-leaks=100` for more calls) and reports how the memory of the plugin grows per call. It also reports shared buffers that were not freed. The first extra call is ignored, because allocators often grow once before they reach a steady state.
+//!
+//! ```sh
+//! wasmlet --check-leaks -p bigfont -p rainbow WASMlet
+//! ```
+//!
+//! ## Benchmarks
+//!
+//! `wasmlet bench` runs a pipeline many times and reports the mean, median and 99th percentile time of every stage and of the whole pipeline. Every `--input` file is used as one text.
+//!
+//! ```sh
+//! wasmlet bench -p bigfont -p rainbow --input corpus.txt
+//! ```
+//!
+//! Use `--baseline` to measure a second build of the pipeline on the same inputs and see the change side by side. `--warmup` and `--iterations` control how often the inputs are processed and `--format json` prints the results as JSON.
+//!
+//! ```sh
+//! wasmlet bench -p bigfont -p rainbow -b old/bigfont.wasm -b rainbow "Hello World"
+//! ```
+//!
+//! `--threads 4` runs the benchmark on four threads at once to see how the plugins behave with concurrent callers. Every stage hands out its instances from a pool, so each thread gets an instance of its own.
+//!
+//! ## Validating plugins
+//!
+//! Run `wasmlet validate` before you distribute a plugin:
+//!
+//! ```sh
+//! wasmlet validate rainbow
+//! ```
+//!
+//! It checks that the module exports `allocate_shared_buffer`, `free_shared_buffer`, `process` and `memory` with the right types, only imports functions the host provides and declares its ABI version and metadata with `wasmlet_sdk::metadata!()`. Then it runs the plugin with empty, non-ASCII, escape code and huge inputs and calls it repeatedly to look for crashes, malformed results and leaks. Use `--format json` for a machine readable report. The exit code is `1` if any check failed.
+//!
+//! ## Golden file tests
+//!
+//! `wasmlet test` runs test cases through the real host, so the wasm build of a plugin is tested and not only its native code. A test directory contains cases named `<name>.in`. The expected output is in `<name>.out`, or in `<name>.err` if the pipeline should fail. A trailing newline in these files is ignored.
+//!
+//! ```sh
+//! wasmlet test -p rainbow
+//! ```
+//!
+//! Without a directory, the cases are read from the `tests` directory next to the plugin, like `../rainbow/tests` for the crate of `rainbow`. Without `-p`, the plugins are read from the `pipeline` file in the test directory, one specifier per line, and `wasmlet test` on its own runs every `tests` directory with a `pipeline` file in the current directory or a crate next to this project. A directory without cases counts as a failure. The plugins see an 80x24 truecolor terminal. A `<name>.options` file can change that for a case with `key=value` lines for `width`, `height`, `color` (`none`, `16`, `256` or `truecolor`), `tty`, `max-output` and `lossy-utf8`.
+//!
+//! When a case fails, WASMlet prints a diff. Run with `--bless` to write the actual results to the expectation files.
+//!
+//! `--differential` also runs every case through the native builds of the plugins and fails if they produce a different result than the wasm builds. This catches miscompilations and code that depends on the target, like the width of `usize`. Plugins need to use `wasmlet_sdk::export_transformer!` and be built for the host, the native libraries are found in the `target` directory of each plugin crate or given in pipeline order with `--native`:
+//!
+//! ```sh
+//! (cd ../rainbow && cargo build --release --target x86_64-unknown-linux-gnu)
+//! wasmlet test --differential -p rainbow
+//! ```
+//!
+//! Native builds run inside WASMlet without any isolation, so only use this with plugins you built yourself.
+//!
+//! ## Fuzzing
+//!
+//! `wasmlet fuzz` runs a plugin with random inputs: ASCII, tricky unicode, escape sequences, invalid utf8, inputs around the page size and mutations of the files in `--corpus`. It reports traps, calls that exceed `--max-fuel`, malformed results, shared buffers that are not freed, memory that grows with every call and results that differ between two instances of the plugin.
+//!
+//! ```sh
+//! wasmlet fuzz -p rainbow --iterations 10000 --corpus rainbow/tests
+//! ```
+//!
+//! Failing inputs are minimized and saved to `fuzz-findings` (change it with `--output`). Use `--seed` to repeat a run and `--replay` to run a saved input again:
+//!
+//! ```sh
+//! wasmlet fuzz -p rainbow --replay fuzz-findings/rainbow-trap-0123456789ab.bin
+//! ```
+//!
+//! `wasmlet fuzz --host` fuzzes WASMlet itself. It runs hand-crafted modules that break the ABI on purpose, checks that each one is rejected with the right error and then mutates them to look for modules that make the host panic.
+//!
+//! ## Compilers
+//!
+//! WASMlet compiles plugins with Cranelift. Select another compiler with `--compiler`, after enabling its cargo feature:
+//!
+//! ```sh
+//! cargo build --release --features singlepass,llvm
+//! wasmlet --compiler singlepass -p bigfont -p rainbow WASMlet
+//! ```
+//!
+//! Singlepass compiles in linear time, LLVM generates the fastest code. `wasmlet bench --compiler` shows which one suits your plugins.
+//!
+//! A headless build contains no compiler and only loads precompiled plugins, which makes the binary much smaller. Precompile the plugins with a regular build for the same target and version of WASMlet:
+//!
+//! ```sh
+//! wasmlet compile rainbow -o rainbow.wasmu
+//! cargo build --release --no-default-features
+//! ./target/release/wasmlet -p rainbow.wasmu WASMlet
+//! ```
+//!
+//! Precompiled plugins only support `--stats` and fuel limits if they were compiled with `wasmlet compile --metering`. Precompiled plugins contain machine code that runs outside of the sandbox, so they are only loaded from local `.wasmu` files and never from the internet.
+//!
+//! ## Runtimes
+//!
+//! Plugins run in [wasmer](https://wasmer.io) by default. Builds with the `wasmtime` feature can run them in [wasmtime](https://wasmtime.dev) instead:
+//!
+//! ```sh
+//! cargo build --release --features wasmtime
+//! wasmlet --runtime wasmtime -p bigfont -p rainbow WASMlet
+//! ```
+//!
+//! Both runtimes share the implementation of the ABI and the host functions, so plugins behave the same in each of them. Metering uses the fuel of wasmtime, which counts about the same as wasmer. Extism plugins, `--compiler` and precompiled plugins are only supported by wasmer.
+//!
+//! ## Reusing instances
+//!
+//! A plugin keeps its memory and globals from one call to the next. A long-running program that reuses a `Plugin` for many texts can set `PluginConfig::reset`: WASMlet then takes a snapshot of the memory and the mutable globals right after instantiation and restores it before every call, so no state and no leaked allocation carries over. This is much cheaper than a new instance per call. Globals the plugin does not export, like the position of its allocator, are exported when the plugin is compiled, so precompiled plugins need to come from `wasmlet compile`. Instances that trapped or grew their memory can not be restored and are replaced by a new instance instead.
+//!
+//! `PluginPool` keeps several instances of a compiled plugin for concurrent callers and creates more when all of them are busy. See what both cost with:
+//!
+//! ```sh
+//! wasmlet bench -p bigfont -p rainbow --reset --threads 4 "Hello World"
+//! ```
+//!
+//! ## Lifecycle
+//!
+//! Besides `process`, plugins can export three optional functions. The host calls `init` once after instantiating the plugin, then `configure` with the options of the plugin and `shutdown` before it drops the instance. `init` and `shutdown` take no parameters and return nothing. `configure` receives a shared buffer with one `key=value` line per option and returns a result buffer like `process`, so it can reject invalid options before any text is processed. The host calls it even if there are no options. Programs that embed WASMlet pass options with `Plugin::configure`.
+//!
+//! Plugins written in Rust export them with `wasmlet_sdk::export_init!`, `export_configure!` and `export_shutdown!`. A trap in any of them is reported like a trap in `process`.
+//!
+//! ## Pre-initialization
+//!
+//! `init` is the place for setup that every call would otherwise repeat, like bigfont parsing its font.
+//!
+//! `wasmlet preinit` runs `init` ahead of time, like [wizer](https://github.com/bytecodealliance/wizer). It writes a new module that starts with the memory and globals `init` left behind, so loading it skips the setup entirely:
+//!
+//! ```sh
+//! wasmlet preinit bigfont -o bigfont.preinit.wasm
+//! wasmlet -p bigfont.preinit.wasm -p rainbow WASMlet
+//! ```
+//!
+//! `init` runs without a terminal during pre-initialization, so it must not depend on the terminal size or color depth. Plugins need exactly one memory to be pre-initialized.
+//!
+//! ## Named transforms
+//!
+//! A plugin can offer several transforms. Besides `process`, it exports them as `process_<name>` with the same signature and lists them with a description in the `wasmlet-transforms` custom section. Select one with `#name` after the specifier:
+//!
+//! ```sh
+//! wasmlet -p effects#gradient -p effects#blink WASMlet
+//! ```
+//!
+//! Without a name, the host calls `process`, or the only named transform if the plugin has no `process`. Plugins written in Rust add transforms with `wasmlet_sdk::export_transform!`, and `wasmlet validate` checks that the exports and the listed transforms match.
+//!
+//! ## Options
+//!
+//! Options follow the specifier after a `:`, one `key=value` pair each, and are passed to the `configure` export of the plugin. Values can not contain a `:`:
+//!
+//! ```sh
+//! wasmlet -p bigfont:width=40:shadow=true WASMlet
+//! ```
+//!
+//! Plugins declare their options in the `wasmlet-options` custom section with a type, a default, the allowed values and a help text. The host checks the options against it before the plugin sees them, so a typo fails with exit code `5` and a list of the valid options. Plugins written in Rust declare them with `wasmlet_sdk::option!`.
+//!
+//! `wasmlet help <plugin>` shows the description from the metadata of a plugin, its transforms and its options, without running it. `wasmlet validate` checks that the defaults are valid.
+//!
+//! ## Content types
+//!
+//! Plugins can declare which content types they accept and produce, like `text/plain`, `text/x-ansi` for text with escape codes or `application/json`. WASMlet checks the whole pipeline before it runs any plugin and names the stages that do not fit together:
+//!
+//! ```sh
+//! # Fails with exit code 5: bigfont does not accept `text/x-ansi`, which rainbow produces
+//! wasmlet -p rainbow -p bigfont WASMlet
+//! ```
+//!
+//! The input of the pipeline is `text/plain`. Accepted types can be patterns like `text/*` or `*/*`, and plugins that accept `text/x-ansi` also accept `text/plain`. Plugins that declare nothing are text plugins, which accept `text/*` and produce `text/plain`. The types are stored in the `wasmlet-content-types` custom section, plugins written in Rust declare them with `wasmlet_sdk::content_types!` and `wasmlet help <plugin>` shows them.
+//!
+//! ## Binary payloads
+//!
+//! Stages can exchange bytes instead of text, like images or compressed data. A plugin whose declared output type is not `text/*`, like `image/png`, can return any bytes, and WASMlet passes them on without checking for utf8. Output of other plugins still needs to be utf8, so text plugins keep working unchanged, and a text plugin after a binary stage fails before the pipeline runs. If the last stage produces binary content, it is written to stdout as it is:
+//!
+//! ```sh
+//! # A hypothetical plugin that renders the banner to a PNG
+//! wasmlet -p bigfont -p render WASMlet > banner.png
+//! ```
+//!
+//! The JSON report names the content type of the output and leaves `output` empty for binary content. Plugins written in Rust implement `wasmlet_sdk::BinaryTransformer` and export it with `wasmlet_sdk::export_binary_transformer!`.
+//!
 //! ## Plugin Resolution
 //!
 //! When you specify plugins with the `-p` flag, WASMlet uses the following strategy to find plugins:
@@ -51,15 +267,66 @@
 //! 5. Try to load the specifier from a rust crate next to this project.
 #![feature(error_generic_member_access)]
 
-use clap::Parser;
+use bench::BenchArgs;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use compile::{CompileArgs, Compiler};
+use content_types::Payload;
 use env_logger::Builder;
-use plugin::Plugin;
+use error::StageError;
+use fuzz::FuzzArgs;
+use golden::TestArgs;
+use help::HelpArgs;
+use plugin::{DEFAULT_MAX_OUTPUT, PluginConfig, load_pipeline};
+use preinit::PreinitArgs;
+use report::{ErrorReport, Report, StageReport, milliseconds};
+use runtime::Runtime;
+use std::io::Write;
 use std::process::ExitCode;
+use terminal::{ColorChoice, TerminalInfo};
+use validate::ValidateArgs;
+mod bench;
+mod color;
+mod compile;
+mod content_types;
+mod diff;
+mod error;
+mod extism;
+mod fuzz;
+mod golden;
+mod help;
+mod host;
+mod interface;
+mod leaks;
+mod malicious;
+mod metadata;
+mod native;
+mod options;
 mod plugin;
+mod pool;
+mod preinit;
+mod report;
+mod result_buffer;
+mod runtime;
+mod stats;
+mod terminal;
+mod transforms;
+mod validate;
+mod wasmer_runtime;
+#[cfg(feature = "wasmtime")]
+mod wasmtime_runtime;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Print the resulting text and log errors to stderr
+    #[default]
+    Text,
+    /// Print a JSON report with the result, timings, plugin information and errors
+    Json,
+}
 
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, disable_help_subcommand = true)]
 #[clap(after_help = "
 \x1b[1;4mPLUGIN RESOLUTION:\x1b[0m
   WASMlet uses the following strategy to load plugins:
@@ -68,8 +335,47 @@ mod plugin;
   3. Try the specifier with an appended `.wasm` extension.
   4. Try to load the specifier relative to the directory specified in `WASMLET_PLUGIN_DIR` (defaults to `/etc/wasmlet/plugins`).
   5. Try to load the specifier from a rust crate next to this project.
+
+\x1b[1;4mEXIT CODES:\x1b[0m
+  0 Success
+  2 The arguments are invalid
+  3 A plugin could not be found or downloaded
+  4 A plugin could not be compiled
+  5 A plugin could not be instantiated
+  6 A plugin crashed or violated the ABI
+  7 A plugin reported an error
+  8 A plugin exceeded a resource limit
 ")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Measure how long every stage of a pipeline takes
+    Bench(BenchArgs),
+    /// Check that a plugin implements the ABI and handles unusual inputs
+    Validate(ValidateArgs),
+    /// Run the golden file tests in the given directories
+    Test(TestArgs),
+    /// Run a plugin with random inputs and save the ones that break it
+    Fuzz(FuzzArgs),
+    /// Precompile a plugin for builds of WASMlet without a compiler
+    Compile(CompileArgs),
+    /// Run the `init` export of a plugin and save the initialized state as a new module
+    Preinit(PreinitArgs),
+    /// Show the description, transforms and options of a plugin
+    Help(HelpArgs),
+}
+
+/// Run the text through the plugins. This is what happens without a subcommand.
+#[derive(clap::Args, Debug)]
+struct RunArgs {
     /// The text that should get printed
     #[arg(required = true)]
     text: Vec<String>,
@@ -77,6 +383,100 @@ struct Args {
     /// WASM plugins that should process the text
     #[arg(short, long)]
     plugins: Vec<String>,
+
+    /// When to use colors. Colors in the output are reduced to what the terminal supports
+    #[arg(long, value_enum, default_value_t)]
+    color: ColorChoice,
+
+    /// The output format
+    #[arg(long, value_enum, default_value_t)]
+    format: OutputFormat,
+
+    /// Print timings, fuel and memory usage of every stage to stderr. Included in the JSON report with `--format json`
+    #[arg(long)]
+    stats: bool,
+
+    /// Call every stage this many more times with the same input and report whether its memory keeps growing
+    #[arg(long, value_name = "CALLS", num_args = 0..=1, require_equals = true, default_missing_value = "20")]
+    check_leaks: Option<usize>,
+
+    /// The maximum number of bytes a single plugin may return
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_OUTPUT)]
+    max_output: u64,
+
+    /// Replace invalid utf8 in the output of plugins instead of failing
+    #[arg(long)]
+    lossy_utf8: bool,
+
+    /// The compiler for the plugins. Defaults to the first one this build includes
+    #[arg(long, value_enum)]
+    compiler: Option<Compiler>,
+
+    /// The wasm runtime that runs the plugins
+    #[arg(long, value_enum, default_value_t)]
+    runtime: Runtime,
+}
+
+/// Load all plugins and run the text through them. The result has the type the last stage produces.
+///
+/// Plugin information and timings are recorded in the report.
+fn run(args: &RunArgs, terminal: TerminalInfo, report: &mut Report) -> Result<Payload, StageError> {
+    let config = PluginConfig {
+        metering: args.stats,
+        max_output: args.max_output,
+        lossy_utf8: args.lossy_utf8,
+        compiler: args.compiler.or(Compiler::default_compiler()),
+        runtime: args.runtime,
+        ..PluginConfig::new(terminal)
+    };
+    let mut plugins = Vec::new();
+    let stages = load_pipeline(&args.plugins, &config);
+    for (index, (specifier, plugin)) in args.plugins.iter().zip(stages).enumerate() {
+        let plugin = plugin.map_err(|error| StageError {
+            stage: index,
+            specifier: specifier.clone(),
+            error,
+        })?;
+        let mut stage = StageReport::new(index, specifier, &plugin.info, plugin.stats.load_time());
+        if args.stats {
+            stage.stats = Some(plugin.stats.clone());
+        }
+        report.stages.push(stage);
+        plugins.push(plugin);
+    }
+    content_types::check_pipeline(&args.plugins, &plugins)?;
+
+    let mut payload = Payload::text(args.text.join(" "));
+    for (index, plugin) in plugins.iter_mut().enumerate() {
+        let result = plugin.apply_payload(&payload);
+        let stage = &mut report.stages[index];
+        // The plugin is instantiated on its first call, which belongs to the load time
+        stage.load_time_ms = milliseconds(plugin.stats.load_time());
+        stage.apply_time_ms = Some(milliseconds(plugin.stats.process_time));
+        if args.stats {
+            stage.stats = Some(plugin.stats.clone());
+        }
+        let stage_error = |error| StageError {
+            stage: index,
+            specifier: args.plugins[index].clone(),
+            error,
+        };
+        let output = result.map_err(stage_error)?;
+        if let Some(calls) = args.check_leaks {
+            stage.leaks =
+                Some(leaks::check_leaks(plugin, &payload.bytes, calls).map_err(stage_error)?);
+        }
+        payload = output;
+    }
+    // Dropping the plugins would only log errors of `shutdown`
+    for (index, plugin) in plugins.iter_mut().enumerate() {
+        plugin.shutdown().map_err(|error| StageError {
+            stage: index,
+            specifier: args.plugins[index].clone(),
+            error,
+        })?;
+    }
+    Ok(payload)
 }
 
 fn main() -> ExitCode {
@@ -87,32 +487,63 @@ fn main() -> ExitCode {
         .init();
 
     let args = Args::parse();
+    match args.command {
+        Some(Command::Bench(bench_args)) => bench::bench(bench_args),
+        Some(Command::Validate(validate_args)) => validate::validate(validate_args),
+        Some(Command::Test(test_args)) => golden::test(test_args),
+        Some(Command::Fuzz(fuzz_args)) => fuzz::fuzz(fuzz_args),
+        Some(Command::Compile(compile_args)) => compile::compile(compile_args),
+        Some(Command::Preinit(preinit_args)) => preinit::preinit(preinit_args),
+        Some(Command::Help(help_args)) => help::help(help_args, Args::command()),
+        None => transform(args.run),
+    }
+}
 
-    let mut plugins = match args
-        .plugins
-        .iter()
-        .map(Plugin::new)
-        .collect::<Result<Vec<_>, _>>()
-    {
-        Ok(plugins) => plugins,
-        Err(err) => {
-            log::error!("{}", err);
-            return 1.into();
-        }
-    };
+/// Run the text through the plugins and print the result or a report.
+fn transform(args: RunArgs) -> ExitCode {
+    let terminal = TerminalInfo::detect(args.color);
 
-    let input_text: String = args.text.join(" ");
-    let result = match plugins
-        .iter_mut()
-        .try_fold(input_text, |text, plugin| plugin.apply(&text))
-    {
-        Ok(result) => result,
+    let mut report = Report::default();
+    let exit_code = match run(&args, terminal, &mut report) {
+        Ok(payload) => {
+            let output = payload
+                .as_text()
+                .map(|text| color::downgrade(text, terminal.color_depth));
+            if args.format == OutputFormat::Text {
+                match &output {
+                    Some(text) => println!("{}", text),
+                    // Binary output is written as it is, like an image to redirect to a file
+                    None => std::io::stdout()
+                        .write_all(&payload.bytes)
+                        .expect("The output can be written to stdout"),
+                }
+            }
+            report.success = true;
+            report.output = output;
+            report.content_type = Some(payload.content_type);
+            ExitCode::SUCCESS
+        }
         Err(err) => {
-            log::error!("{}", err);
-            return 1.into();
+            report.error = Some(ErrorReport::new(&err));
+            let exit_code = err.error.category().exit_code();
+            if args.format == OutputFormat::Text {
+                eprintln!("{:?}", miette::Report::new(err));
+            }
+            exit_code.into()
         }
     };
 
-    println!("{}", result);
-    0.into()
+    if args.stats && args.format == OutputFormat::Text {
+        stats::print_stats(&report.stages);
+    }
+    if args.check_leaks.is_some() && args.format == OutputFormat::Text {
+        leaks::print_leaks(&report.stages);
+    }
+    if args.format == OutputFormat::Json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).expect("The report can always be serialized")
+        );
+    }
+    exit_code
 }
@@ -0,0 +1,347 @@
+//! Hand-crafted modules that lie to the host.
+//!
+//! Each module breaks the ABI in one specific way. The host has to turn every one of them into the expected error instead of crashing or reading outside of the guest memory. `wasmlet fuzz --host` mutates these modules to look for more.
+
+/// The parts of a module that implements the ABI. Every malicious module replaces one of them. Tests of the host build their modules from it as well.
+#[derive(Clone, Copy)]
+pub struct Template {
+    pub imports: &'static str,
+    pub memory: &'static str,
+    pub allocate: &'static str,
+    pub free: &'static str,
+    pub process_name: &'static str,
+    pub process: &'static str,
+    pub extra: &'static str,
+}
+
+/// A well-behaved module. `allocate_shared_buffer` is a bump allocator that keeps its position in the global `$next`, starting at 4096, and `process` returns an empty result at 2048.
+pub const VALID: Template = Template {
+    imports: "",
+    memory: r#"(memory (export "memory") 1)"#,
+    allocate: r#"
+        (local $pointer i32)
+        (local.set $pointer (global.get $next))
+        (global.set $next (i32.add (global.get $next) (i32.add (local.get $size) (i32.const 8))))
+        (local.get $pointer)"#,
+    free: "(i32.const 1)",
+    process_name: "process",
+    process: r#"
+        (i32.store8 (i32.const 2048) (i32.const 1))
+        (i32.store (i32.const 2049) (i32.const 0))
+        (i32.const 2048)"#,
+    extra: "",
+};
+
+impl Template {
+    pub fn wat(&self) -> String {
+        format!(
+            r#"(module
+                {imports}
+                {memory}
+                (global $next (mut i32) (i32.const 4096))
+                (func (export "allocate_shared_buffer") (param $size i32) (result i32) {allocate})
+                (func (export "free_shared_buffer") (param $pointer i32) (result i32) {free})
+                (func (export "{process_name}") (param $input i32) (result i32) {process})
+                {extra}
+            )"#,
+            imports = self.imports,
+            memory = self.memory,
+            allocate = self.allocate,
+            free = self.free,
+            process_name = self.process_name,
+            process = self.process,
+            extra = self.extra,
+        )
+    }
+}
+
+pub struct MaliciousModule {
+    pub name: &'static str,
+    /// The module in the wasm text format.
+    pub wat: String,
+    /// The kind of error the host should report, see `PluginError::kind`. Nothing if the module works.
+    pub expected: Option<&'static str>,
+}
+
+/// A result header with the given success flag and length at 2048.
+macro_rules! result_at_2048 {
+    ($flag:literal, $length:literal) => {
+        concat!(
+            "(i32.store8 (i32.const 2048) (i32.const ",
+            $flag,
+            ")) (i32.store (i32.const 2049) (i32.const ",
+            $length,
+            ")) (i32.const 2048)"
+        )
+    };
+}
+pub(crate) use result_at_2048;
+
+pub fn malicious_modules() -> Vec<MaliciousModule> {
+    let cases: [(&str, Template, Option<&str>); 20] = [
+        ("valid", VALID, None),
+        (
+            "null result",
+            Template {
+                process: "(i32.const 0)",
+                ..VALID
+            },
+            Some("NullResult"),
+        ),
+        (
+            "result at the end of memory",
+            Template {
+                process: "(i32.const 65534)",
+                ..VALID
+            },
+            Some("ResultOutOfBounds"),
+        ),
+        (
+            "length beyond memory",
+            Template {
+                process: result_at_2048!(1, 100000),
+                ..VALID
+            },
+            Some("ResultLengthOutOfBounds"),
+        ),
+        (
+            "4GiB length",
+            Template {
+                process: result_at_2048!(1, -1),
+                ..VALID
+            },
+            Some("OutputTooLarge"),
+        ),
+        (
+            "returns the input",
+            Template {
+                process: "(local.get $input)",
+                ..VALID
+            },
+            Some("ResultOverlapsInput"),
+        ),
+        (
+            "invalid success flag",
+            Template {
+                process: result_at_2048!(7, 0),
+                ..VALID
+            },
+            Some("InvalidSuccessFlag"),
+        ),
+        (
+            "invalid utf8",
+            Template {
+                process: concat!(
+                    "(i32.store8 (i32.const 2053) (i32.const 255)) ",
+                    result_at_2048!(1, 1)
+                ),
+                ..VALID
+            },
+            Some("OutputIsNotUtf8"),
+        ),
+        (
+            "endless loop",
+            Template {
+                process: "(loop $forever (br $forever)) (i32.const 0)",
+                ..VALID
+            },
+            Some("FuelExhausted"),
+        ),
+        (
+            "stack overflow",
+            Template {
+                process: "(call $recurse)",
+                extra: "(func $recurse (result i32) (call $recurse))",
+                ..VALID
+            },
+            Some("Trap"),
+        ),
+        (
+            "unreachable",
+            Template {
+                process: "(unreachable)",
+                ..VALID
+            },
+            Some("Trap"),
+        ),
+        (
+            "refuses to free",
+            Template {
+                free: "(i32.const 0)",
+                ..VALID
+            },
+            Some("FailedToFreeSharedBuffer"),
+        ),
+        (
+            "input buffer outside of memory",
+            Template {
+                allocate: "(i32.const 65534)",
+                ..VALID
+            },
+            Some("InvalidInputBuffer"),
+        ),
+        (
+            "panics with a message outside of memory",
+            Template {
+                imports: r#"(import "wasmlet" "panic" (func $panic (param i32 i32)))"#,
+                process: concat!(
+                    "(call $panic (i32.const 65530) (i32.const 1000)) ",
+                    result_at_2048!(1, 0)
+                ),
+                ..VALID
+            },
+            Some("Trap"),
+        ),
+        (
+            "imports an unknown function",
+            Template {
+                imports: r#"(import "env" "system" (func $system))"#,
+                ..VALID
+            },
+            Some("Instantiation"),
+        ),
+        (
+            "does not export memory",
+            Template {
+                memory: "(memory 1)",
+                ..VALID
+            },
+            Some("MissingMemory"),
+        ),
+        (
+            "does not export process",
+            Template {
+                process_name: "transform",
+                ..VALID
+            },
+            Some("MissingFunction"),
+        ),
+        (
+            "traps in init",
+            Template {
+                extra: r#"(func (export "init") (unreachable))"#,
+                ..VALID
+            },
+            Some("Trap"),
+        ),
+        (
+            "rejects the default options",
+            Template {
+                extra: concat!(
+                    r#"(func (export "configure") (param $options i32) (result i32) "#,
+                    result_at_2048!(0, 0),
+                    ")"
+                ),
+                ..VALID
+            },
+            Some("OptionsRejected"),
+        ),
+        (
+            "null result from configure",
+            Template {
+                extra: r#"(func (export "configure") (param $options i32) (result i32) (i32.const 0))"#,
+                ..VALID
+            },
+            Some("NullResult"),
+        ),
+    ];
+    cases
+        .into_iter()
+        .map(|(name, template, expected)| MaliciousModule {
+            name,
+            wat: template.wat(),
+            expected,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::{Plugin, PluginConfig};
+    use crate::runtime::Runtime;
+    use crate::terminal::TerminalInfo;
+    #[cfg(feature = "compiler")]
+    use crate::wasmer_runtime;
+
+    #[test]
+    fn every_runtime_rejects_malicious_modules() {
+        for &runtime in Runtime::available() {
+            let config = PluginConfig {
+                fuel_limit: Some(1_000_000),
+                runtime,
+                ..PluginConfig::new(TerminalInfo::STANDARD)
+            };
+            for module in malicious_modules() {
+                let result = Plugin::from_wasm(module.name, module.wat.as_bytes(), &config)
+                    .and_then(|mut plugin| plugin.apply("Hello"));
+                let kind = result.as_ref().err().map(|error| error.kind());
+                assert_eq!(
+                    kind, module.expected,
+                    "{runtime}, {}: {:?}",
+                    module.name, result
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn lifecycle_exports_see_options_and_report_errors() {
+        // Only accepts options that start with `a`, so it rejects the empty default options
+        let configurable = Template {
+            extra: concat!(
+                r#"(func (export "configure") (param $options i32) (result i32) "#,
+                "(if (i32.eq (i32.load8_u (local.get $options)) (i32.const 97)) (then (return ",
+                result_at_2048!(1, 0),
+                "))) ",
+                result_at_2048!(0, 0),
+                ")",
+                r#"(func (export "shutdown") (unreachable))"#,
+            ),
+            ..VALID
+        };
+        for &runtime in Runtime::available() {
+            let config = PluginConfig {
+                runtime,
+                ..PluginConfig::new(TerminalInfo::STANDARD)
+            };
+            let mut plugin =
+                Plugin::from_wasm("configurable", configurable.wat().as_bytes(), &config).unwrap();
+            let options = vec![("a".to_string(), "1".to_string())];
+            plugin.configure(options.clone()).unwrap();
+            assert_eq!(plugin.apply("Hello").unwrap(), "", "{runtime}");
+            let error = plugin.shutdown().unwrap_err();
+            assert_eq!(error.kind(), "Trap", "{runtime}: {error}");
+            assert_eq!(
+                plugin.configure(Vec::new()).unwrap_err().kind(),
+                "OptionsRejected",
+                "{runtime}"
+            );
+
+            let mut plugin = Plugin::from_wasm("valid", VALID.wat().as_bytes(), &config).unwrap();
+            assert_eq!(
+                plugin.configure(options).unwrap_err().kind(),
+                "NotConfigurable",
+                "{runtime}"
+            );
+        }
+    }
+
+    #[cfg(feature = "compiler")]
+    #[test]
+    fn only_local_precompiled_plugins_are_deserialized() {
+        let config = PluginConfig::new(TerminalInfo::STANDARD);
+        let engine = wasmer_runtime::create_engine(&config);
+        let artifact = wasmer_runtime::compile(&engine, VALID.wat().as_bytes(), false, &config)
+            .unwrap()
+            .serialize()
+            .unwrap();
+        // Plugins from memory are treated like downloaded ones
+        let error = Plugin::from_wasm("precompiled", &artifact, &config)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), "UntrustedArtifact");
+        // Only a local `.wasmu` file is marked as precompiled
+        assert!(wasmer_runtime::compile(&engine, &artifact, true, &config).is_ok());
+    }
+}
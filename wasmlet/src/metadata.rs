@@ -0,0 +1,64 @@
+//! Metadata that plugins embed in the `wasmlet-metadata` custom section.
+//!
+//! The section contains `key=value` lines. The `metadata!` macro of the SDK creates it.
+
+use serde::Serialize;
+
+/// The name of the custom section.
+pub const SECTION: &str = "wasmlet-metadata";
+
+/// The version of the ABI this host implements.
+pub const ABI_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Metadata {
+    /// The ABI version the plugin was built for.
+    pub abi: Option<String>,
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub description: Option<String>,
+}
+
+impl Metadata {
+    /// Parse the content of a metadata section. Unknown keys and empty values are ignored.
+    pub fn parse(section: &[u8]) -> Self {
+        let mut metadata = Metadata::default();
+        for line in String::from_utf8_lossy(section).lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            if value.is_empty() {
+                continue;
+            }
+            let field = match key {
+                "abi" => &mut metadata.abi,
+                "name" => &mut metadata.name,
+                "version" => &mut metadata.version,
+                "description" => &mut metadata.description,
+                _ => continue,
+            };
+            *field = Some(value.to_string());
+        }
+        metadata
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_metadata_section() {
+        let metadata =
+            Metadata::parse(b"abi=1\nname=rainbow\nversion=0.1.0\ndescription=\nfuture=1\n");
+        assert_eq!(
+            metadata,
+            Metadata {
+                abi: Some("1".to_string()),
+                name: Some("rainbow".to_string()),
+                version: Some("0.1.0".to_string()),
+                description: None,
+            }
+        );
+    }
+}
@@ -0,0 +1,259 @@
+//! Native builds of plugins for `wasmlet test --differential`.
+//!
+//! Plugins that use `wasmlet_sdk::export_transformer!` export the same functions when they are compiled natively as a `cdylib`. Comparing the results of both builds finds miscompilations and code that depends on the target, like the width of `usize`.
+//!
+//! A native build runs inside the host process without any isolation, so only load plugins you built yourself.
+
+use std::env::consts::{DLL_PREFIX, DLL_SUFFIX};
+use std::path::{Path, PathBuf};
+
+use glob::glob;
+use libloading::Library;
+
+use crate::error::{ExecutionError, LifecycleError, LinkError, PluginError, ResolutionError};
+use crate::options;
+use crate::plugin::{PluginConfig, plugin_name, split_fragment};
+use crate::result_buffer::{self, HEADER_SIZE};
+use crate::runtime::{CONFIGURE, INIT, SHUTDOWN};
+use crate::transforms;
+
+type AllocateSharedBuffer = unsafe extern "C" fn(usize) -> usize;
+type FreeSharedBuffer = unsafe extern "C" fn(usize) -> bool;
+type Process = unsafe extern "C" fn(usize) -> usize;
+type Configure = unsafe extern "C" fn(usize) -> usize;
+type SetTerminal = unsafe extern "C" fn(u32, u32, u32, u32);
+type Init = unsafe extern "C" fn();
+type Shutdown = unsafe extern "C" fn();
+
+/// Find the native build of a plugin.
+///
+/// The specifier can be the path of a native library. Otherwise the release or debug build of the rust crate next to this project is used, like step 5 of the plugin resolution.
+pub fn find_native_library(specifier: &str) -> Result<PathBuf, PluginError> {
+    let (path, _) = split_fragment(options::split(specifier).0);
+    if path.ends_with(DLL_SUFFIX) && Path::new(path).is_file() {
+        return Ok(PathBuf::from(path));
+    }
+    let name = plugin_name(specifier);
+    let library = format!("{DLL_PREFIX}{}{DLL_SUFFIX}", name.replace('-', "_"));
+    // The plugins default to the wasm target, so native builds usually end up in a directory named after the host target
+    let patterns = [
+        format!("../{name}/target/*/release/{library}"),
+        format!("../{name}/target/release/{library}"),
+        format!("../{name}/target/*/debug/{library}"),
+        format!("../{name}/target/debug/{library}"),
+    ];
+    patterns
+        .iter()
+        .find_map(|pattern| glob(pattern).ok()?.filter_map(Result::ok).next())
+        .ok_or_else(|| {
+            ResolutionError::NotFound {
+                specifier: specifier.to_string(),
+                help: format!(
+                    "Searched for `{}`. Build the plugin natively with `cargo build --release --target <host target>`.",
+                    patterns.join("`, `")
+                ),
+            }
+            .into()
+        })
+}
+
+/// A plugin loaded from a native library.
+pub struct NativePlugin {
+    allocate_shared_buffer: AllocateSharedBuffer,
+    free_shared_buffer: FreeSharedBuffer,
+    process: Process,
+    /// Called with the options of the specifier after `init`, like the wasm build.
+    configure: Option<Configure>,
+    /// Called when the plugin is dropped.
+    shutdown: Option<Shutdown>,
+    max_output: u64,
+    lossy_utf8: bool,
+    /// Keeps the functions above valid.
+    _library: Library,
+}
+
+impl NativePlugin {
+    /// Load a native library, tell it about the terminal in the config, initialize and configure it.
+    ///
+    /// The transform and the options are taken from the specifier of the wasm build. Without a transform, the library has to export the primary transform. Unlike wasm modules, native libraries can not list their exports to find the only named one.
+    pub fn new(path: &Path, specifier: &str, config: &PluginConfig) -> Result<Self, PluginError> {
+        let (without_options, options) = options::split(specifier);
+        let (_, transform) = split_fragment(without_options);
+        let link_error = |source| LinkError::NativeLibrary {
+            path: path.display().to_string(),
+            source,
+        };
+        // SAFETY: Loading the library runs its initializers. It is a native build of a plugin, which the user trusts.
+        let library = unsafe { Library::new(path) }.map_err(link_error)?;
+        // SAFETY: The types match the functions exported by `export_transformer!` and the lifecycle macros of the SDK.
+        let mut plugin = unsafe {
+            let terminal = config.terminal;
+            if let Ok(set_terminal) = library.get::<SetTerminal>(b"wasmlet_set_terminal") {
+                set_terminal(
+                    terminal.width.unwrap_or(0) as u32,
+                    terminal.height.unwrap_or(0) as u32,
+                    terminal.color_depth as u32,
+                    terminal.is_tty as u32,
+                );
+            }
+            // Like the wasm build, the native build is initialized after it knows the terminal
+            if let Ok(init) = library.get::<Init>(INIT.as_bytes()) {
+                init();
+            }
+            NativePlugin {
+                allocate_shared_buffer: *library
+                    .get::<AllocateSharedBuffer>(b"allocate_shared_buffer")
+                    .map_err(link_error)?,
+                free_shared_buffer: *library
+                    .get::<FreeSharedBuffer>(b"free_shared_buffer")
+                    .map_err(link_error)?,
+                process: *library
+                    .get::<Process>(transforms::export_name(transform).as_bytes())
+                    .map_err(link_error)?,
+                configure: library
+                    .get::<Configure>(CONFIGURE.as_bytes())
+                    .ok()
+                    .map(|configure| *configure),
+                shutdown: library
+                    .get::<Shutdown>(SHUTDOWN.as_bytes())
+                    .ok()
+                    .map(|shutdown| *shutdown),
+                max_output: config.max_output,
+                lossy_utf8: config.lossy_utf8,
+                _library: library,
+            }
+        };
+        if let Some(configure) = plugin.configure {
+            options::check_format(&options)?;
+            let options: String = options
+                .iter()
+                .map(|(key, value)| format!("{key}={value}\n"))
+                .collect();
+            let (success, message) = plugin.call(configure, options.as_bytes())?;
+            if !success {
+                return Err(LifecycleError::OptionsRejected(message).into());
+            }
+        }
+        Ok(plugin)
+    }
+
+    /// Apply this plugin to the input, like [`crate::plugin::WasmletPlugin::apply`] does for the wasm build.
+    pub fn apply(&mut self, input: &[u8]) -> Result<String, PluginError> {
+        let (success, text) = self.call(self.process, input)?;
+        if !success {
+            return Err(ExecutionError::GuestError(text).into());
+        }
+        Ok(text)
+    }
+
+    /// Call the function with the input in a shared buffer and return the success flag and text of the result buffer.
+    fn call(&mut self, function: Process, input: &[u8]) -> Result<(bool, String), PluginError> {
+        // SAFETY: The plugin is trusted to return buffers of the requested size and result buffers with a complete header and text.
+        let (success, bytes) = unsafe {
+            let input_buffer = (self.allocate_shared_buffer)(input.len());
+            if input_buffer == 0 {
+                return Err(ExecutionError::InvalidInputBuffer(
+                    "`allocate_shared_buffer` returned a null pointer".into(),
+                )
+                .into());
+            }
+            std::ptr::copy_nonoverlapping(input.as_ptr(), input_buffer as *mut u8, input.len());
+            let result = function(input_buffer);
+            let freed_input = (self.free_shared_buffer)(input_buffer);
+            if result == 0 {
+                return Err(ExecutionError::NullResult.into());
+            }
+
+            let mut header = [0; HEADER_SIZE as usize];
+            header.copy_from_slice(std::slice::from_raw_parts(
+                result as *const u8,
+                HEADER_SIZE as usize,
+            ));
+            let parsed = result_buffer::parse_flag_and_length(header, self.max_output);
+            let bytes = parsed.as_ref().ok().map(|(_, length)| {
+                std::slice::from_raw_parts(
+                    (result + HEADER_SIZE as usize) as *const u8,
+                    *length as usize,
+                )
+                .to_vec()
+            });
+            let freed_result = (self.free_shared_buffer)(result);
+            if !freed_input || !freed_result {
+                return Err(ExecutionError::FailedToFreeSharedBuffer.into());
+            }
+            let (success, _) = parsed?;
+            (success, bytes.unwrap_or_default())
+        };
+
+        let text = result_buffer::decode_output(bytes, self.lossy_utf8)?;
+        Ok((success, text))
+    }
+}
+
+impl Drop for NativePlugin {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown {
+            // SAFETY: The library is still loaded, it is dropped after this.
+            unsafe { shutdown() }
+        }
+    }
+}
+
+/// Build an example plugin of this project for wasm and natively for the host, like `wasmlet test --differential` expects them, and return the native library.
+#[cfg(test)]
+pub fn build_example(name: &str) -> PathBuf {
+    use std::process::Command;
+
+    let rustc = Command::new("rustc").arg("-vV").output().unwrap();
+    let host = String::from_utf8(rustc.stdout)
+        .unwrap()
+        .lines()
+        .find_map(|line| line.strip_prefix("host: ").map(str::to_string))
+        .unwrap();
+    // The plugins default to the wasm target
+    for target in [None, Some(&host)] {
+        let mut cargo = Command::new(env!("CARGO"));
+        cargo
+            .args(["build", "--release"])
+            .current_dir(format!("../{name}"));
+        if let Some(target) = target {
+            cargo.args(["--target", target]);
+        }
+        assert!(cargo.status().unwrap().success(), "Failed to build {name}");
+    }
+    find_native_library(name).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::Plugin;
+    use crate::terminal::TerminalInfo;
+
+    #[test]
+    #[ignore = "builds ../rainbow with cargo"]
+    fn native_and_wasm_builds_agree() {
+        let library = build_example("rainbow");
+        let config = PluginConfig::new(TerminalInfo::STANDARD);
+        let mut native = NativePlugin::new(&library, "rainbow", &config).unwrap();
+        let mut wasm = Plugin::new("rainbow", &config).unwrap();
+        for input in [
+            "Hello, world!",
+            "",
+            "Roses are red\nViolets are blue",
+            "Grüße 🌈",
+        ] {
+            assert_eq!(
+                native.apply(input.as_bytes()).unwrap(),
+                wasm.apply(input).unwrap(),
+                "{input:?}"
+            );
+        }
+        // Rainbow refuses text that is colored already
+        let colored = "\x1b[31mred\x1b[0m";
+        let native_error = native.apply(colored.as_bytes()).unwrap_err();
+        let wasm_error = wasm.apply(colored).unwrap_err();
+        assert_eq!(native_error.kind(), "GuestError");
+        assert_eq!(native_error.guest_message(), wasm_error.guest_message());
+    }
+}
@@ -0,0 +1,281 @@
+//! Options that plugins declare in the `wasmlet-options` custom section.
+//!
+//! The section contains `<option>.<field>=<value>` lines. The fields are `type` (`string`, `integer`, `number` or `boolean`), `default`, `values` with the comma separated values the option allows and `help`. The `option!` macro of the SDK creates them. The host checks options against them before it passes them to `configure`, and `wasmlet help` shows them.
+//!
+//! Options follow the specifier after a `:`, like `bigfont:width=40:shadow=true`.
+
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::error::LifecycleError;
+
+/// The name of the custom section.
+pub const SECTION: &str = "wasmlet-options";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OptionType {
+    #[default]
+    String,
+    Integer,
+    Number,
+    Boolean,
+}
+
+impl OptionType {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "string" => Some(OptionType::String),
+            "integer" => Some(OptionType::Integer),
+            "number" => Some(OptionType::Number),
+            "boolean" => Some(OptionType::Boolean),
+            _ => None,
+        }
+    }
+
+    /// Whether the value can be parsed as this type.
+    pub fn accepts(self, value: &str) -> bool {
+        match self {
+            OptionType::String => true,
+            OptionType::Integer => value.parse::<i64>().is_ok(),
+            OptionType::Number => value.parse::<f64>().is_ok(),
+            OptionType::Boolean => matches!(value, "true" | "false"),
+        }
+    }
+}
+
+impl fmt::Display for OptionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptionType::String => write!(f, "string"),
+            OptionType::Integer => write!(f, "integer"),
+            OptionType::Number => write!(f, "number"),
+            OptionType::Boolean => write!(f, "boolean"),
+        }
+    }
+}
+
+/// An option the plugin takes.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct OptionSpec {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub option_type: OptionType,
+    /// What the plugin uses if the option is not given.
+    pub default: Option<String>,
+    /// The values the option allows. Any value of the type if empty.
+    pub values: Vec<String>,
+    pub help: Option<String>,
+}
+
+impl OptionSpec {
+    /// Check a value of this option. Returns what the value needs to look like if it is invalid.
+    pub fn check(&self, value: &str) -> Result<(), String> {
+        if !self.values.is_empty() && !self.values.iter().any(|allowed| allowed == value) {
+            return Err(format!(
+                "It needs to be one of `{}`",
+                self.values.join("`, `")
+            ));
+        }
+        if !self.option_type.accepts(value) {
+            return Err(format!("It needs to be a {}", self.option_type));
+        }
+        Ok(())
+    }
+}
+
+/// Parse the options sections of a module. Returns nothing if there are none.
+pub fn from_sections(sections: &[impl AsRef<[u8]>]) -> Option<Vec<OptionSpec>> {
+    (!sections.is_empty()).then(|| {
+        sections
+            .iter()
+            .flat_map(|section| parse(section.as_ref()))
+            .collect()
+    })
+}
+
+/// Parse the content of an options section. The options keep the order in which they first appear, lines with unknown fields are ignored.
+pub fn parse(section: &[u8]) -> Vec<OptionSpec> {
+    let mut options: Vec<OptionSpec> = Vec::new();
+    for line in String::from_utf8_lossy(section).lines() {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let Some((name, field)) = key.split_once('.') else {
+            continue;
+        };
+        let index = match options.iter().position(|option| option.name == name) {
+            Some(index) => index,
+            None => {
+                options.push(OptionSpec {
+                    name: name.to_string(),
+                    ..Default::default()
+                });
+                options.len() - 1
+            }
+        };
+        let option = &mut options[index];
+        match field {
+            "type" => option.option_type = OptionType::parse(value).unwrap_or_default(),
+            "default" => option.default = Some(value.to_string()),
+            "values" => {
+                option.values = value
+                    .split(',')
+                    .filter(|value| !value.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            }
+            "help" => option.help = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    options
+}
+
+/// Check that the options survive being passed as `key=value` lines. Otherwise a value could smuggle in more options.
+pub fn check_format(options: &[(String, String)]) -> Result<(), LifecycleError> {
+    match options
+        .iter()
+        .find(|(key, value)| key.contains(['=', '\n']) || value.contains('\n'))
+    {
+        Some((key, value)) => Err(LifecycleError::MalformedOption {
+            option: format!("{key}={value}"),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Check the options against the ones the plugin declares.
+pub fn check(schema: &[OptionSpec], options: &[(String, String)]) -> Result<(), LifecycleError> {
+    for (key, value) in options {
+        let Some(option) = schema.iter().find(|option| option.name == *key) else {
+            let names: Vec<_> = schema.iter().map(|option| option.name.as_str()).collect();
+            return Err(LifecycleError::UnknownOption {
+                option: key.clone(),
+                help: match names.as_slice() {
+                    [] => "The plugin takes no options".to_string(),
+                    _ => format!("The plugin takes the options `{}`", names.join("`, `")),
+                },
+            });
+        };
+        option
+            .check(value)
+            .map_err(|help| LifecycleError::InvalidOptionValue {
+                option: key.clone(),
+                value: value.clone(),
+                help,
+            })?;
+    }
+    Ok(())
+}
+
+/// Split the options off a specifier, like `width=40` in `bigfont:width=40`.
+///
+/// Every option follows a `:` and its key consists of letters, digits, `-` and `_`, so urls and paths with a `:` are left alone. Values can not contain a `:`.
+pub fn split(specifier: &str) -> (&str, Vec<(String, String)>) {
+    let mut source = specifier;
+    let mut options = Vec::new();
+    while let Some((rest, option)) = source.rsplit_once(':') {
+        let Some((key, value)) = option.split_once('=') else {
+            break;
+        };
+        let is_key = !key.is_empty()
+            && key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if rest.is_empty() || !is_key {
+            break;
+        }
+        options.push((key.to_string(), value.to_string()));
+        source = rest;
+    }
+    options.reverse();
+    (source, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn splits_options_off_the_specifier() {
+        assert_eq!(
+            split("bigfont:width=40:shadow=true"),
+            ("bigfont", options(&[("width", "40"), ("shadow", "true")]))
+        );
+        assert_eq!(
+            split("effects#gradient:colors=#ff0000"),
+            ("effects#gradient", options(&[("colors", "#ff0000")]))
+        );
+        assert_eq!(
+            split("https://example.com/plugin.wasm?version=2"),
+            ("https://example.com/plugin.wasm?version=2", Vec::new())
+        );
+    }
+
+    #[test]
+    fn parses_the_options_section() {
+        let schema = parse(
+            b"width.type=integer\nwidth.default=80\ncolor.values=red,green,\nwidth.help=The width\nshadow.type=boolean\ninvalid\n",
+        );
+        assert_eq!(
+            schema,
+            [
+                OptionSpec {
+                    name: "width".to_string(),
+                    option_type: OptionType::Integer,
+                    default: Some("80".to_string()),
+                    values: Vec::new(),
+                    help: Some("The width".to_string()),
+                },
+                OptionSpec {
+                    name: "color".to_string(),
+                    values: vec!["red".to_string(), "green".to_string()],
+                    ..Default::default()
+                },
+                OptionSpec {
+                    name: "shadow".to_string(),
+                    option_type: OptionType::Boolean,
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_options_against_the_schema() {
+        let schema = parse(b"width.type=integer\ncolor.values=red,green\n");
+        assert!(check(&schema, &options(&[("width", "40"), ("color", "red")])).is_ok());
+        assert!(matches!(
+            check(&schema, &options(&[("width", "wide")])),
+            Err(LifecycleError::InvalidOptionValue { .. })
+        ));
+        assert!(matches!(
+            check(&schema, &options(&[("color", "blue")])),
+            Err(LifecycleError::InvalidOptionValue { .. })
+        ));
+        assert!(matches!(
+            check(&schema, &options(&[("height", "10")])),
+            Err(LifecycleError::UnknownOption { .. })
+        ));
+    }
+
+    #[test]
+    fn options_can_not_inject_more_options() {
+        assert!(check_format(&options(&[("width", "40"), ("text", "a=b")])).is_ok());
+        for malformed in [("text", "x\nwidth=999"), ("a=b", "c"), ("a\nb", "c")] {
+            assert!(matches!(
+                check_format(&options(&[malformed])),
+                Err(LifecycleError::MalformedOption { .. })
+            ));
+        }
+    }
+}
@@ -1,44 +1,28 @@
+use std::collections::HashSet;
+use std::ops::Range;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
 
-use glob::glob;
-use thiserror::Error;
-use wasmer::{
-    CompileError, ExportError, Instance, InstantiationError, Memory, MemoryAccessError, Module,
-    RuntimeError, Store, TypedFunction, WasmPtr, imports,
+use crate::compile::Compiler;
+use crate::content_types::{self, ContentTypes, Payload};
+use crate::error::{
+    BackendError, DownloadError, ExecutionError, LifecycleError, PluginError, ResolutionError,
 };
+use crate::extism::ExtismPlugin;
+use crate::options::{self, OptionSpec};
+use crate::preinit;
+use crate::result_buffer::{self, HEADER_SIZE};
+use crate::runtime::{Engine, GuestInstance, GuestModule, Runtime, Snapshot};
+use crate::stats::StageStats;
+use crate::terminal::TerminalInfo;
+use crate::transforms;
+use crate::wasmer_runtime;
+use glob::glob;
+use sha2::{Digest, Sha256};
 
-#[derive(Error, Debug)]
-pub enum PluginError {
-    #[error("Failed to load plugin: {0}")]
-    FailedToLoadModule(std::io::Error),
-    #[error("Failed to compile plugin: {0}")]
-    CompileError(#[from] CompileError),
-    // Clippy recommended that we box the error and I agree with the reasoning
-    // https://rust-lang.github.io/rust-clippy/master/index.html#result_large_err
-    #[error("Failed to instantiate plugin: {0}")]
-    InstantiationError(#[from] Box<InstantiationError>),
-    #[error("The plugin does not provide the required function `{0}` in its exports ({1})")]
-    PluginDoesNotExportRequiredFunction(String, ExportError),
-    #[error("The plugin does not export memory: `memory`")]
-    PluginDoesNotExportMemory(#[source] ExportError),
-    #[error("The plugin crashed while allocating a buffer: {0}")]
-    RuntimeErrorWhileFreeingBuffer(#[source] RuntimeError),
-    #[error("The plugin crashed while freeing a buffer: {0}")]
-    RuntimeErrorWhileAllocatingBuffer(#[source] RuntimeError),
-    #[error("The plugin crashed while processing your input: {0}")]
-    RuntimeErrorWhileProcessingText(#[source] RuntimeError),
-    #[error("The plugin failed to process the input: {0}")]
-    GuestError(String),
-    #[error("Failed to free a shared buffer")]
-    FailedToFreeSharedBuffer,
-    /// Umbrella error when `process` returns a pointer to something that is not a valid result.
-    #[error(
-        "Process returned a malformed datastructure, please check that the plugin returns a correctly formatted buffer. (1 byte success flag, 4 byte length, length bytes utf8-formatted string)"
-    )]
-    ProcessReturnedMalformedDatastructure(#[source] MemoryAccessError),
-    #[error("The plugin failed to allocate a valid buffer for the input.")]
-    AllocatedBufferCausedMemoryError(#[source] MemoryAccessError),
-}
+/// The size of a page of wasm memory.
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
 
 fn try_glob(pattern: &str) -> Option<(PathBuf, Vec<u8>)> {
     let plugin_path = glob(pattern);
@@ -54,6 +38,26 @@ fn try_glob(pattern: &str) -> Option<(PathBuf, Vec<u8>)> {
     return None;
 }
 
+/// Derive a short name for the plugin from its specifier, e.g. `rainbow` for `../plugins/rainbow.wasm`.
+pub fn plugin_name(specifier: &str) -> &str {
+    let (specifier, _) = options::split(specifier);
+    let specifier = specifier.strip_prefix("extism:").unwrap_or(specifier);
+    let (specifier, _) = split_fragment(specifier);
+    let file_name = specifier.rsplit('/').next().unwrap_or(specifier);
+    file_name
+        .strip_suffix(".wasm")
+        .or_else(|| file_name.strip_suffix(".wasmu"))
+        .unwrap_or(file_name)
+}
+
+/// Split the part after a `#` off a specifier. It names the function of an Extism plugin or a [transform](crate::transforms) of a WASMlet plugin.
+pub fn split_fragment(specifier: &str) -> (&str, Option<&str>) {
+    match specifier.rsplit_once('#') {
+        Some((source, fragment)) => (source, Some(fragment)),
+        None => (specifier, None),
+    }
+}
+
 /// Find the source of a plugin.
 ///
 /// It will look in the following locations and load the first one where it finds a file:
@@ -63,195 +67,885 @@ fn try_glob(pattern: &str) -> Option<(PathBuf, Vec<u8>)> {
 /// 3. Try the specifier with an appended `.wasm` extension.
 /// 4. Try to load the specifier relative to the directory specified in `WASMLET_PLUGIN_DIR` (defaults to `/etc/wasmlet/plugins`).
 /// 5. Try to load the specifier from a rust crate next to this project.
-fn load_plugin_source(specifier: &str) -> Result<Vec<u8>, PluginError> {
+///
+/// Returns the url or path the plugin was loaded from and its content.
+pub fn load_plugin_source(specifier: &str) -> Result<PluginSource, PluginError> {
     if specifier.starts_with("https://") {
-        let mut response = ureq::get(specifier)
-            .header("Accept", "application/wasm")
-            .call()
-            .map_err(|_| {
-                // TODO: Rework error types
-                PluginError::FailedToLoadModule(std::io::Error::new(
-                    std::io::ErrorKind::NotFound,
-                    "Failed to download plugin",
-                ))
-            })?;
-        if response.headers().get("Content-Type").map(|v| v.as_bytes()) != Some(b"application/wasm")
-        {
-            return Err(PluginError::FailedToLoadModule(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                "Server did not provide an `application/wasm` file",
-            )));
+        return Ok(PluginSource {
+            location: specifier.to_string(),
+            bytes: download_plugin(specifier)?,
+            precompiled: false,
+        });
+    }
+
+    let mut plugin_dir = std::env::var("WASMLET_PLUGIN_DIR").unwrap_or("".into());
+    if plugin_dir.is_empty() {
+        plugin_dir = "/etc/wasmlet/plugins".to_string();
+    }
+    let patterns = [
+        format!("{}*", specifier),
+        format!("{}/{}*", plugin_dir, specifier),
+        format!(
+            "../{}/target/wasm32-*/release/{}.wasm",
+            specifier, specifier
+        ),
+    ];
+    for pattern in &patterns {
+        if let Some((path, file)) = try_glob(pattern) {
+            log::debug!("Found plugin at {:?}", path);
+            return Ok(PluginSource {
+                location: path.display().to_string(),
+                precompiled: path
+                    .extension()
+                    .is_some_and(|extension| extension == "wasmu"),
+                bytes: file,
+            });
         }
-        let wasm_from_the_internet = response.body_mut().read_to_vec().map_err(|_| {
-            PluginError::FailedToLoadModule(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                "Failed to download plugin",
-            ))
+    }
+
+    Err(ResolutionError::NotFound {
+        specifier: specifier.to_string(),
+        help: format!(
+            "Searched for `{}`. Set `WASMLET_PLUGIN_DIR` to load plugins from another directory.",
+            patterns.join("`, `")
+        ),
+    }
+    .into())
+}
+
+/// A plugin found by [`load_plugin_source`].
+pub struct PluginSource {
+    /// The url or path the plugin was loaded from.
+    pub location: String,
+    pub bytes: Vec<u8>,
+    /// The plugin is a local `.wasmu` file, which `wasmlet compile` creates.
+    ///
+    /// Only these are loaded as precompiled plugins. They contain machine code that runs outside of the sandbox, so plugins from the internet or from memory are never deserialized.
+    pub precompiled: bool,
+}
+
+/// Download a plugin. The server has to provide it as `application/wasm`.
+fn download_plugin(url: &str) -> Result<Vec<u8>, DownloadError> {
+    let transport_error = |source| DownloadError::Transport {
+        url: url.to_string(),
+        source: Box::new(source),
+    };
+    let mut response = ureq::get(url)
+        .header("Accept", "application/wasm")
+        .call()
+        .map_err(|error| match error {
+            ureq::Error::StatusCode(status) => DownloadError::HttpStatus {
+                url: url.to_string(),
+                status,
+            },
+            error => transport_error(error),
         })?;
-        log::debug!("Downloaded plugin from the internet");
-        return Ok(wasm_from_the_internet);
+    let content_type = response.headers().get("Content-Type");
+    if content_type.map(|v| v.as_bytes()) != Some(b"application/wasm") {
+        return Err(DownloadError::WrongContentType {
+            url: url.to_string(),
+            content_type: content_type
+                .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+                .unwrap_or_else(|| "no content type".to_string()),
+        });
     }
+    let wasm_from_the_internet = response.body_mut().read_to_vec().map_err(transport_error)?;
+    log::debug!("Downloaded plugin from the internet");
+    Ok(wasm_from_the_internet)
+}
+
+/// Where a plugin was loaded from.
+#[derive(Clone)]
+pub struct PluginInfo {
+    /// A short name derived from the specifier.
+    pub name: String,
+    /// The url or path the plugin was loaded from.
+    pub location: String,
+    /// Hex encoded sha256 hash of the wasm module.
+    pub sha256: String,
+}
 
-    if let Some((path, file)) = try_glob(&format!("{}*", specifier)) {
-        log::debug!("Found plugin at {:?}", path);
-        return Ok(file);
+impl PluginInfo {
+    fn new(specifier: &str, location: String, wasm_bytes: &[u8]) -> Self {
+        PluginInfo {
+            name: plugin_name(specifier).to_string(),
+            location,
+            sha256: format!("{:x}", Sha256::digest(wasm_bytes)),
+        }
     }
+}
 
-    let mut plugin_dir = std::env::var("WASMLET_PLUGIN_DIR").unwrap_or("".into());
-    if plugin_dir.is_empty() {
-        plugin_dir = "/etc/wasmlet/plugins".to_string();
+/// The default limit for the output of a single plugin call.
+pub const DEFAULT_MAX_OUTPUT: u64 = 16 * 1024 * 1024;
+
+/// Settings that apply to every plugin.
+#[derive(Clone, Copy, Debug)]
+pub struct PluginConfig {
+    /// Made available to the plugin.
+    pub terminal: TerminalInfo,
+    /// Count the executed wasm operators, so the fuel consumed by a stage can be reported.
+    pub metering: bool,
+    /// The maximum number of bytes a plugin call may return.
+    pub max_output: u64,
+    /// Replace invalid utf8 in the output instead of failing.
+    pub lossy_utf8: bool,
+    /// The number of wasm operators a single call may execute. Stops plugins that are stuck in a loop.
+    pub fuel_limit: Option<u64>,
+    /// Compiles the plugins. Without a compiler only precompiled plugins can be loaded. Only used by wasmer.
+    pub compiler: Option<Compiler>,
+    /// The runtime that runs the plugins.
+    pub runtime: Runtime,
+    /// Restore the instance to its state right after instantiation before every call, so nothing carries over from one call to the next. See [`Snapshot`] for what is restored.
+    ///
+    /// Plugins are compiled with all their mutable globals exported, see [`preinit::export_mutable_globals`]. Precompiled plugins need to come from `wasmlet compile`, which exports them as well.
+    pub reset: bool,
+}
+
+impl PluginConfig {
+    pub fn new(terminal: TerminalInfo) -> Self {
+        PluginConfig {
+            terminal,
+            metering: false,
+            max_output: DEFAULT_MAX_OUTPUT,
+            lossy_utf8: false,
+            fuel_limit: None,
+            compiler: Compiler::default_compiler(),
+            runtime: Runtime::default(),
+            reset: false,
+        }
     }
-    if let Some((path, file)) = try_glob(&format!("{}/{}*", plugin_dir, specifier)) {
-        log::debug!("Found plugin at {:?}", path);
-        return Ok(file);
+
+    pub fn needs_metering(&self) -> bool {
+        self.metering || self.fuel_limit.is_some()
     }
 
-    if let Some((path, file)) = try_glob(&format!(
-        "../{}/target/wasm32-*/release/{}.wasm",
-        specifier, specifier
-    )) {
-        log::debug!("Found plugin at {:?}", path);
-        return Ok(file);
+    /// Whether all plugins can be compiled by the same engine. The metering middleware of wasmer keeps state for a single module.
+    fn can_share_engine(&self) -> bool {
+        self.runtime != Runtime::Wasmer || !self.needs_metering()
     }
+}
 
-    return Err(PluginError::FailedToLoadModule(std::io::Error::new(
-        std::io::ErrorKind::NotFound,
-        format!("Could not find plugin {}", specifier),
-    )));
+/// Load and compile all plugins of a pipeline in parallel.
+///
+/// Every stage is resolved and compiled on its own thread, so the pipeline is ready in about the time of its slowest stage. The stages share one engine if the runtime allows it, see [`PluginConfig::can_share_engine`]. Instantiation happens on the first call of each plugin.
+///
+/// Returns the plugins in the order of the specifiers.
+pub fn load_pipeline(
+    specifiers: &[String],
+    config: &PluginConfig,
+) -> Vec<Result<Plugin, PluginError>> {
+    let shared_engine = config.can_share_engine().then(|| Engine::new(config));
+    std::thread::scope(|scope| {
+        let stages: Vec<_> = specifiers
+            .iter()
+            .map(|specifier| {
+                let engine = shared_engine.clone().unwrap_or_else(|| Engine::new(config));
+                scope.spawn(move || CompiledPlugin::load(specifier, engine, config))
+            })
+            .collect();
+        stages
+            .into_iter()
+            .map(|stage| {
+                stage
+                    .join()
+                    .expect("Loading a plugin does not panic")
+                    .map(Plugin::from)
+            })
+            .collect()
+    })
 }
 
+/// A plugin that can be used as a stage in the pipeline.
+///
+/// Plugins are compiled right away, but only instantiated when they are used.
 pub struct Plugin {
-    allocate_shared_buffer: TypedFunction<u32, WasmPtr<u8>>,
-    free_shared_buffer: TypedFunction<WasmPtr<u8>, u32>,
-    process: TypedFunction<WasmPtr<u8>, WasmPtr<u8>>,
-    store: Store,
-    memory: Memory,
+    pub info: PluginInfo,
+    /// Timings and resource usage of this plugin.
+    pub stats: StageStats,
+    /// Shared with the plugins created by [`Plugin::duplicate`].
+    module: Arc<CompiledModule>,
+    instance: Option<PluginAbi>,
+    metering: bool,
+    fuel_limit: Option<u64>,
+    reset: bool,
+    /// The last call trapped. The instance is replaced then instead of being reset, because the plugin may have stopped in the middle of changing state the snapshot does not cover, like its tables.
+    trapped: bool,
+    /// Passed to `configure` whenever the plugin is instantiated.
+    options: Vec<(String, String)>,
 }
 
-impl Plugin {
-    /// Load the plugin from the given file.
-    pub fn new(specifier: impl AsRef<str>) -> Result<Self, PluginError> {
-        let wasm_bytes = load_plugin_source(specifier.as_ref())?;
-
-        let mut store = Store::default();
-        let module = Module::new(&store, &wasm_bytes)?;
-        let instance = Instance::new(&mut store, &module, &imports! {}).map_err(Box::new)?;
-
-        let allocate_shared_buffer = instance
-            .exports
-            .get_typed_function::<u32, WasmPtr<u8>>(&store, "allocate_shared_buffer")
-            .map_err(|e| {
-                PluginError::PluginDoesNotExportRequiredFunction(
-                    "allocate_shared_buffer".to_string(),
-                    e,
-                )
-            })?;
-        let free_shared_buffer = instance
-            .exports
-            .get_typed_function::<WasmPtr<u8>, u32>(&store, "free_shared_buffer")
-            .map_err(|e| {
-                PluginError::PluginDoesNotExportRequiredFunction(
-                    "free_shared_buffer".to_string(),
-                    e,
-                )
-            })?;
-        let process = instance
-            .exports
-            .get_typed_function::<WasmPtr<u8>, WasmPtr<u8>>(&store, "process")
-            .map_err(|e| {
-                PluginError::PluginDoesNotExportRequiredFunction("process".to_string(), e)
-            })?;
-
-        let memory = instance
-            .exports
-            .get_memory("memory")
-            .map_err(PluginError::PluginDoesNotExportMemory)?
-            .clone();
-
-        Ok(Plugin {
-            allocate_shared_buffer,
-            free_shared_buffer,
-            process,
-            store,
-            memory,
+/// Everything needed to instantiate a compiled module.
+struct CompiledModule {
+    abi: CompiledAbi,
+    config: PluginConfig,
+    /// What the plugin declares to accept and produce.
+    content_types: Option<ContentTypes>,
+}
+
+enum CompiledAbi {
+    Wasmlet {
+        module: Box<dyn GuestModule>,
+        /// The export of the selected transform.
+        function: String,
+        /// The options the plugin declares. Options are not checked by the host if it declares none.
+        schema: Option<Vec<OptionSpec>>,
+    },
+    /// Extism plugins only run in wasmer.
+    Extism {
+        engine: wasmer::Engine,
+        module: wasmer::Module,
+        /// The function to call.
+        function: String,
+    },
+}
+
+impl CompiledModule {
+    /// Check options before they are passed to the plugin.
+    fn check_options(&self, options: &[(String, String)]) -> Result<(), PluginError> {
+        options::check_format(options)?;
+        match &self.abi {
+            CompiledAbi::Wasmlet {
+                schema: Some(schema),
+                ..
+            } => Ok(options::check(schema, options)?),
+            CompiledAbi::Wasmlet { schema: None, .. } => Ok(()),
+            // Extism plugins read their configuration from the host, which WASMlet does not provide
+            CompiledAbi::Extism { .. } if !options.is_empty() => {
+                Err(LifecycleError::NotConfigurable.into())
+            }
+            CompiledAbi::Extism { .. } => Ok(()),
+        }
+    }
+
+    fn instantiate(
+        &self,
+        name: &str,
+        options: &[(String, String)],
+    ) -> Result<PluginAbi, PluginError> {
+        Ok(match &self.abi {
+            CompiledAbi::Wasmlet {
+                module, function, ..
+            } => PluginAbi::Wasmlet(WasmletPlugin::new(
+                module.instantiate(name, function, &self.config)?,
+                &self.config,
+                options,
+            )?),
+            CompiledAbi::Extism {
+                engine,
+                module,
+                function,
+            } => PluginAbi::Extism(ExtismPlugin::new(
+                name,
+                wasmer::Store::new(engine.clone()),
+                module,
+                function,
+                &self.config,
+            )?),
+        })
+    }
+}
+
+/// A plugin that was resolved and compiled. It has no instance yet, so it can be sent to another thread.
+struct CompiledPlugin {
+    info: PluginInfo,
+    stats: StageStats,
+    module: CompiledModule,
+    /// The options given in the specifier. They were already checked.
+    options: Vec<(String, String)>,
+}
+
+impl CompiledPlugin {
+    /// Resolve the plugin and compile it with the given engine.
+    ///
+    /// Specifiers starting with `extism:` load an Extism plugin. They need to name the function that should be called after a `#`, for example `extism:plugin.wasm#function`. Other specifiers can select a transform after a `#`, for example `effects#gradient`. Options follow after a `:`, for example `bigfont:width=40`.
+    fn load(specifier: &str, engine: Engine, config: &PluginConfig) -> Result<Self, PluginError> {
+        let (without_options, _) = options::split(specifier);
+        let (source, extism_function) = match without_options.strip_prefix("extism:") {
+            Some(extism_specifier) => {
+                let (source, function) = split_fragment(extism_specifier);
+                let function =
+                    function.ok_or_else(|| ResolutionError::MissingExtismFunctionName {
+                        specifier: extism_specifier.to_string(),
+                    })?;
+                (source, Some(function))
+            }
+            None => (split_fragment(without_options).0, None),
+        };
+        let start = Instant::now();
+        let source = load_plugin_source(source)?;
+        let resolution_time = start.elapsed();
+
+        let mut plugin = CompiledPlugin::compile(
+            specifier,
+            source.location,
+            &source.bytes,
+            source.precompiled,
+            extism_function,
+            engine,
+            config,
+        )?;
+        plugin.stats.resolution_time = resolution_time;
+        Ok(plugin)
+    }
+
+    /// Compile a module. It is an Extism plugin if a function is given, otherwise the transform is selected from the specifier. The options in the specifier are checked right away.
+    ///
+    /// Only `precompiled` modules are deserialized, see [`PluginSource::precompiled`].
+    fn compile(
+        specifier: &str,
+        location: String,
+        wasm_bytes: &[u8],
+        precompiled: bool,
+        extism_function: Option<&str>,
+        engine: Engine,
+        config: &PluginConfig,
+    ) -> Result<Self, PluginError> {
+        let mut stats = StageStats {
+            bytes_loaded: wasm_bytes.len(),
+            ..Default::default()
+        };
+        let info = PluginInfo::new(specifier, location, wasm_bytes);
+        let (without_options, options) = options::split(specifier);
+
+        let start = Instant::now();
+        let mut declared_content_types = None;
+        let abi = match (extism_function, &engine) {
+            (None, engine) => {
+                // The snapshot for `reset` can only restore the globals the plugin exports
+                let module = if config.reset && !precompiled {
+                    let instrumented = preinit::export_mutable_globals(wasm_bytes)?;
+                    engine.compile(&instrumented, false, config)?
+                } else {
+                    engine.compile(wasm_bytes, precompiled, config)?
+                };
+                if config.reset
+                    && module
+                        .custom_sections(preinit::EXPORTED_GLOBALS_SECTION)
+                        .is_empty()
+                {
+                    return Err(BackendError::HiddenGlobals.into());
+                }
+                let (_, transform) = split_fragment(without_options);
+                let listed =
+                    transforms::from_sections(&module.custom_sections(transforms::SECTION));
+                let function = transforms::select(&module.function_exports(), &listed, transform)?;
+                let schema = options::from_sections(&module.custom_sections(options::SECTION));
+                declared_content_types = module
+                    .custom_sections(content_types::SECTION)
+                    .first()
+                    .map(|section| ContentTypes::parse(section));
+                CompiledAbi::Wasmlet {
+                    module,
+                    function,
+                    schema,
+                }
+            }
+            (Some(function), Engine::Wasmer(engine)) => CompiledAbi::Extism {
+                engine: engine.clone(),
+                module: wasmer_runtime::compile(engine, wasm_bytes, precompiled, config)?,
+                function: function.to_string(),
+            },
+            #[cfg(feature = "wasmtime")]
+            (Some(_), Engine::Wasmtime(_)) => {
+                return Err(BackendError::Unsupported {
+                    runtime: config.runtime,
+                    feature: "Extism plugins",
+                }
+                .into());
+            }
+        };
+        stats.compile_time = start.elapsed();
+
+        let module = CompiledModule {
+            abi,
+            config: *config,
+            content_types: declared_content_types,
+        };
+        module.check_options(&options)?;
+        Ok(CompiledPlugin {
+            info,
+            stats,
+            module,
+            options,
         })
     }
+}
+
+impl From<CompiledPlugin> for Plugin {
+    fn from(compiled: CompiledPlugin) -> Self {
+        Plugin {
+            info: compiled.info,
+            stats: compiled.stats,
+            metering: compiled.module.config.needs_metering(),
+            fuel_limit: compiled.module.config.fuel_limit,
+            reset: compiled.module.config.reset,
+            trapped: false,
+            module: Arc::new(compiled.module),
+            instance: None,
+            options: compiled.options,
+        }
+    }
+}
+
+/// The ABI the plugin implements.
+enum PluginAbi {
+    /// A plugin that implements the WASMlet ABI.
+    Wasmlet(WasmletPlugin),
+    /// A plugin written with the Extism PDK.
+    Extism(ExtismPlugin),
+}
+
+impl PluginAbi {
+    /// The fuel left in the instance. Only works with metering.
+    fn remaining_fuel(&mut self) -> u64 {
+        match self {
+            PluginAbi::Wasmlet(plugin) => plugin.guest.remaining_fuel(),
+            PluginAbi::Extism(plugin) => {
+                let (store, instance) = plugin.store_and_instance();
+                wasmer_runtime::remaining_fuel(store, instance)
+            }
+        }
+    }
+
+    fn set_remaining_fuel(&mut self, fuel: u64) {
+        match self {
+            PluginAbi::Wasmlet(plugin) => plugin.guest.set_remaining_fuel(fuel),
+            PluginAbi::Extism(plugin) => {
+                let (store, instance) = plugin.store_and_instance();
+                wasmer_runtime::set_remaining_fuel(store, instance, fuel)
+            }
+        }
+    }
+
+    /// Restore the snapshot taken after instantiation. Returns false if the instance needs to be replaced instead.
+    fn reset(&mut self) -> bool {
+        match self {
+            PluginAbi::Wasmlet(plugin) => plugin.reset(),
+            // Extism plugins are not snapshotted, they get a new instance for every call
+            PluginAbi::Extism(_) => false,
+        }
+    }
+
+    /// The current size of the exported memory in pages.
+    fn memory_pages(&mut self) -> Option<u32> {
+        match self {
+            PluginAbi::Wasmlet(plugin) => {
+                Some((plugin.guest.memory_size() / WASM_PAGE_SIZE) as u32)
+            }
+            PluginAbi::Extism(plugin) => {
+                let (store, instance) = plugin.store_and_instance();
+                wasmer_runtime::memory_pages(store, instance)
+            }
+        }
+    }
+}
+
+impl Plugin {
+    /// Load the plugin from the given specifier. It is compiled right away and instantiated on first use.
+    ///
+    /// Specifiers starting with `extism:` load an Extism plugin. They need to name the function that should be called after a `#`, for example `extism:plugin.wasm#function`.
+    pub fn new(specifier: impl AsRef<str>, config: &PluginConfig) -> Result<Self, PluginError> {
+        CompiledPlugin::load(specifier.as_ref(), Engine::new(config), config).map(Plugin::from)
+    }
+
+    /// Create a WASMlet plugin from the bytes of a module. The specifier is only used to name the plugin and select a transform.
+    ///
+    /// The bytes need to be a wasm module, precompiled plugins are rejected.
+    pub fn from_wasm(
+        specifier: &str,
+        wasm_bytes: &[u8],
+        config: &PluginConfig,
+    ) -> Result<Self, PluginError> {
+        CompiledPlugin::compile(
+            specifier,
+            "memory".to_string(),
+            wasm_bytes,
+            false,
+            None,
+            Engine::new(config),
+            config,
+        )
+        .map(Plugin::from)
+    }
+
+    /// Create another plugin from the same compiled module. It gets its own instance, so both can be used at the same time.
+    pub fn duplicate(&self) -> Plugin {
+        Plugin {
+            info: self.info.clone(),
+            stats: StageStats {
+                resolution_time: self.stats.resolution_time,
+                bytes_loaded: self.stats.bytes_loaded,
+                compile_time: self.stats.compile_time,
+                ..Default::default()
+            },
+            module: Arc::clone(&self.module),
+            instance: None,
+            metering: self.metering,
+            fuel_limit: self.fuel_limit,
+            reset: self.reset,
+            trapped: false,
+            options: self.options.clone(),
+        }
+    }
+
+    /// Instantiate the plugin if that did not happen yet. Plugins are instantiated automatically when they are first applied.
+    pub fn instantiate(&mut self) -> Result<(), PluginError> {
+        self.abi().map(|_| ())
+    }
+
+    /// The instance of the plugin. Instantiates it on first use.
+    ///
+    /// If instantiation fails, the plugin stays compiled and the next call fails the same way.
+    fn abi(&mut self) -> Result<&mut PluginAbi, PluginError> {
+        if self.instance.is_none() {
+            let start = Instant::now();
+            let abi = self.module.instantiate(&self.info.name, &self.options)?;
+            self.stats.instantiation_time = start.elapsed();
+            self.instance = Some(abi);
+        }
+        Ok(self
+            .instance
+            .as_mut()
+            .expect("The plugin was instantiated above"))
+    }
+
+    /// Restore the instance before a call with [`PluginConfig::reset`]. An instance that can not be restored is shut down, so the call gets a new one.
+    fn reset_instance(&mut self) {
+        let restored = !self.trapped && self.instance.as_mut().is_some_and(PluginAbi::reset);
+        if !restored {
+            if let Err(error) = self.shutdown() {
+                log::debug!("Failed to shut down an instance before replacing it: {error}");
+            }
+        }
+        self.trapped = false;
+    }
+
+    /// Pass options to the `configure` export of the plugin. They are `key=value` pairs, neither may contain a newline and the key no `=`. Options that do are rejected.
+    ///
+    /// If the plugin declares its [options](crate::options), they are checked first. The current instance is shut down and a new one is configured right away, so invalid options are reported before any text is processed. Later instances, like the ones of a [`PluginPool`](crate::pool::PluginPool), get the same options.
+    pub fn configure(&mut self, options: Vec<(String, String)>) -> Result<(), PluginError> {
+        self.module.check_options(&options)?;
+        self.shutdown()?;
+        self.options = options;
+        self.instantiate()
+    }
+
+    /// Call the `shutdown` export of the plugin and drop its instance. The next call instantiates the plugin again.
+    ///
+    /// Dropping the plugin does this as well, but can only log errors. Instances that trapped are dropped without calling `shutdown`, because their state may be inconsistent.
+    pub fn shutdown(&mut self) -> Result<(), PluginError> {
+        let trapped = std::mem::take(&mut self.trapped);
+        match self.instance.take() {
+            Some(PluginAbi::Wasmlet(mut plugin)) if !trapped => {
+                if let Some(limit) = self.fuel_limit {
+                    plugin.guest.set_remaining_fuel(limit);
+                }
+                Ok(plugin.guest.shutdown()?)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Apply this plugin to a text.
+    pub fn apply(&mut self, input: &str) -> Result<String, PluginError> {
+        self.apply_bytes(input.as_bytes())
+    }
+
+    /// Apply this plugin to raw bytes, which do not need to be valid utf8. The output needs to be text.
+    pub fn apply_bytes(&mut self, input: &[u8]) -> Result<String, PluginError> {
+        let output = self.apply_binary(input)?;
+        Ok(result_buffer::decode_output(
+            output,
+            self.module.config.lossy_utf8,
+        )?)
+    }
+
+    /// Apply this plugin to a payload. The output has the type the plugin declares to produce, or [`TEXT_PLAIN`](content_types::TEXT_PLAIN) if it declares none.
+    ///
+    /// Text output is checked to be utf8 like the output of [`Plugin::apply`], anything else is passed on as it is.
+    pub fn apply_payload(&mut self, input: &Payload) -> Result<Payload, PluginError> {
+        let content_type = self
+            .content_types()
+            .map_or(content_types::TEXT_PLAIN, ContentTypes::produces)
+            .to_string();
+        if content_types::is_text(&content_type) {
+            let text = self.apply_bytes(&input.bytes)?;
+            return Ok(Payload::new(text.into_bytes(), content_type));
+        }
+        let bytes = self.apply_binary(&input.bytes)?;
+        Ok(Payload::new(bytes, content_type))
+    }
+
+    /// Apply this plugin to raw bytes and return its output as it is.
+    pub fn apply_binary(&mut self, input: &[u8]) -> Result<Vec<u8>, PluginError> {
+        if self.reset {
+            self.reset_instance();
+        }
+        let (metering, fuel_limit) = (self.metering, self.fuel_limit);
+        let abi = self.abi()?;
+        if let Some(limit) = fuel_limit {
+            abi.set_remaining_fuel(limit);
+        }
+        let fuel_before = metering.then(|| abi.remaining_fuel());
+        let start = Instant::now();
+        let result = match &mut *abi {
+            PluginAbi::Wasmlet(plugin) => plugin.apply(input),
+            PluginAbi::Extism(plugin) => plugin.apply(input),
+        };
+        let process_time = start.elapsed();
+        let remaining_fuel = metering.then(|| abi.remaining_fuel());
+        let peak_memory_pages = abi.memory_pages();
+        self.stats.process_time = process_time;
+        self.stats.fuel_consumed = fuel_before
+            .zip(remaining_fuel)
+            .map(|(before, after)| before - after);
+        let result = match (result, self.fuel_limit) {
+            // Metering stops the plugin with a trap when it runs out of fuel
+            (
+                Err(PluginError::Execution(
+                    ExecutionError::Trap { .. } | ExecutionError::Panicked { .. },
+                )),
+                Some(limit),
+            ) if remaining_fuel == Some(0) => Err(ExecutionError::FuelExhausted { limit }.into()),
+            (result, _) => result,
+        };
+        self.trapped = matches!(
+            result,
+            Err(PluginError::Execution(
+                ExecutionError::Trap { .. }
+                    | ExecutionError::Panicked { .. }
+                    | ExecutionError::FuelExhausted { .. }
+            ))
+        );
+        self.stats.peak_memory_pages = peak_memory_pages;
+        self.stats.input_bytes = input.len();
+        let output = result?;
+        self.stats.output_bytes = output.len();
+        Ok(output)
+    }
+
+    /// The current size of the guest memory in 64KiB pages.
+    pub fn memory_pages(&mut self) -> Option<u32> {
+        self.instance.as_mut()?.memory_pages()
+    }
+
+    /// The content types the plugin declares. Only WASMlet plugins can declare them.
+    pub fn content_types(&self) -> Option<&ContentTypes> {
+        self.module.content_types.as_ref()
+    }
+
+    /// The number of buffers the host allocated or received from the plugin and did not free yet.
+    ///
+    /// Only WASMlet plugins have shared buffers.
+    pub fn outstanding_buffers(&self) -> Option<usize> {
+        match &self.instance {
+            Some(PluginAbi::Wasmlet(plugin)) => Some(plugin.outstanding_buffers.len()),
+            Some(PluginAbi::Extism(_)) => None,
+            None => matches!(self.module.abi, CompiledAbi::Wasmlet { .. }).then_some(0),
+        }
+    }
+}
+
+impl Drop for Plugin {
+    fn drop(&mut self) {
+        if let Err(error) = self.shutdown() {
+            log::warn!("Plugin `{}` failed to shut down: {error}", self.info.name);
+        }
+    }
+}
+
+/// A function of the guest that takes a shared buffer and returns a result buffer.
+type BufferFunction = fn(&mut dyn GuestInstance, u32) -> Result<u32, ExecutionError>;
+
+/// The ABI logic of a WASMlet plugin. It runs the same way in every runtime.
+pub struct WasmletPlugin {
+    guest: Box<dyn GuestInstance>,
+    /// Offsets of the shared buffers that the host is responsible for freeing.
+    outstanding_buffers: HashSet<u32>,
+    max_output: u64,
+    lossy_utf8: bool,
+    /// Taken right after instantiation if the plugin is reset between calls.
+    snapshot: Option<Snapshot>,
+}
+
+impl WasmletPlugin {
+    /// Initialize and configure a new instance. The snapshot for [`PluginConfig::reset`] is taken afterwards, so every call starts from the configured state.
+    pub fn new(
+        guest: Box<dyn GuestInstance>,
+        config: &PluginConfig,
+        options: &[(String, String)],
+    ) -> Result<Self, PluginError> {
+        let mut plugin = WasmletPlugin {
+            guest,
+            outstanding_buffers: HashSet::new(),
+            max_output: config.max_output,
+            lossy_utf8: config.lossy_utf8,
+            snapshot: None,
+        };
+        plugin.guest.init()?;
+        plugin.configure(options)?;
+        if config.reset {
+            plugin.snapshot = Some(Snapshot::capture(&mut *plugin.guest));
+        }
+        Ok(plugin)
+    }
+
+    /// Pass the options to `configure`. Plugins without it only accept an empty list.
+    fn configure(&mut self, options: &[(String, String)]) -> Result<(), PluginError> {
+        if !self.guest.exports_configure() {
+            return match options {
+                [] => Ok(()),
+                _ => Err(LifecycleError::NotConfigurable.into()),
+            };
+        }
+        let options: String = options
+            .iter()
+            .map(|(key, value)| format!("{key}={value}\n"))
+            .collect();
+        let (success, message) =
+            self.call(|guest, buffer| guest.configure(buffer), options.as_bytes())?;
+        if !success {
+            let message = result_buffer::decode_output(message, self.lossy_utf8)?;
+            return Err(LifecycleError::OptionsRejected(message).into());
+        }
+        Ok(())
+    }
+
+    /// Restore the snapshot. Buffers that were not freed are gone with the rest of the state.
+    fn reset(&mut self) -> bool {
+        let restored = self
+            .snapshot
+            .as_ref()
+            .is_some_and(|snapshot| snapshot.restore(&mut *self.guest));
+        if restored {
+            self.outstanding_buffers.clear();
+        }
+        restored
+    }
 
     /// Create a shared buffer in guest memory.
     ///
     /// You need to free it afterwards using `free_shared_buffer`.
-    fn create_shared_buffer(&mut self, data: &[u8]) -> Result<WasmPtr<u8>, PluginError> {
-        let address = self
-            .allocate_shared_buffer
-            .call(&mut self.store, data.len() as u32)
-            .map_err(PluginError::RuntimeErrorWhileAllocatingBuffer)?;
-        let view = self.memory.view(&self.store);
-        address
-            .slice(&view, data.len() as u32)
-            .and_then(|slice| slice.write_slice(data))
-            .map_err(PluginError::AllocatedBufferCausedMemoryError)?;
+    fn create_shared_buffer(&mut self, data: &[u8]) -> Result<u32, PluginError> {
+        let address = self.guest.allocate_shared_buffer(data.len() as u32)?;
+        self.outstanding_buffers.insert(address);
+        if let Err(error) = self.guest.write_memory(address as u64, data) {
+            self.free_shared_buffer_after_error(address);
+            return Err(ExecutionError::InvalidInputBuffer(error).into());
+        }
         Ok(address)
     }
 
     /// Free a shared buffer in guest memory.
-    fn free_shared_buffer(&mut self, address: WasmPtr<u8>) -> Result<(), PluginError> {
-        let result = self
-            .free_shared_buffer
-            .call(&mut self.store, address)
-            .map_err(PluginError::RuntimeErrorWhileFreeingBuffer)?;
+    fn free_shared_buffer(&mut self, address: u32) -> Result<(), PluginError> {
+        let result = self.guest.free_shared_buffer(address)?;
 
         if result == 0 {
-            return Err(PluginError::FailedToFreeSharedBuffer);
+            return Err(ExecutionError::FailedToFreeSharedBuffer.into());
         }
 
+        self.outstanding_buffers.remove(&address);
         Ok(())
     }
 
-    fn process(&mut self, input: WasmPtr<u8>) -> Result<String, PluginError> {
-        let output_ptr = self
-            .process
-            .call(&mut self.store, input)
-            .map_err(PluginError::RuntimeErrorWhileProcessingText)?;
-
-        let view = self.memory.view(&self.store);
-        let success_ptr = output_ptr;
-        let length_ptr: WasmPtr<u32, _> = output_ptr
-            .add_offset(1)
-            .map_err(PluginError::ProcessReturnedMalformedDatastructure)?
-            .cast();
-        let string_ptr = output_ptr
-            .add_offset(1 + 4)
-            .map_err(PluginError::ProcessReturnedMalformedDatastructure)?;
-
-        let success = success_ptr
-            .deref(&view)
-            .read()
-            .map_err(PluginError::ProcessReturnedMalformedDatastructure)?
-            != 0;
-
-        let length = length_ptr
-            .read(&view)
-            .map_err(PluginError::ProcessReturnedMalformedDatastructure)?;
-
-        let string_slice = string_ptr
-            .read_utf8_string(&view, length)
-            .map_err(PluginError::ProcessReturnedMalformedDatastructure)?;
+    /// Try to free a shared buffer while another error is already being returned.
+    ///
+    /// Errors are only logged, because the original error is more useful.
+    fn free_shared_buffer_after_error(&mut self, address: u32) {
+        if let Err(error) = self.free_shared_buffer(address) {
+            log::debug!("Failed to free a shared buffer after an error: {error}");
+        }
+    }
 
-        self.free_shared_buffer(output_ptr)?;
+    /// Read the result buffer that `process` or `configure` returned.
+    ///
+    /// `input` is the range of the input buffer. Returns the success flag and the content.
+    fn read_result(
+        &mut self,
+        pointer: u32,
+        input: Range<u64>,
+    ) -> Result<(bool, Vec<u8>), PluginError> {
+        let memory_size = self.guest.memory_size();
+        let out_of_bounds = |_| ExecutionError::ResultOutOfBounds {
+            pointer,
+            memory_size,
+        };
 
-        if !success {
-            return Err(PluginError::GuestError(string_slice));
-        }
-        Ok(string_slice)
+        result_buffer::check_header_bounds(pointer, memory_size)?;
+        let mut header = [0; HEADER_SIZE as usize];
+        self.guest
+            .read_memory(pointer as u64, &mut header)
+            .map_err(out_of_bounds)?;
+        let (success, text) =
+            result_buffer::parse_header(pointer, header, memory_size, input, self.max_output)?;
+
+        let mut bytes = vec![0; (text.end - text.start) as usize];
+        self.guest
+            .read_memory(text.start, &mut bytes)
+            .map_err(out_of_bounds)?;
+        Ok((success, bytes))
     }
 
-    /// Apply this plugin to a text.
-    pub fn apply(&mut self, input: &str) -> Result<String, PluginError> {
-        let input_ptr = self.create_shared_buffer(input.as_bytes())?;
+    /// Call the function with the input buffer and read the result buffer it returns.
+    fn call_with_buffer(
+        &mut self,
+        function: BufferFunction,
+        input: u32,
+        input_length: u32,
+    ) -> Result<(bool, Vec<u8>), PluginError> {
+        let output_ptr = function(&mut *self.guest, input)?;
 
-        let result = self.process(input_ptr)?;
+        let input_range = input as u64..input as u64 + input_length as u64;
+        let (success, output) = match self.read_result(output_ptr, input_range) {
+            Ok(result) => result,
+            Err(
+                error @ PluginError::Execution(
+                    ExecutionError::NullResult
+                    | ExecutionError::ResultOutOfBounds { .. }
+                    | ExecutionError::ResultOverlapsInput { .. },
+                ),
+            ) => {
+                // The pointer is not a buffer of its own, so there is nothing we could free
+                return Err(error);
+            }
+            Err(error) => {
+                self.outstanding_buffers.insert(output_ptr);
+                self.free_shared_buffer_after_error(output_ptr);
+                return Err(error);
+            }
+        };
+        self.outstanding_buffers.insert(output_ptr);
+        self.free_shared_buffer(output_ptr)?;
+        Ok((success, output))
+    }
+
+    /// Copy the input into a shared buffer, call the function with it and return the success flag and content of the result.
+    fn call(
+        &mut self,
+        function: BufferFunction,
+        input: &[u8],
+    ) -> Result<(bool, Vec<u8>), PluginError> {
+        let input_ptr = self.create_shared_buffer(input)?;
+
+        let result = match self.call_with_buffer(function, input_ptr, input.len() as u32) {
+            Ok(result) => result,
+            Err(error) => {
+                self.free_shared_buffer_after_error(input_ptr);
+                return Err(error);
+            }
+        };
 
         self.free_shared_buffer(input_ptr)?;
         Ok(result)
     }
+
+    /// Apply this plugin to the input and return its output. Only error messages need to be utf8.
+    pub fn apply(&mut self, input: &[u8]) -> Result<Vec<u8>, PluginError> {
+        let (success, output) = self.call(|guest, input| guest.process(input), input)?;
+        if !success {
+            let message = result_buffer::decode_output(output, self.lossy_utf8)?;
+            return Err(ExecutionError::GuestError(message).into());
+        }
+        Ok(output)
+    }
 }
@@ -0,0 +1,213 @@
+//! A pool of instances of one plugin for concurrent callers.
+//!
+//! A [`Plugin`] has a single instance, so only one caller can use it at a time. The pool keeps several instances of the same compiled module and hands out an idle one to every caller. With [`PluginConfig::reset`](crate::plugin::PluginConfig::reset) every call also starts from a freshly instantiated state, without paying for instantiation every time.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+use crate::error::PluginError;
+use crate::plugin::Plugin;
+
+pub struct PluginPool {
+    instances: Mutex<Instances>,
+}
+
+struct Instances {
+    /// Never instantiated. New instances are duplicated from it.
+    template: Plugin,
+    idle: Vec<Plugin>,
+}
+
+impl PluginPool {
+    /// Create a pool with `size` instances of the plugin. They are instantiated right away, so the first callers do not wait for it.
+    pub fn new(plugin: &Plugin, size: usize) -> Result<Self, PluginError> {
+        let template = plugin.duplicate();
+        let idle = (0..size)
+            .map(|_| {
+                let mut instance = template.duplicate();
+                instance.instantiate().map(|_| instance)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(PluginPool {
+            instances: Mutex::new(Instances { template, idle }),
+        })
+    }
+
+    /// Take an idle instance. If all of them are in use, a new one is created. It joins the pool when it is dropped.
+    pub fn get(&self) -> Result<PooledPlugin<'_>, PluginError> {
+        let mut instances = self.lock();
+        let plugin = match instances.idle.pop() {
+            Some(plugin) => plugin,
+            None => {
+                let mut plugin = instances.template.duplicate();
+                // Instantiating takes a while, so other callers can use the pool meanwhile
+                drop(instances);
+                plugin.instantiate()?;
+                plugin
+            }
+        };
+        Ok(PooledPlugin {
+            pool: self,
+            plugin: Some(plugin),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Instances> {
+        self.instances
+            .lock()
+            .expect("No thread panics while holding the lock")
+    }
+}
+
+/// An instance taken from a [`PluginPool`].
+pub struct PooledPlugin<'a> {
+    pool: &'a PluginPool,
+    /// Only taken when it is returned to the pool.
+    plugin: Option<Plugin>,
+}
+
+impl Deref for PooledPlugin<'_> {
+    type Target = Plugin;
+
+    fn deref(&self) -> &Plugin {
+        self.plugin.as_ref().expect("The plugin is in use")
+    }
+}
+
+impl DerefMut for PooledPlugin<'_> {
+    fn deref_mut(&mut self) -> &mut Plugin {
+        self.plugin.as_mut().expect("The plugin is in use")
+    }
+}
+
+impl Drop for PooledPlugin<'_> {
+    fn drop(&mut self) {
+        if let Some(plugin) = self.plugin.take() {
+            self.pool.lock().idle.push(plugin);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::malicious::{Template, VALID, result_at_2048};
+    use crate::plugin::PluginConfig;
+    use crate::runtime::Runtime;
+    use crate::terminal::TerminalInfo;
+
+    /// Counts its calls in an exported global and in memory and returns both counts as digits. `{before}` runs at the start of every call.
+    const COUNTER: Template = Template {
+        process: concat!(
+            "{before} ",
+            "(global.set $calls (i32.add (global.get $calls) (i32.const 1))) ",
+            "(i32.store8 (i32.const 1024) (i32.add (i32.load8_u (i32.const 1024)) (i32.const 1))) ",
+            "(i32.store8 (i32.const 2053) (i32.add (global.get $calls) (i32.const 48))) ",
+            "(i32.store8 (i32.const 2054) (i32.add (i32.load8_u (i32.const 1024)) (i32.const 48))) ",
+            result_at_2048!(1, 2)
+        ),
+        extra: r#"(global $calls (export "calls") (mut i32) (i32.const 0))"#,
+        ..VALID
+    };
+
+    fn counter(before: &str, runtime: Runtime, reset: bool) -> Plugin {
+        let config = PluginConfig {
+            runtime,
+            reset,
+            ..PluginConfig::new(TerminalInfo::STANDARD)
+        };
+        let wat = COUNTER.wat().replace("{before}", before);
+        Plugin::from_wasm("counter", wat.as_bytes(), &config).unwrap()
+    }
+
+    fn outputs(plugin: &mut Plugin, calls: usize) -> Vec<String> {
+        (0..calls).map(|_| plugin.apply("Hello").unwrap()).collect()
+    }
+
+    #[test]
+    fn state_carries_over_without_reset() {
+        for &runtime in Runtime::available() {
+            let mut plugin = counter("", runtime, false);
+            assert_eq!(outputs(&mut plugin, 3), ["11", "22", "33"], "{runtime}");
+        }
+    }
+
+    #[test]
+    fn reset_restores_memory_and_globals() {
+        for &runtime in Runtime::available() {
+            let mut plugin = counter("", runtime, true);
+            assert_eq!(outputs(&mut plugin, 3), ["11", "11", "11"], "{runtime}");
+            assert_eq!(plugin.memory_pages(), Some(1), "{runtime}");
+        }
+    }
+
+    #[test]
+    fn reset_replaces_instances_that_grew_their_memory() {
+        for &runtime in Runtime::available() {
+            let mut plugin = counter("(drop (memory.grow (i32.const 1)))", runtime, true);
+            assert_eq!(outputs(&mut plugin, 3), ["11", "11", "11"], "{runtime}");
+            assert_eq!(plugin.memory_pages(), Some(2), "{runtime}");
+        }
+    }
+
+    #[test]
+    fn reset_restores_globals_that_are_not_exported() {
+        for &runtime in Runtime::available() {
+            // The allocator keeps its position in `$next`, which is not exported, so every call only gets its input at 4096 if it is restored
+            let mut plugin = counter(
+                "(if (i32.ne (local.get $input) (i32.const 4096)) (then unreachable))",
+                runtime,
+                true,
+            );
+            assert_eq!(outputs(&mut plugin, 3), ["11", "11", "11"], "{runtime}");
+        }
+    }
+
+    #[test]
+    fn reset_replaces_instances_that_trapped() {
+        for &runtime in Runtime::available() {
+            // Inputs starting with `!` overwrite the count in memory and trap
+            let mut plugin = counter(
+                "(if (i32.eq (i32.load8_u (local.get $input)) (i32.const 33)) (then
+                    (i32.store8 (i32.const 1024) (i32.const 5))
+                    unreachable))",
+                runtime,
+                true,
+            );
+            assert_eq!(plugin.apply("Hello").unwrap(), "11", "{runtime}");
+            assert!(plugin.apply("!").is_err(), "{runtime}");
+            assert_eq!(plugin.apply("Hello").unwrap(), "11", "{runtime}");
+        }
+    }
+
+    #[test]
+    fn pool_hands_out_separate_instances() {
+        for &runtime in Runtime::available() {
+            let pool = PluginPool::new(&counter("", runtime, false), 1).unwrap();
+            let mut first = pool.get().unwrap();
+            let mut second = pool.get().unwrap();
+            assert_eq!(first.apply("Hello").unwrap(), "11", "{runtime}");
+            assert_eq!(second.apply("Hello").unwrap(), "11", "{runtime}");
+            drop((first, second));
+            assert_eq!(pool.lock().idle.len(), 2, "{runtime}");
+        }
+    }
+
+    #[test]
+    fn pool_serves_concurrent_callers() {
+        for &runtime in Runtime::available() {
+            let pool = PluginPool::new(&counter("", runtime, true), 2).unwrap();
+            std::thread::scope(|scope| {
+                for _ in 0..4 {
+                    scope.spawn(|| {
+                        for _ in 0..10 {
+                            let mut plugin = pool.get().unwrap();
+                            assert_eq!(plugin.apply("Hello").unwrap(), "11", "{runtime}");
+                        }
+                    });
+                }
+            });
+            assert!((2..=4).contains(&pool.lock().idle.len()), "{runtime}");
+        }
+    }
+}
@@ -0,0 +1,611 @@
+//! `wasmlet preinit`: Run the `init` export of a plugin ahead of time and store the result in a new module.
+//!
+//! This works like [wizer](https://github.com/bytecodealliance/wizer): The plugin is instantiated once, `init` runs and the memory and mutable globals are written into the module as data segments and initial values. Plugins that do expensive setup in `init`, like parsing a font, start in that state without running it again.
+
+use std::borrow::Cow;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use wasm_encoder::{
+    ConstExpr, CustomSection, DataCountSection, DataSection, ExportKind, ExportSection,
+    GlobalSection, GlobalType, MemorySection, MemoryType, Module, RawSection,
+};
+use wasmparser::{
+    CompositeInnerType, DataKind, DataSectionReader, ExternalKind, Parser, Payload, TypeRef,
+};
+
+use crate::error::{PluginError, PreinitError};
+use crate::plugin::{PluginConfig, PluginSource, load_plugin_source, plugin_name};
+use crate::runtime::{Engine, GlobalValue, INIT, Snapshot};
+use crate::terminal::{ColorDepth, TerminalInfo};
+use crate::transforms;
+
+#[derive(clap::Args, Debug)]
+pub struct PreinitArgs {
+    /// The plugin to pre-initialize. It is resolved like the plugins of a pipeline
+    plugin: String,
+
+    /// Where to write the pre-initialized plugin. Defaults to the name of the plugin with the extension `.preinit.wasm`
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+/// What the plugin sees of the terminal while it is pre-initialized. The real terminal is only known when the plugin runs, so `init` must not depend on it.
+const UNKNOWN_TERMINAL: TerminalInfo = TerminalInfo {
+    width: None,
+    height: None,
+    color_depth: ColorDepth::None,
+    is_tty: false,
+};
+
+/// [`export_mutable_globals`] exports every mutable global under this prefix and its index.
+const GLOBAL_EXPORT_PREFIX: &str = "__wasmlet_global_";
+
+/// An empty custom section that marks modules whose mutable globals are all exported. Precompiled plugins can not be rewritten, so this is how the host tells whether they can be reset.
+pub const EXPORTED_GLOBALS_SECTION: &str = "wasmlet-exported-globals";
+
+/// Zero bytes between two runs of data that are still put into the same data segment. Every segment has an overhead of a few bytes.
+const MAX_GAP: usize = 16;
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+/// What the rewriting needs to know about a module.
+#[derive(Default)]
+struct ModuleInfo {
+    /// Globals defined by the module are numbered after the imported ones.
+    imported_globals: u32,
+    /// Indices of the mutable globals the module defines.
+    mutable_globals: Vec<u32>,
+    memories: usize,
+    /// Whether `init` is exported as a function without parameters and results.
+    has_init: bool,
+    /// Whether the module has an [`EXPORTED_GLOBALS_SECTION`].
+    exports_globals: bool,
+}
+
+fn inspect(wasm: &[u8]) -> Result<ModuleInfo, PreinitError> {
+    let mut info = ModuleInfo::default();
+    // Whether each type is a function without parameters and results
+    let mut types = Vec::new();
+    // The type of every function
+    let mut functions = Vec::new();
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload? {
+            Payload::TypeSection(section) => {
+                for group in section {
+                    for ty in group?.into_types() {
+                        types.push(matches!(
+                            &ty.composite_type.inner,
+                            CompositeInnerType::Func(function)
+                                if function.params().is_empty() && function.results().is_empty()
+                        ));
+                    }
+                }
+            }
+            Payload::ImportSection(section) => {
+                for import in section {
+                    match import?.ty {
+                        TypeRef::Func(ty) => functions.push(ty),
+                        TypeRef::Global(_) => info.imported_globals += 1,
+                        TypeRef::Memory(_) => info.memories += 1,
+                        _ => {}
+                    }
+                }
+            }
+            Payload::FunctionSection(section) => {
+                for ty in section {
+                    functions.push(ty?);
+                }
+            }
+            Payload::MemorySection(section) => info.memories += section.count() as usize,
+            Payload::GlobalSection(section) => {
+                for (index, global) in section.into_iter().enumerate() {
+                    if global?.ty.mutable {
+                        info.mutable_globals
+                            .push(info.imported_globals + index as u32);
+                    }
+                }
+            }
+            Payload::ExportSection(section) => {
+                for export in section {
+                    let export = export?;
+                    if export.name == INIT && export.kind == ExternalKind::Func {
+                        info.has_init = functions
+                            .get(export.index as usize)
+                            .and_then(|&ty| types.get(ty as usize))
+                            .is_some_and(|&no_arguments| no_arguments);
+                    }
+                }
+            }
+            Payload::CustomSection(section) if section.name() == EXPORTED_GLOBALS_SECTION => {
+                info.exports_globals = true;
+            }
+            _ => {}
+        }
+    }
+    Ok(info)
+}
+
+/// Copy a section without changes.
+fn copy_section(module: &mut Module, wasm: &[u8], payload: &Payload) {
+    if let Some((id, range)) = payload.as_section() {
+        module.section(&RawSection {
+            id,
+            data: &wasm[range],
+        });
+    }
+}
+
+/// Export all mutable globals and mark the module with [`EXPORTED_GLOBALS_SECTION`]. Modules that are marked already are copied as they are.
+fn export_globals(wasm: &[u8], info: &ModuleInfo) -> Result<Vec<u8>, PreinitError> {
+    if info.exports_globals {
+        return Ok(wasm.to_vec());
+    }
+    let mut module = Module::new();
+    for payload in Parser::new(0).parse_all(wasm) {
+        let payload = payload?;
+        match &payload {
+            Payload::ExportSection(exports) => {
+                let mut section = ExportSection::new();
+                for export in exports.clone() {
+                    let export = export?;
+                    section.export(export.name, export.kind.into(), export.index);
+                }
+                for &index in &info.mutable_globals {
+                    section.export(
+                        &format!("{GLOBAL_EXPORT_PREFIX}{index}"),
+                        ExportKind::Global,
+                        index,
+                    );
+                }
+                module.section(&section);
+            }
+            _ => copy_section(&mut module, wasm, &payload),
+        }
+    }
+    module.section(&CustomSection {
+        name: Cow::Borrowed(EXPORTED_GLOBALS_SECTION),
+        data: Cow::Borrowed(&[]),
+    });
+    Ok(module.finish())
+}
+
+/// Export all mutable globals, so the host can read them after `init`. Globals that are not exported are invisible to the host.
+fn instrument(wasm: &[u8]) -> Result<Vec<u8>, PreinitError> {
+    let info = inspect(wasm)?;
+    if !info.has_init {
+        return Err(PreinitError::MissingInit);
+    }
+    if info.memories != 1 {
+        return Err(PreinitError::Memories(info.memories));
+    }
+    export_globals(wasm, &info)
+}
+
+/// Export all mutable globals of a module in the binary or text format, so [`Snapshot`] can restore them for [`PluginConfig::reset`]. Otherwise the globals a plugin does not export, like the position of its allocator, would carry over from one call to the next.
+pub fn export_mutable_globals(wasm_bytes: &[u8]) -> Result<Vec<u8>, PluginError> {
+    let wasm = wasmer::wat2wasm(wasm_bytes).map_err(|error| PluginError::Compile(error.into()))?;
+    inspect(&wasm)
+        .and_then(|info| export_globals(&wasm, &info))
+        .map_err(|error| PluginError::Compile(error.into()))
+}
+
+fn const_expr(value: GlobalValue) -> ConstExpr {
+    match value {
+        GlobalValue::I32(value) => ConstExpr::i32_const(value),
+        GlobalValue::I64(value) => ConstExpr::i64_const(value),
+        GlobalValue::F32(bits) => ConstExpr::f32_const(f32::from_bits(bits)),
+        GlobalValue::F64(bits) => ConstExpr::f64_const(f64::from_bits(bits)),
+        GlobalValue::V128(value) => ConstExpr::v128_const(value as i128),
+    }
+}
+
+/// Split the memory into runs of bytes that are not zero. Memory starts out zeroed, so only these runs need data segments.
+fn segments(memory: &[u8]) -> Vec<(usize, &[u8])> {
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    let mut offset = 0;
+    while offset < memory.len() {
+        if memory[offset] == 0 {
+            offset += 1;
+            continue;
+        }
+        let start = offset;
+        while offset < memory.len() && memory[offset] != 0 {
+            offset += 1;
+        }
+        match runs.last_mut() {
+            Some((_, end)) if start - *end <= MAX_GAP => *end = offset,
+            _ => runs.push((start, offset)),
+        }
+    }
+    runs.into_iter()
+        .map(|(start, end)| (start, &memory[start..end]))
+        .collect()
+}
+
+/// Replace the active data segments with the snapshot of the memory.
+///
+/// The segments keep their indices, because `memory.init` and `data.drop` refer to them. Active segments become empty passive segments and the snapshot is appended.
+fn data_section(
+    original: Option<DataSectionReader>,
+    memory: &[u8],
+) -> Result<DataSection, PreinitError> {
+    let mut section = DataSection::new();
+    for data in original.into_iter().flatten() {
+        let data = data?;
+        match data.kind {
+            DataKind::Passive => section.passive(data.data.iter().copied()),
+            DataKind::Active { .. } => section.passive([]),
+        };
+    }
+    for (offset, bytes) in segments(memory) {
+        section.active(
+            0,
+            &ConstExpr::i32_const(offset as i32),
+            bytes.iter().copied(),
+        );
+    }
+    Ok(section)
+}
+
+/// Build a module that starts out in the state of an instance after `init`.
+///
+/// `memory` and `globals` are read from an instance of the [instrumented](instrument) module. The globals are identified by their index. `init` is no longer exported and the start function is removed, because both already ran.
+fn initialized_module(
+    wasm: &[u8],
+    memory: &[u8],
+    globals: &[(u32, GlobalValue)],
+) -> Result<Vec<u8>, PreinitError> {
+    let info = inspect(wasm)?;
+    let segment_count = segments(memory).len() as u32;
+    let mut module = Module::new();
+    let mut has_data_section = false;
+    for payload in Parser::new(0).parse_all(wasm) {
+        let payload = payload?;
+        match &payload {
+            Payload::MemorySection(memories) => {
+                let mut section = MemorySection::new();
+                for memory_type in memories.clone() {
+                    section.memory(MemoryType {
+                        minimum: memory.len() as u64 / WASM_PAGE_SIZE,
+                        ..memory_type?.into()
+                    });
+                }
+                module.section(&section);
+            }
+            Payload::GlobalSection(globals_reader) => {
+                let mut section = GlobalSection::new();
+                for (index, global) in globals_reader.clone().into_iter().enumerate() {
+                    let global = global?;
+                    let index = info.imported_globals + index as u32;
+                    let init_expr = match globals.iter().find(|(global, _)| *global == index) {
+                        Some(&(_, value)) => const_expr(value),
+                        None => ConstExpr::try_from(global.init_expr)?,
+                    };
+                    section.global(GlobalType::try_from(global.ty)?, &init_expr);
+                }
+                module.section(&section);
+            }
+            Payload::ExportSection(exports) => {
+                let mut section = ExportSection::new();
+                for export in exports.clone() {
+                    let export = export?;
+                    if export.name != INIT {
+                        section.export(export.name, export.kind.into(), export.index);
+                    }
+                }
+                module.section(&section);
+            }
+            // The start function ran before `init`
+            Payload::StartSection { .. } => {}
+            Payload::DataCountSection { count, .. } => {
+                module.section(&DataCountSection {
+                    count: count + segment_count,
+                });
+            }
+            Payload::DataSection(data) => {
+                module.section(&data_section(Some(data.clone()), memory)?);
+                has_data_section = true;
+            }
+            _ => copy_section(&mut module, wasm, &payload),
+        }
+    }
+    if !has_data_section {
+        module.section(&data_section(None, memory)?);
+    }
+    Ok(module.finish())
+}
+
+/// Run `init` in an instance of the plugin and build a module that starts in the resulting state.
+fn preinitialize(name: &str, source: &PluginSource) -> Result<Vec<u8>, PluginError> {
+    if source.precompiled {
+        return Err(PreinitError::Precompiled.into());
+    }
+    let wasm = wasmer::wat2wasm(&source.bytes).map_err(|e| PluginError::Compile(e.into()))?;
+    let instrumented = instrument(&wasm)?;
+    let config = PluginConfig::new(UNKNOWN_TERMINAL);
+    let module = Engine::new(&config).compile(&instrumented, false, &config)?;
+    // `init` does not depend on the transform, so any of them will do
+    let process = module
+        .function_exports()
+        .into_iter()
+        .find(|export| transforms::is_transform(export))
+        .unwrap_or_else(|| transforms::PRIMARY.to_string());
+    let mut guest = module.instantiate(name, &process, &config)?;
+    guest.init()?;
+    let snapshot = Snapshot::capture(&mut *guest);
+    let globals: Vec<_> = snapshot
+        .globals
+        .iter()
+        .filter_map(|(export, value)| {
+            let index = export.strip_prefix(GLOBAL_EXPORT_PREFIX)?.parse().ok()?;
+            Some((index, *value))
+        })
+        .collect();
+    Ok(initialized_module(&wasm, &snapshot.memory, &globals)?)
+}
+
+pub fn preinit(args: PreinitArgs) -> ExitCode {
+    let name = plugin_name(&args.plugin);
+    let module = match load_plugin_source(&args.plugin).and_then(|source| {
+        log::debug!("Pre-initializing {}", source.location);
+        preinitialize(name, &source)
+    }) {
+        Ok(module) => module,
+        Err(error) => {
+            let exit_code = error.category().exit_code();
+            eprintln!("{:?}", miette::Report::new(error));
+            return exit_code.into();
+        }
+    };
+    let output = args
+        .output
+        .unwrap_or_else(|| PathBuf::from(format!("{name}.preinit.wasm")));
+    if let Err(error) = std::fs::write(&output, module) {
+        eprintln!("Failed to write {}: {error}", output.display());
+        return ExitCode::FAILURE;
+    }
+    println!("Pre-initialized {} to {}", args.plugin, output.display());
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::malicious::{Template, VALID, result_at_2048};
+    use crate::plugin::Plugin;
+    use crate::runtime::Runtime;
+
+    fn wasm(wat: &str) -> Vec<u8> {
+        wasmer::wat2wasm(wat.as_bytes()).unwrap().to_vec()
+    }
+
+    const MODULE: Template = Template {
+        imports: r#"(import "wasmlet" "terminal_width" (func (result i32)))"#,
+        process: "(data.drop $passive) (i32.const 0)",
+        extra: r#"
+            (global $limit i32 (i32.const 8))
+            (global $ratio (export "ratio") (mut f64) (f64.const 0.5))
+            (data (i32.const 16) "Hello")
+            (data $passive "passive")
+            (func $start)
+            (start $start)
+            (func (export "init")
+                (global.set $next (i32.const 70000))
+                (drop (memory.grow (i32.const 1))))"#,
+        ..VALID
+    };
+
+    fn exports(wasm: &[u8]) -> Vec<String> {
+        Parser::new(0)
+            .parse_all(wasm)
+            .filter_map(|payload| match payload.unwrap() {
+                Payload::ExportSection(section) => Some(
+                    section
+                        .into_iter()
+                        .map(|export| export.unwrap().name.to_string())
+                        .collect::<Vec<_>>(),
+                ),
+                _ => None,
+            })
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn exports_the_mutable_globals() {
+        let instrumented = instrument(&wasm(&MODULE.wat())).unwrap();
+        wasmparser::validate(&instrumented).unwrap();
+        assert_eq!(
+            exports(&instrumented),
+            [
+                "memory",
+                "allocate_shared_buffer",
+                "free_shared_buffer",
+                "process",
+                "ratio",
+                "init",
+                "__wasmlet_global_0",
+                "__wasmlet_global_2"
+            ]
+        );
+    }
+
+    #[test]
+    fn marks_modules_with_exported_globals() {
+        let exported = export_mutable_globals(MODULE.wat().as_bytes()).unwrap();
+        wasmparser::validate(&exported).unwrap();
+        assert!(inspect(&exported).unwrap().exports_globals);
+        assert!(exports(&exported).contains(&"__wasmlet_global_0".to_string()));
+        // Marked modules are not rewritten again
+        assert_eq!(export_mutable_globals(&exported).unwrap(), exported);
+    }
+
+    #[test]
+    fn needs_an_init_function() {
+        let without_init = wasm(r#"(module (memory 1) (func (export "process")))"#);
+        assert!(matches!(
+            instrument(&without_init),
+            Err(PreinitError::MissingInit)
+        ));
+        let with_arguments = wasm(r#"(module (memory 1) (func (export "init") (param i32)))"#);
+        assert!(matches!(
+            instrument(&with_arguments),
+            Err(PreinitError::MissingInit)
+        ));
+        let without_memory = wasm(r#"(module (func (export "init")))"#);
+        assert!(matches!(
+            instrument(&without_memory),
+            Err(PreinitError::Memories(0))
+        ));
+    }
+
+    #[test]
+    fn starts_in_the_state_after_init() {
+        let mut memory = vec![0; 2 * WASM_PAGE_SIZE as usize];
+        memory[16..21].copy_from_slice(b"Hello");
+        memory[70000..70005].copy_from_slice(b"World");
+        let module = initialized_module(
+            &wasm(&MODULE.wat()),
+            &memory,
+            &[
+                (0, GlobalValue::I32(70000)),
+                (2, GlobalValue::F64(2.0f64.to_bits())),
+            ],
+        )
+        .unwrap();
+        wasmparser::validate(&module).unwrap();
+        assert_eq!(
+            exports(&module),
+            [
+                "memory",
+                "allocate_shared_buffer",
+                "free_shared_buffer",
+                "process",
+                "ratio"
+            ]
+        );
+
+        let mut data = Vec::new();
+        for payload in Parser::new(0).parse_all(&module) {
+            match payload.unwrap() {
+                Payload::MemorySection(section) => {
+                    let memory = section.into_iter().next().unwrap().unwrap();
+                    assert_eq!(memory.initial, 2);
+                }
+                Payload::GlobalSection(section) => {
+                    let globals: Vec<_> = section
+                        .into_iter()
+                        .map(|global| {
+                            let global = global.unwrap();
+                            let mut operators = global.init_expr.get_operators_reader();
+                            format!("{:?}", operators.read().unwrap())
+                        })
+                        .collect();
+                    assert_eq!(
+                        globals,
+                        [
+                            "I32Const { value: 70000 }",
+                            "I32Const { value: 8 }",
+                            "F64Const { value: Ieee64(4611686018427387904) }"
+                        ]
+                    );
+                }
+                Payload::StartSection { .. } => panic!("The start function ran already"),
+                Payload::DataSection(section) => {
+                    for segment in section {
+                        let segment = segment.unwrap();
+                        let offset = match segment.kind {
+                            DataKind::Passive => None,
+                            DataKind::Active { offset_expr, .. } => {
+                                match offset_expr.get_operators_reader().read().unwrap() {
+                                    wasmparser::Operator::I32Const { value } => Some(value),
+                                    operator => panic!("Unexpected offset {operator:?}"),
+                                }
+                            }
+                        };
+                        data.push((offset, segment.data.to_vec()));
+                    }
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(
+            data,
+            [
+                (None, Vec::new()),
+                (None, b"passive".to_vec()),
+                (Some(16), b"Hello".to_vec()),
+                (Some(70000), b"World".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn merges_runs_with_short_gaps() {
+        let mut memory = vec![0; 100];
+        memory[10] = 1;
+        memory[12] = 2;
+        memory[50] = 3;
+        assert_eq!(segments(&memory), [(10, &[1, 0, 2][..]), (50, &[3][..])]);
+        assert!(segments(&[0; 10]).is_empty());
+    }
+
+    /// Adds 7 to the 10 in memory in `init` and returns the sum as digits. `process` traps if `init` did not run, and runs into 24 if it ran twice.
+    const PLUGIN: Template = Template {
+        process: concat!(
+            "(if (i32.eqz (global.get $ready)) (then unreachable)) ",
+            "(i32.store8 (i32.const 2053) (i32.add (i32.div_u (i32.load8_u (i32.const 1024)) (i32.const 10)) (i32.const 48))) ",
+            "(i32.store8 (i32.const 2054) (i32.add (i32.rem_u (i32.load8_u (i32.const 1024)) (i32.const 10)) (i32.const 48))) ",
+            result_at_2048!(1, 2)
+        ),
+        extra: r#"
+            (global $ready (mut i32) (i32.const 0))
+            (data (i32.const 1024) "\0a")
+            (func (export "init")
+                (global.set $ready (i32.const 1))
+                (i32.store8 (i32.const 1024) (i32.add (i32.load8_u (i32.const 1024)) (i32.const 7))))"#,
+        ..VALID
+    };
+
+    fn apply(wasm: &[u8], runtime: Runtime) -> String {
+        let config = PluginConfig {
+            runtime,
+            ..PluginConfig::new(TerminalInfo::STANDARD)
+        };
+        let mut plugin = Plugin::from_wasm("plugin", wasm, &config).unwrap();
+        plugin.apply("Hello").unwrap()
+    }
+
+    #[test]
+    fn the_host_calls_init() {
+        for &runtime in Runtime::available() {
+            assert_eq!(apply(&wasm(&PLUGIN.wat()), runtime), "17", "{runtime}");
+        }
+    }
+
+    #[test]
+    fn preinitialized_plugins_do_not_run_init_again() {
+        let source = PluginSource {
+            location: "plugin.wat".to_string(),
+            bytes: PLUGIN.wat().into_bytes(),
+            precompiled: false,
+        };
+        let module = preinitialize("plugin", &source).unwrap();
+        assert!(!exports(&module).contains(&INIT.to_string()));
+        for &runtime in Runtime::available() {
+            assert_eq!(apply(&module, runtime), "17", "{runtime}");
+        }
+    }
+
+    #[test]
+    fn rejects_precompiled_plugins() {
+        let source = PluginSource {
+            location: "plugin.wasmu".to_string(),
+            bytes: Vec::new(),
+            precompiled: true,
+        };
+        let error = preinitialize("plugin", &source).unwrap_err();
+        assert_eq!(error.kind(), "Precompiled");
+    }
+}
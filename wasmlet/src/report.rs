@@ -0,0 +1,90 @@
+//! Machine readable output for `--format json`.
+
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::error::{ErrorCategory, StageError};
+use crate::leaks::LeakReport;
+use crate::plugin::{PluginInfo, plugin_name};
+use crate::stats::StageStats;
+
+/// Everything we know about a run of the pipeline.
+#[derive(Serialize, Default)]
+pub struct Report {
+    pub success: bool,
+    /// The output of the last stage. Missing for binary output.
+    pub output: Option<String>,
+    /// The type of the output of the last stage.
+    pub content_type: Option<String>,
+    /// All stages that were loaded successfully.
+    pub stages: Vec<StageReport>,
+    pub error: Option<ErrorReport>,
+}
+
+#[derive(Serialize)]
+pub struct StageReport {
+    pub index: usize,
+    pub specifier: String,
+    pub plugin: String,
+    /// The url or path the plugin was loaded from.
+    pub location: String,
+    pub sha256: String,
+    /// Time spent resolving, compiling and instantiating the plugin.
+    pub load_time_ms: f64,
+    /// Time spent processing the text. Missing if the stage did not run.
+    pub apply_time_ms: Option<f64>,
+    /// Detailed profiling data. Only present with `--stats`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<StageStats>,
+    /// Memory growth over repeated calls. Only present with `--check-leaks`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub leaks: Option<LeakReport>,
+}
+
+impl StageReport {
+    pub fn new(index: usize, specifier: &str, info: &PluginInfo, load_time: Duration) -> Self {
+        StageReport {
+            index,
+            specifier: specifier.to_string(),
+            plugin: info.name.clone(),
+            location: info.location.clone(),
+            sha256: info.sha256.clone(),
+            load_time_ms: milliseconds(load_time),
+            apply_time_ms: None,
+            stats: None,
+            leaks: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ErrorReport {
+    /// The index of the stage that failed.
+    pub stage: usize,
+    pub plugin: String,
+    pub category: ErrorCategory,
+    /// The name of the error variant, e.g. `NotFound` or `GuestError`.
+    pub kind: &'static str,
+    pub message: String,
+    /// The message reported by the plugin itself.
+    pub guest_message: Option<String>,
+}
+
+impl ErrorReport {
+    pub fn new(stage_error: &StageError) -> Self {
+        let error = &stage_error.error;
+        ErrorReport {
+            stage: stage_error.stage,
+            plugin: plugin_name(&stage_error.specifier).to_string(),
+            category: error.category(),
+            kind: error.kind(),
+            message: error.to_string(),
+            guest_message: error.guest_message().map(str::to_string),
+        }
+    }
+}
+
+pub fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
@@ -0,0 +1,162 @@
+//! Validation of the result buffers that `process` returns.
+//!
+//! The guest controls the pointer and every byte of the buffer, so nothing in it is trusted: The header and the text have to be inside the guest memory, the text has to stay below the output limit and the buffer must not overlap the input buffer.
+
+use std::ops::Range;
+
+use crate::error::ExecutionError;
+
+/// Size of the success flag and the length in front of the text.
+pub const HEADER_SIZE: u64 = 1 + 4;
+
+/// Check that a result header at `pointer` fits into a memory of `memory_size` bytes.
+pub fn check_header_bounds(pointer: u32, memory_size: u64) -> Result<(), ExecutionError> {
+    if pointer == 0 {
+        return Err(ExecutionError::NullResult);
+    }
+    if pointer as u64 + HEADER_SIZE > memory_size {
+        return Err(ExecutionError::ResultOutOfBounds {
+            pointer,
+            memory_size,
+        });
+    }
+    Ok(())
+}
+
+/// Parse the success flag and the length of the text. The length must not exceed `max_output`.
+pub fn parse_flag_and_length(
+    header: [u8; HEADER_SIZE as usize],
+    max_output: u64,
+) -> Result<(bool, u32), ExecutionError> {
+    let success = match header[0] {
+        0 => false,
+        1 => true,
+        flag => return Err(ExecutionError::InvalidSuccessFlag(flag)),
+    };
+    let length = u32::from_le_bytes([header[1], header[2], header[3], header[4]]);
+    if length as u64 > max_output {
+        return Err(ExecutionError::OutputTooLarge {
+            length: length as u64,
+            limit: max_output,
+        });
+    }
+    Ok((success, length))
+}
+
+/// Parse the header of the result buffer at `pointer` and validate the text it describes.
+///
+/// `input` is the range of the input buffer, which is still allocated. Returns the success flag and the range of the text in guest memory.
+pub fn parse_header(
+    pointer: u32,
+    header: [u8; HEADER_SIZE as usize],
+    memory_size: u64,
+    input: Range<u64>,
+    max_output: u64,
+) -> Result<(bool, Range<u64>), ExecutionError> {
+    let (success, length) = parse_flag_and_length(header, max_output)?;
+    let start = pointer as u64 + HEADER_SIZE;
+    let text = start..start + length as u64;
+    if text.end > memory_size {
+        return Err(ExecutionError::ResultLengthOutOfBounds {
+            pointer,
+            length,
+            memory_size,
+        });
+    }
+    // An empty input buffer can still share its address with the result
+    if pointer as u64 == input.start || ((pointer as u64) < input.end && input.start < text.end) {
+        return Err(ExecutionError::ResultOverlapsInput {
+            pointer,
+            input: input.start as u32,
+        });
+    }
+    Ok((success, text))
+}
+
+/// Decode the output of a plugin. With `lossy` invalid utf8 is replaced instead of rejected.
+pub fn decode_output(bytes: Vec<u8>, lossy: bool) -> Result<String, ExecutionError> {
+    if lossy {
+        return Ok(match String::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(error) => String::from_utf8_lossy(error.as_bytes()).into_owned(),
+        });
+    }
+    String::from_utf8(bytes).map_err(ExecutionError::OutputIsNotUtf8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MEMORY_SIZE: u64 = 65536;
+    const NO_INPUT: Range<u64> = 50..50;
+
+    fn header(flag: u8, length: u32) -> [u8; 5] {
+        let [a, b, c, d] = length.to_le_bytes();
+        [flag, a, b, c, d]
+    }
+
+    #[test]
+    fn accepts_a_valid_result() {
+        assert_eq!(
+            parse_header(100, header(1, 10), MEMORY_SIZE, 1000..1010, 1024).unwrap(),
+            (true, 105..115)
+        );
+        assert_eq!(
+            parse_header(100, header(0, 0), MEMORY_SIZE, NO_INPUT, 1024).unwrap(),
+            (false, 105..105)
+        );
+    }
+
+    #[test]
+    fn rejects_headers_outside_of_memory() {
+        assert!(matches!(
+            check_header_bounds(0, MEMORY_SIZE),
+            Err(ExecutionError::NullResult)
+        ));
+        assert!(matches!(
+            check_header_bounds(MEMORY_SIZE as u32 - 4, MEMORY_SIZE),
+            Err(ExecutionError::ResultOutOfBounds { .. })
+        ));
+        assert!(check_header_bounds(MEMORY_SIZE as u32 - 5, MEMORY_SIZE).is_ok());
+    }
+
+    #[test]
+    fn rejects_lying_lengths() {
+        assert!(matches!(
+            parse_header(100, header(1, u32::MAX), MEMORY_SIZE, NO_INPUT, u64::MAX),
+            Err(ExecutionError::ResultLengthOutOfBounds { .. })
+        ));
+        assert!(matches!(
+            parse_header(100, header(1, 2048), MEMORY_SIZE, NO_INPUT, 1024),
+            Err(ExecutionError::OutputTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_flags_and_overlaps() {
+        assert!(matches!(
+            parse_header(100, header(2, 0), MEMORY_SIZE, NO_INPUT, 1024),
+            Err(ExecutionError::InvalidSuccessFlag(2))
+        ));
+        assert!(matches!(
+            parse_header(100, header(1, 10), MEMORY_SIZE, 110..120, 1024),
+            Err(ExecutionError::ResultOverlapsInput { .. })
+        ));
+        assert!(matches!(
+            parse_header(100, header(1, 10), MEMORY_SIZE, 90..101, 1024),
+            Err(ExecutionError::ResultOverlapsInput { .. })
+        ));
+        assert!(matches!(
+            parse_header(100, header(1, 10), MEMORY_SIZE, 100..100, 1024),
+            Err(ExecutionError::ResultOverlapsInput { .. })
+        ));
+    }
+
+    #[test]
+    fn decodes_invalid_utf8_lossily() {
+        let invalid = vec![b'H', 0xff, b'i'];
+        assert!(decode_output(invalid.clone(), false).is_err());
+        assert_eq!(decode_output(invalid, true).unwrap(), "H\u{fffd}i");
+    }
+}
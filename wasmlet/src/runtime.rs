@@ -0,0 +1,241 @@
+//! The wasm runtimes that can run plugins.
+//!
+//! A runtime compiles modules, instantiates them with the host functions and moves bytes in and out of the guest memory. Everything the WASMlet ABI requires on top of that, like tracking shared buffers and validating result buffers, happens in [`WasmletPlugin`](crate::plugin::WasmletPlugin), so all runtimes behave the same. wasmer is the default, wasmtime needs the `wasmtime` cargo feature.
+
+use std::fmt;
+
+use clap::ValueEnum;
+use clap::builder::PossibleValue;
+use thiserror::Error;
+
+use crate::error::{BoxedError, ExecutionError, Operation, PluginError};
+use crate::plugin::PluginConfig;
+use crate::wasmer_runtime;
+#[cfg(feature = "wasmtime")]
+use crate::wasmtime_runtime;
+
+/// The runtimes this build of WASMlet can use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Runtime {
+    #[default]
+    Wasmer,
+    #[cfg(feature = "wasmtime")]
+    Wasmtime,
+}
+
+impl Runtime {
+    const AVAILABLE: &'static [Runtime] = &[
+        Runtime::Wasmer,
+        #[cfg(feature = "wasmtime")]
+        Runtime::Wasmtime,
+    ];
+
+    /// All runtimes this build includes. Tests run against each of them.
+    pub fn available() -> &'static [Runtime] {
+        Runtime::AVAILABLE
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Runtime::Wasmer => "wasmer",
+            #[cfg(feature = "wasmtime")]
+            Runtime::Wasmtime => "wasmtime",
+        }
+    }
+}
+
+impl fmt::Display for Runtime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// Implemented by hand, so only the runtimes of this build are offered
+impl ValueEnum for Runtime {
+    fn value_variants<'a>() -> &'a [Self] {
+        Runtime::AVAILABLE
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(PossibleValue::new(self.name()))
+    }
+}
+
+/// A trap in the guest, as reported by any of the runtimes.
+#[derive(Error, Debug)]
+#[error("{message}")]
+pub struct Trap {
+    pub message: String,
+    /// The guest ran out of stack. That is a resource limit rather than a bug in the plugin.
+    pub stack_overflow: bool,
+    /// The frames of the guest, formatted with [`crate::host::format_frame`].
+    pub backtrace: String,
+}
+
+impl Trap {
+    /// Convert the trap into an error.
+    ///
+    /// If the plugin reported a panic before trapping, the panic message is used instead.
+    pub fn into_error(self, panic_message: Option<String>, operation: Operation) -> ExecutionError {
+        match panic_message {
+            Some(message) => ExecutionError::Panicked {
+                message,
+                backtrace: self.backtrace.clone(),
+                source: self,
+            },
+            None => ExecutionError::Trap {
+                operation,
+                source: self,
+            },
+        }
+    }
+}
+
+/// An engine of one of the runtimes. Modules compiled by the same engine share its settings and caches.
+#[derive(Clone)]
+pub enum Engine {
+    Wasmer(wasmer::Engine),
+    #[cfg(feature = "wasmtime")]
+    Wasmtime(wasmtime::Engine),
+}
+
+impl Engine {
+    /// Create an engine of the configured runtime.
+    pub fn new(config: &PluginConfig) -> Self {
+        match config.runtime {
+            Runtime::Wasmer => Engine::Wasmer(wasmer_runtime::create_engine(config)),
+            #[cfg(feature = "wasmtime")]
+            Runtime::Wasmtime => Engine::Wasmtime(wasmtime_runtime::create_engine(config)),
+        }
+    }
+
+    /// Compile a WASMlet plugin. `precompiled` plugins are deserialized instead, see [`PluginSource::precompiled`](crate::plugin::PluginSource::precompiled).
+    pub fn compile(
+        &self,
+        wasm_bytes: &[u8],
+        precompiled: bool,
+        config: &PluginConfig,
+    ) -> Result<Box<dyn GuestModule>, PluginError> {
+        Ok(match self {
+            Engine::Wasmer(engine) => Box::new(wasmer_runtime::WasmerModule::new(
+                engine,
+                wasm_bytes,
+                precompiled,
+                config,
+            )?),
+            #[cfg(feature = "wasmtime")]
+            Engine::Wasmtime(engine) => Box::new(wasmtime_runtime::WasmtimeModule::new(
+                engine,
+                wasm_bytes,
+                precompiled,
+                config,
+            )?),
+        })
+    }
+}
+
+/// The optional export that initializes a plugin. The host calls it once after instantiation, before the first call and before the [`Snapshot`] is taken. It takes no parameters and returns nothing.
+pub const INIT: &str = "init";
+/// The optional export that receives the options of a plugin. The host calls it after [`INIT`] with a shared buffer of `key=value` lines, even if there are no options. It returns a result buffer like `process`, a failure rejects the options.
+pub const CONFIGURE: &str = "configure";
+/// The optional export that the host calls before it drops an instance. It takes no parameters and returns nothing.
+pub const SHUTDOWN: &str = "shutdown";
+
+/// A compiled WASMlet plugin. It can be sent to another thread and instantiated from several threads.
+pub trait GuestModule: Send + Sync {
+    /// The names of the functions the module exports.
+    fn function_exports(&self) -> Vec<String>;
+
+    /// The contents of the custom sections with the given name, like the [`options`](crate::options) of the plugin.
+    fn custom_sections(&self, name: &str) -> Vec<Vec<u8>>;
+
+    /// Instantiate the module with the host functions. The name is used as the log target for messages from the plugin.
+    ///
+    /// `process` is the export that [`GuestInstance::process`] calls, see [`transforms`](crate::transforms).
+    fn instantiate(
+        &self,
+        name: &str,
+        process: &str,
+        config: &PluginConfig,
+    ) -> Result<Box<dyn GuestInstance>, PluginError>;
+}
+
+/// An instance of a WASMlet plugin.
+///
+/// The functions call the exports of the ABI as they are. Traps are converted with [`Trap::into_error`], everything else is up to the caller.
+pub trait GuestInstance: Send {
+    fn allocate_shared_buffer(&mut self, size: u32) -> Result<u32, ExecutionError>;
+    fn free_shared_buffer(&mut self, pointer: u32) -> Result<u32, ExecutionError>;
+    fn process(&mut self, input: u32) -> Result<u32, ExecutionError>;
+    /// Call the optional [`INIT`] export. Does nothing if the plugin does not export it.
+    fn init(&mut self) -> Result<(), ExecutionError>;
+    /// Whether the plugin exports [`CONFIGURE`].
+    fn exports_configure(&self) -> bool;
+    /// Call the [`CONFIGURE`] export. Only called if the plugin [exports it](GuestInstance::exports_configure).
+    fn configure(&mut self, options: u32) -> Result<u32, ExecutionError>;
+    /// Call the optional [`SHUTDOWN`] export. Does nothing if the plugin does not export it.
+    fn shutdown(&mut self) -> Result<(), ExecutionError>;
+
+    /// The size of the guest memory in bytes.
+    fn memory_size(&mut self) -> u64;
+    /// Fill the buffer with guest memory starting at `offset`.
+    fn read_memory(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), BoxedError>;
+    /// Copy the data into guest memory at `offset`.
+    fn write_memory(&mut self, offset: u64, data: &[u8]) -> Result<(), BoxedError>;
+
+    /// The mutable globals the plugin exports. Globals that the runtime adds itself, like the metering counters of wasmer, are left out.
+    fn mutable_globals(&mut self) -> Vec<(String, GlobalValue)>;
+    /// Set one of the [`mutable_globals`](GuestInstance::mutable_globals). The value needs to have the type of the global.
+    fn set_global(&mut self, name: &str, value: GlobalValue);
+
+    /// The fuel left in the instance. Only works with metering.
+    fn remaining_fuel(&mut self) -> u64;
+    fn set_remaining_fuel(&mut self, fuel: u64);
+}
+
+/// The value of a global. Floats are kept as bits, so they are restored exactly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GlobalValue {
+    I32(i32),
+    I64(i64),
+    F32(u32),
+    F64(u64),
+    V128(u128),
+}
+
+/// The state of a freshly instantiated plugin: its memory and the mutable globals it exports.
+///
+/// Globals the plugin does not export can not be read by the host, like the stack pointer of plugins written in Rust or the position of a bump allocator. With [`PluginConfig::reset`] plugins are compiled with all mutable globals exported, so the snapshot covers them.
+pub struct Snapshot {
+    pub memory: Vec<u8>,
+    pub globals: Vec<(String, GlobalValue)>,
+}
+
+impl Snapshot {
+    pub fn capture(guest: &mut dyn GuestInstance) -> Self {
+        let mut memory = vec![0; guest.memory_size() as usize];
+        guest
+            .read_memory(0, &mut memory)
+            .expect("The whole memory can be read");
+        Snapshot {
+            memory,
+            globals: guest.mutable_globals(),
+        }
+    }
+
+    /// Restore the memory and globals of the guest.
+    ///
+    /// Returns false without changing anything if the memory grew since the snapshot was taken. Memory can not shrink and allocators remember how far they grew it, so such an instance needs to be replaced.
+    pub fn restore(&self, guest: &mut dyn GuestInstance) -> bool {
+        if guest.memory_size() != self.memory.len() as u64 {
+            return false;
+        }
+        guest
+            .write_memory(0, &self.memory)
+            .expect("The memory has the size of the snapshot");
+        for (name, value) in &self.globals {
+            guest.set_global(name, *value);
+        }
+        true
+    }
+}
@@ -0,0 +1,92 @@
+//! Per-stage profiling for `--stats`.
+
+use std::time::Duration;
+
+use serde::{Serialize, Serializer};
+
+use crate::report::{StageReport, milliseconds};
+
+/// Where the time and resources of a stage went.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct StageStats {
+    /// Time spent finding, reading or downloading the plugin.
+    #[serde(
+        rename = "resolution_time_ms",
+        serialize_with = "serialize_milliseconds"
+    )]
+    pub resolution_time: Duration,
+    /// Size of the wasm module.
+    pub bytes_loaded: usize,
+    #[serde(rename = "compile_time_ms", serialize_with = "serialize_milliseconds")]
+    pub compile_time: Duration,
+    #[serde(
+        rename = "instantiation_time_ms",
+        serialize_with = "serialize_milliseconds"
+    )]
+    pub instantiation_time: Duration,
+    /// Time spent in the plugin while processing the text.
+    #[serde(rename = "process_time_ms", serialize_with = "serialize_milliseconds")]
+    pub process_time: Duration,
+    /// Number of wasm operators executed while processing the text. Missing if the stage did not run.
+    pub fuel_consumed: Option<u64>,
+    /// Size of the guest memory in 64KiB pages after processing. Memory never shrinks, so this is the peak.
+    pub peak_memory_pages: Option<u32>,
+    pub input_bytes: usize,
+    pub output_bytes: usize,
+}
+
+impl StageStats {
+    /// Time spent resolving, compiling and instantiating the plugin.
+    pub fn load_time(&self) -> Duration {
+        self.resolution_time + self.compile_time + self.instantiation_time
+    }
+}
+
+fn serialize_milliseconds<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(milliseconds(*duration))
+}
+
+/// Print a table with the stats of every stage to stderr.
+pub fn print_stats(stages: &[StageReport]) {
+    eprintln!(
+        "{:<5} {:<16} {:>10} {:>10} {:>10} {:>10} {:>10} {:>12} {:>7} {:>10} {:>10}",
+        "stage",
+        "plugin",
+        "resolve",
+        "size",
+        "compile",
+        "instance",
+        "process",
+        "fuel",
+        "pages",
+        "input",
+        "output"
+    );
+    for stage in stages {
+        let Some(stats) = &stage.stats else {
+            continue;
+        };
+        let optional = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+        eprintln!(
+            "{:<5} {:<16} {:>10} {:>10} {:>10} {:>10} {:>10} {:>12} {:>7} {:>10} {:>10}",
+            stage.index,
+            stage.plugin,
+            format_duration(stats.resolution_time),
+            stats.bytes_loaded,
+            format_duration(stats.compile_time),
+            format_duration(stats.instantiation_time),
+            format_duration(stats.process_time),
+            optional(stats.fuel_consumed.map(|fuel| fuel.to_string())),
+            optional(stats.peak_memory_pages.map(|pages| pages.to_string())),
+            stats.input_bytes,
+            stats.output_bytes,
+        );
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!("{:.2}ms", milliseconds(duration))
+}
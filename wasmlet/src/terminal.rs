@@ -0,0 +1,155 @@
+//! Detect the capabilities of the terminal the output is written to.
+
+use std::io::IsTerminal;
+
+/// How many colors the terminal can display.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ColorDepth {
+    /// No colors at all, escape codes should not be used.
+    None = 0,
+    /// The 16 basic ANSI colors.
+    Ansi16 = 1,
+    /// The 256 color palette.
+    Ansi256 = 2,
+    /// 24 bit colors.
+    TrueColor = 3,
+}
+
+/// When to use colors, set with `--color`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// Use colors if the output is a terminal and `NO_COLOR` is not set.
+    #[default]
+    Auto,
+    /// Always use colors, even when writing to a pipe or file.
+    Always,
+    /// Never use colors.
+    Never,
+}
+
+/// What we know about the terminal. This is passed to every plugin.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TerminalInfo {
+    /// Width in columns, if known.
+    pub width: Option<u16>,
+    /// Height in rows, if known.
+    pub height: Option<u16>,
+    pub color_depth: ColorDepth,
+    /// Whether stdout is connected to a terminal.
+    pub is_tty: bool,
+}
+
+impl TerminalInfo {
+    /// A common terminal that does not depend on the environment. Used where results need to be reproducible.
+    pub const STANDARD: TerminalInfo = TerminalInfo {
+        width: Some(80),
+        height: Some(24),
+        color_depth: ColorDepth::TrueColor,
+        is_tty: true,
+    };
+
+    /// Detect the capabilities of the terminal connected to stdout.
+    ///
+    /// If stdout is not a terminal, the size is taken from `COLUMNS` and `LINES` if they are set.
+    /// With [`ColorChoice::Always`] the color depth is guessed from `COLORTERM` and `TERM` even if the output is not a terminal, falling back to truecolor.
+    pub fn detect(color: ColorChoice) -> Self {
+        let is_tty = std::io::stdout().is_terminal();
+        let size = terminal_size::terminal_size();
+        let width = size
+            .map(|(width, _)| width.0)
+            .or_else(|| env_number("COLUMNS"));
+        let height = size
+            .map(|(_, height)| height.0)
+            .or_else(|| env_number("LINES"));
+
+        let colorterm = std::env::var("COLORTERM").ok();
+        let term = std::env::var("TERM").ok();
+        let color_depth = match color {
+            ColorChoice::Never => ColorDepth::None,
+            ColorChoice::Auto => detect_color_depth(
+                std::env::var("NO_COLOR").ok().as_deref(),
+                colorterm.as_deref(),
+                term.as_deref(),
+                is_tty,
+            ),
+            ColorChoice::Always => {
+                match detect_color_depth(None, colorterm.as_deref(), term.as_deref(), true) {
+                    ColorDepth::None => ColorDepth::TrueColor,
+                    depth => depth,
+                }
+            }
+        };
+
+        TerminalInfo {
+            width,
+            height,
+            color_depth,
+            is_tty,
+        }
+    }
+}
+
+fn env_number(name: &str) -> Option<u16> {
+    std::env::var(name).ok()?.trim().parse().ok()
+}
+
+/// Guess the color depth from the environment.
+///
+/// `NO_COLOR` (if not empty) and output that is not a terminal disable colors. Otherwise `COLORTERM` and `TERM` are used.
+fn detect_color_depth(
+    no_color: Option<&str>,
+    colorterm: Option<&str>,
+    term: Option<&str>,
+    is_tty: bool,
+) -> ColorDepth {
+    if no_color.is_some_and(|value| !value.is_empty()) || !is_tty {
+        return ColorDepth::None;
+    }
+    if matches!(colorterm, Some("truecolor" | "24bit")) {
+        return ColorDepth::TrueColor;
+    }
+    match term {
+        None | Some("") | Some("dumb") => ColorDepth::None,
+        Some(term) if term.contains("256color") => ColorDepth::Ansi256,
+        Some(_) => ColorDepth::Ansi16,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_color_disables_colors() {
+        let depth = detect_color_depth(Some("1"), Some("truecolor"), Some("xterm-256color"), true);
+        assert_eq!(depth, ColorDepth::None);
+    }
+
+    #[test]
+    fn empty_no_color_is_ignored() {
+        let depth = detect_color_depth(Some(""), None, Some("xterm"), true);
+        assert_eq!(depth, ColorDepth::Ansi16);
+    }
+
+    #[test]
+    fn pipes_get_no_colors() {
+        let depth = detect_color_depth(None, Some("truecolor"), Some("xterm-256color"), false);
+        assert_eq!(depth, ColorDepth::None);
+    }
+
+    #[test]
+    fn detects_color_depth_from_term() {
+        assert_eq!(
+            detect_color_depth(None, Some("24bit"), Some("xterm"), true),
+            ColorDepth::TrueColor
+        );
+        assert_eq!(
+            detect_color_depth(None, None, Some("screen-256color"), true),
+            ColorDepth::Ansi256
+        );
+        assert_eq!(
+            detect_color_depth(None, None, Some("dumb"), true),
+            ColorDepth::None
+        );
+    }
+}
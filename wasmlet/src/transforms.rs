@@ -0,0 +1,164 @@
+//! Plugins with several named transforms.
+//!
+//! Besides `process`, a module can export more transforms as `process_<name>`, with the same signature. Their names and descriptions are listed in the `wasmlet-transforms` custom section as `name=description` lines, which the `export_transform!` macro of the SDK creates. A specifier selects a transform after a `#`, like `effects#gradient`.
+//!
+//! If a module has the section, only the transforms listed in it can be selected, so helper exports that happen to start with `process_` stay private. Modules without it offer every export with the prefix.
+
+use serde::Serialize;
+
+use crate::error::LinkError;
+
+/// The name of the custom section.
+pub const SECTION: &str = "wasmlet-transforms";
+
+/// The export of the primary transform, which is used if the specifier does not name one.
+pub const PRIMARY: &str = "process";
+
+/// Named transforms are exported with this prefix.
+const PREFIX: &str = "process_";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Transform {
+    pub name: String,
+    pub description: String,
+}
+
+/// Whether the export is the primary or a named transform.
+pub fn is_transform(export: &str) -> bool {
+    export == PRIMARY || export.starts_with(PREFIX)
+}
+
+/// The export that implements a transform. Without a name, it is the primary transform.
+pub fn export_name(transform: Option<&str>) -> String {
+    match transform {
+        Some(name) => format!("{PREFIX}{name}"),
+        None => PRIMARY.to_string(),
+    }
+}
+
+/// Choose the export to call from the function exports and the transform table of a module.
+///
+/// Without a name, the primary transform is used. If the module does not export one, but offers exactly one named transform, that one is used instead.
+pub fn select(
+    function_exports: &[String],
+    listed: &[Transform],
+    transform: Option<&str>,
+) -> Result<String, LinkError> {
+    let names: Vec<&str> = match listed {
+        [] => function_exports
+            .iter()
+            .filter_map(|export| export.strip_prefix(PREFIX))
+            .collect(),
+        listed => listed
+            .iter()
+            .map(|transform| transform.name.as_str())
+            .collect(),
+    };
+    let available = || match names.as_slice() {
+        [] => "The plugin has no named transforms".to_string(),
+        _ => format!("The plugin has the transforms `{}`", names.join("`, `")),
+    };
+    match transform {
+        Some(name) if names.contains(&name) => Ok(export_name(Some(name))),
+        Some(name) => Err(LinkError::UnknownTransform {
+            transform: name.to_string(),
+            available: available(),
+        }),
+        None if function_exports.iter().any(|export| export == PRIMARY) => Ok(PRIMARY.to_string()),
+        None => match names.as_slice() {
+            [name] => Ok(export_name(Some(*name))),
+            [] => Ok(PRIMARY.to_string()),
+            _ => Err(LinkError::AmbiguousTransform {
+                available: available(),
+            }),
+        },
+    }
+}
+
+/// Parse the transform tables of a module. Every `export_transform!` adds a section of its own.
+pub fn from_sections(sections: &[impl AsRef<[u8]>]) -> Vec<Transform> {
+    sections
+        .iter()
+        .flat_map(|section| parse(section.as_ref()))
+        .collect()
+}
+
+/// Parse the content of a transform table. Lines without a `=` are ignored.
+pub fn parse(section: &[u8]) -> Vec<Transform> {
+    String::from_utf8_lossy(section)
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, description)| Transform {
+            name: name.to_string(),
+            description: description.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exports(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn selects_the_named_transform() {
+        let effects = exports(&["process", "process_rainbow", "process_gradient", "memory"]);
+        assert_eq!(
+            select(&effects, &[], Some("gradient")).unwrap(),
+            "process_gradient"
+        );
+        assert_eq!(select(&effects, &[], None).unwrap(), "process");
+        assert!(matches!(
+            select(&effects, &[], Some("blink")),
+            Err(LinkError::UnknownTransform { .. })
+        ));
+    }
+
+    #[test]
+    fn defaults_to_the_only_transform() {
+        assert_eq!(
+            select(&exports(&["process_gradient"]), &[], None).unwrap(),
+            "process_gradient"
+        );
+        assert!(matches!(
+            select(&exports(&["process_gradient", "process_blink"]), &[], None),
+            Err(LinkError::AmbiguousTransform { .. })
+        ));
+        // The missing `process` is reported when the plugin is instantiated
+        assert_eq!(
+            select(&exports(&["transform"]), &[], None).unwrap(),
+            "process"
+        );
+    }
+
+    #[test]
+    fn only_listed_transforms_can_be_selected() {
+        let effects = exports(&["process_gradient", "process_internal"]);
+        let listed = parse(b"gradient=Colors the text with a gradient\n");
+        assert_eq!(select(&effects, &listed, None).unwrap(), "process_gradient");
+        assert!(matches!(
+            select(&effects, &listed, Some("internal")),
+            Err(LinkError::UnknownTransform { .. })
+        ));
+    }
+
+    #[test]
+    fn parses_the_transform_table() {
+        assert_eq!(
+            parse(b"gradient=Colors the text with a gradient\nblink=\ninvalid\n"),
+            [
+                Transform {
+                    name: "gradient".to_string(),
+                    description: "Colors the text with a gradient".to_string(),
+                },
+                Transform {
+                    name: "blink".to_string(),
+                    description: String::new(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,516 @@
+//! `wasmlet validate`: Check that a module is a well-behaved WASMlet plugin before distributing it.
+//!
+//! The module is inspected first: It needs the exports of the ABI with the right signatures, may only import host functions and should carry metadata. Then it is run with a battery of inputs to look for traps, malformed results and leaks.
+
+use std::process::ExitCode;
+
+use serde::Serialize;
+use wasmer::Type;
+
+use crate::OutputFormat;
+use crate::error::{ErrorCategory, PluginError};
+use crate::host::{HOST_FUNCTIONS, IMPORT_MODULE};
+use crate::interface::{Export, ExportKind, ModuleInterface};
+use crate::leaks;
+use crate::metadata::{self, ABI_VERSION, Metadata};
+use crate::options::{self, OptionSpec};
+use crate::plugin::{Plugin, PluginConfig, load_plugin_source, plugin_name, split_fragment};
+use crate::runtime::{CONFIGURE, INIT, SHUTDOWN};
+use crate::terminal::TerminalInfo;
+use crate::transforms::{self, Transform};
+
+#[derive(clap::Args, Debug)]
+pub struct ValidateArgs {
+    /// The plugin to check. Resolved like the plugins of a pipeline, a transform selected with `#name` is used to check the behavior
+    plugin: String,
+
+    /// The output format
+    #[arg(long, value_enum, default_value_t)]
+    format: OutputFormat,
+}
+
+/// The functions every plugin has to export besides its transforms. They all take and return a single `i32` like the transforms.
+const REQUIRED_FUNCTIONS: [&str; 2] = ["allocate_shared_buffer", "free_shared_buffer"];
+
+/// The lifecycle functions a plugin may export, with their parameters and results.
+const OPTIONAL_FUNCTIONS: [(&str, &[Type], &[Type]); 3] = [
+    (INIT, &[], &[]),
+    (CONFIGURE, &[Type::I32], &[Type::I32]),
+    (SHUTDOWN, &[], &[]),
+];
+
+/// Inputs that every plugin should be able to handle.
+fn test_inputs() -> Vec<(&'static str, String)> {
+    vec![
+        ("empty", String::new()),
+        ("ascii", "Hello World".to_string()),
+        ("non-ascii", "Grüße, 世界! 🦀".to_string()),
+        (
+            "escape codes",
+            "\x1b[1;31mred\x1b[0m \x1b[38;2;0;0;255mblue".to_string(),
+        ),
+        ("huge", "WASMlet ".repeat(32 * 1024)),
+    ]
+}
+
+/// How many times the plugin is called to look for leaks.
+const LEAK_CHECK_CALLS: usize = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    Pass,
+    /// Something that should be fixed, but does not break the plugin.
+    Warn,
+    Fail,
+}
+
+#[derive(Serialize)]
+struct Check {
+    name: String,
+    status: Status,
+    message: String,
+}
+
+#[derive(Serialize, Default)]
+struct ValidationReport {
+    plugin: String,
+    /// The url or path the plugin was loaded from.
+    location: Option<String>,
+    valid: bool,
+    metadata: Option<Metadata>,
+    /// The named transforms listed in the module.
+    transforms: Vec<Transform>,
+    /// The options the module declares.
+    options: Option<Vec<OptionSpec>>,
+    checks: Vec<Check>,
+}
+
+impl ValidationReport {
+    fn check(&mut self, name: impl Into<String>, status: Status, message: impl Into<String>) {
+        self.checks.push(Check {
+            name: name.into(),
+            status,
+            message: message.into(),
+        });
+    }
+
+    fn failures(&self) -> usize {
+        self.checks
+            .iter()
+            .filter(|check| check.status == Status::Fail)
+            .count()
+    }
+}
+
+/// Check that the export is a function that takes and returns a single `i32`.
+fn check_function(name: &str, export: Option<&Export>, report: &mut ValidationReport) {
+    match export.map(|export| &export.kind) {
+        Some(ExportKind::Function(function))
+            if function.params() == [Type::I32] && function.results() == [Type::I32] =>
+        {
+            report.check(format!("export `{name}`"), Status::Pass, "Exported");
+        }
+        Some(ExportKind::Function(function)) => report.check(
+            format!("export `{name}`"),
+            Status::Fail,
+            format!("Has the signature {function}, but needs [I32] -> [I32]"),
+        ),
+        Some(_) => report.check(
+            format!("export `{name}`"),
+            Status::Fail,
+            "Is exported, but not as a function",
+        ),
+        None => report.check(format!("export `{name}`"), Status::Fail, "Missing"),
+    }
+}
+
+/// Check that the transforms in the `wasmlet-transforms` section and the exported ones match.
+fn check_transforms(module: &ModuleInterface, report: &mut ValidationReport) {
+    let exports: Vec<&Export> = module
+        .exports
+        .iter()
+        .filter(|export| transforms::is_transform(&export.name))
+        .collect();
+    if exports.is_empty() {
+        check_function(transforms::PRIMARY, None, report);
+    }
+    for export in &exports {
+        check_function(&export.name, Some(export), report);
+    }
+
+    let listed = transforms::from_sections(&module.custom_sections(transforms::SECTION));
+    for transform in &listed {
+        let export = transforms::export_name(Some(&transform.name));
+        if !exports.iter().any(|candidate| candidate.name == export) {
+            report.check(
+                format!("transform `{}`", transform.name),
+                Status::Fail,
+                format!(
+                    "Listed in the `{}` section, but `{export}` is not exported",
+                    transforms::SECTION
+                ),
+            );
+        }
+    }
+    for export in &exports {
+        let is_listed = listed
+            .iter()
+            .any(|transform| transforms::export_name(Some(&transform.name)) == export.name);
+        if export.name != transforms::PRIMARY && !is_listed {
+            let message = match listed.as_slice() {
+                [] => {
+                    "Not listed with a description, use `wasmlet_sdk::export_transform!` to export it"
+                }
+                _ => "Not listed in the transform table, so it can not be selected",
+            };
+            report.check(format!("export `{}`", export.name), Status::Warn, message);
+        }
+    }
+    report.transforms = listed;
+}
+
+/// Check that the declared options can be passed to `configure` and that their defaults are valid.
+fn check_options(module: &ModuleInterface, report: &mut ValidationReport) {
+    let Some(declared) = options::from_sections(&module.custom_sections(options::SECTION)) else {
+        return;
+    };
+    if module.export(CONFIGURE).is_none() {
+        report.check(
+            "options",
+            Status::Warn,
+            format!(
+                "The plugin declares options, but does not export `{CONFIGURE}` to receive them"
+            ),
+        );
+    }
+    for option in &declared {
+        let Some(default) = &option.default else {
+            continue;
+        };
+        if let Err(help) = option.check(default) {
+            report.check(
+                format!("option `{}`", option.name),
+                Status::Fail,
+                format!("The default `{default}` is invalid. {help}"),
+            );
+        }
+    }
+    report.options = Some(declared);
+}
+
+/// Check the exports, imports and metadata of the module.
+fn check_module(module: &ModuleInterface, report: &mut ValidationReport) {
+    for name in REQUIRED_FUNCTIONS {
+        check_function(name, module.export(name), report);
+    }
+    check_transforms(module, report);
+    check_options(module, report);
+
+    for (name, params, results) in OPTIONAL_FUNCTIONS {
+        match module.export(name).map(|export| &export.kind) {
+            Some(ExportKind::Function(function))
+                if function.params() == params && function.results() == results =>
+            {
+                report.check(format!("export `{name}`"), Status::Pass, "Exported");
+            }
+            Some(_) => report.check(
+                format!("export `{name}`"),
+                Status::Fail,
+                format!("Needs to be a function {params:?} -> {results:?}, or the host ignores it"),
+            ),
+            None => {}
+        }
+    }
+
+    match module.export("memory").map(|export| &export.kind) {
+        Some(ExportKind::Memory) => report.check("export `memory`", Status::Pass, "Exported"),
+        Some(_) => report.check(
+            "export `memory`",
+            Status::Fail,
+            "Is exported, but not as a memory",
+        ),
+        None => report.check("export `memory`", Status::Fail, "Missing"),
+    }
+
+    for import in &module.imports {
+        let name = format!("import `{}::{}`", import.module, import.name);
+        if import.module == IMPORT_MODULE && HOST_FUNCTIONS.contains(&import.name.as_str()) {
+            report.check(name, Status::Pass, "Provided by the host");
+        } else {
+            report.check(
+                name,
+                Status::Fail,
+                "Not provided by the host, plugins can only import the functions in the `wasmlet` module",
+            );
+        }
+    }
+
+    report.metadata = module
+        .custom_sections(metadata::SECTION)
+        .first()
+        .map(|section| Metadata::parse(section));
+    match report.metadata.as_ref().and_then(|metadata| metadata.abi.as_ref()) {
+        Some(abi) if *abi == ABI_VERSION.to_string() => {
+            report.check("abi version", Status::Pass, format!("Version {abi}"))
+        }
+        Some(abi) => report.check(
+            "abi version",
+            Status::Fail,
+            format!("The plugin was built for version {abi}, but this host implements version {ABI_VERSION}"),
+        ),
+        None => report.check(
+            "abi version",
+            Status::Warn,
+            "Not declared, use `wasmlet_sdk::metadata!()` to declare it",
+        ),
+    }
+    match &report.metadata {
+        Some(Metadata {
+            name: Some(name),
+            version: Some(version),
+            ..
+        }) => report.check("metadata", Status::Pass, format!("{name} {version}")),
+        Some(_) => report.check("metadata", Status::Warn, "The name or version is missing"),
+        None => report.check(
+            "metadata",
+            Status::Warn,
+            "Missing, use `wasmlet_sdk::metadata!()` to embed it",
+        ),
+    }
+}
+
+/// Judge the result of running the plugin. Plugins may reject inputs and hit limits, but must not crash or return malformed results.
+fn status_of(result: &Result<String, PluginError>) -> (Status, String) {
+    match result {
+        Ok(output) => (Status::Pass, format!("Returned {} bytes", output.len())),
+        Err(error)
+            if matches!(
+                error.category(),
+                ErrorCategory::Guest | ErrorCategory::LimitExceeded
+            ) =>
+        {
+            (Status::Warn, error.to_string())
+        }
+        Err(error) => (Status::Fail, error.to_string()),
+    }
+}
+
+/// Run the plugin with all test inputs and look for leaks.
+///
+/// Every input gets a fresh instance, so a crash does not affect the following checks.
+fn check_behavior(specifier: &str, report: &mut ValidationReport) {
+    let config = PluginConfig::new(TerminalInfo::STANDARD);
+    let instantiate = |report: &mut ValidationReport| match Plugin::new(specifier, &config)
+        .and_then(|mut plugin| plugin.instantiate().map(|_| plugin))
+    {
+        Ok(plugin) => Some(plugin),
+        Err(error) => {
+            report.check("instantiate", Status::Fail, error.to_string());
+            None
+        }
+    };
+
+    for (name, input) in test_inputs() {
+        let Some(mut plugin) = instantiate(report) else {
+            return;
+        };
+        let (status, message) = status_of(&plugin.apply(&input));
+        report.check(format!("input: {name}"), status, message);
+    }
+
+    let Some(mut plugin) = instantiate(report) else {
+        return;
+    };
+    match leaks::check_leaks(&mut plugin, "Hello World".as_bytes(), LEAK_CHECK_CALLS) {
+        Ok(leaks) if leaks.outstanding_buffers.is_some_and(|buffers| buffers > 0) => report.check(
+            "leaks",
+            Status::Fail,
+            format!(
+                "{} shared buffers were not freed",
+                leaks.outstanding_buffers.unwrap_or_default()
+            ),
+        ),
+        Ok(leaks) if leaks.is_leaking() => report.check(
+            "leaks",
+            Status::Warn,
+            format!(
+                "The memory grows by {:.2} pages per call",
+                leaks.growth_per_call
+            ),
+        ),
+        Ok(_) => report.check(
+            "leaks",
+            Status::Pass,
+            format!("The memory stayed the same over {LEAK_CHECK_CALLS} calls"),
+        ),
+        Err(error) => report.check("leaks", Status::Fail, error.to_string()),
+    }
+}
+
+fn validate_plugin(specifier: &str) -> ValidationReport {
+    let mut report = ValidationReport {
+        plugin: plugin_name(specifier).to_string(),
+        ..Default::default()
+    };
+    if specifier.starts_with("extism:") {
+        report.check(
+            "abi",
+            Status::Fail,
+            "Only plugins with the WASMlet ABI can be validated",
+        );
+        return report;
+    }
+
+    let (source, _) = split_fragment(options::split(specifier).0);
+    let module = match load_plugin_source(source) {
+        Ok(source) => {
+            report.location = Some(source.location.clone());
+            ModuleInterface::load(&source)
+        }
+        Err(error) => {
+            report.check("resolve", Status::Fail, error.to_string());
+            return report;
+        }
+    };
+    let module = match module {
+        Ok(module) => module,
+        Err(error) => {
+            report.check("read", Status::Fail, error.to_string());
+            return report;
+        }
+    };
+
+    check_module(&module, &mut report);
+    if report.failures() > 0 {
+        report.check(
+            "behavior",
+            Status::Fail,
+            "Not checked, because the module does not implement the ABI",
+        );
+        return report;
+    }
+    check_behavior(specifier, &mut report);
+    report
+}
+
+fn print_report(report: &ValidationReport) {
+    println!(
+        "Validating {} ({})",
+        report.plugin,
+        report.location.as_deref().unwrap_or("not found")
+    );
+    for check in &report.checks {
+        let status = match check.status {
+            Status::Pass => "pass",
+            Status::Warn => "warn",
+            Status::Fail => "fail",
+        };
+        println!("  [{status}] {}: {}", check.name, check.message);
+    }
+    match report.failures() {
+        0 => println!("{} is a valid WASMlet plugin", report.plugin),
+        failures => println!("{} failed {failures} checks", report.plugin),
+    }
+}
+
+pub fn validate(args: ValidateArgs) -> ExitCode {
+    let mut report = validate_plugin(&args.plugin);
+    report.valid = report.failures() == 0;
+    match args.format {
+        OutputFormat::Text => print_report(&report),
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&report).expect("The report can always be serialized")
+        ),
+    }
+    if report.valid {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::malicious::{Template, VALID};
+
+    fn check(template: Template) -> ValidationReport {
+        let wasm = wasmer::wat2wasm(template.wat().as_bytes()).unwrap();
+        let mut report = ValidationReport::default();
+        check_module(&ModuleInterface::parse(&wasm).unwrap(), &mut report);
+        report
+    }
+
+    fn status(report: &ValidationReport, name: &str) -> Option<Status> {
+        report
+            .checks
+            .iter()
+            .find(|check| check.name == name)
+            .map(|check| check.status)
+    }
+
+    #[test]
+    fn requires_process() {
+        let report = check(Template {
+            process_name: "run",
+            ..VALID
+        });
+        assert_eq!(status(&report, "export `process`"), Some(Status::Fail));
+    }
+
+    #[test]
+    fn requires_the_signature_of_the_abi() {
+        let report = check(Template {
+            extra: r#"(func (export "process_wide") (param i64) (result i32) (i32.const 0))"#,
+            ..VALID
+        });
+        assert_eq!(status(&report, "export `process`"), Some(Status::Pass));
+        assert_eq!(status(&report, "export `process_wide`"), Some(Status::Fail));
+    }
+
+    #[test]
+    fn rejects_imports_outside_of_the_host() {
+        let report = check(Template {
+            imports: r#"(import "env" "system" (func (param i32) (result i32)))"#,
+            ..VALID
+        });
+        assert_eq!(status(&report, "import `env::system`"), Some(Status::Fail));
+    }
+
+    #[test]
+    fn rejects_other_abi_versions() {
+        let report = check(Template {
+            extra: r#"(@custom "wasmlet-metadata" "abi=2\0aname=future\0aversion=1.0.0")"#,
+            ..VALID
+        });
+        assert_eq!(status(&report, "abi version"), Some(Status::Fail));
+        assert_eq!(status(&report, "metadata"), Some(Status::Pass));
+    }
+
+    #[test]
+    fn reports_valid_modules_as_valid() {
+        let report = check(VALID);
+        assert_eq!(report.failures(), 0);
+        assert_eq!(status(&report, "abi version"), Some(Status::Warn));
+
+        // Enough memory for the bump allocator to take the huge input
+        let valid = Template {
+            memory: r#"(memory (export "memory") 17)"#,
+            extra: r#"(@custom "wasmlet-metadata" "abi=1\0aname=valid\0aversion=1.0.0")"#,
+            ..VALID
+        };
+        let path = std::env::temp_dir().join("wasmlet-validate-valid.wat");
+        std::fs::write(&path, valid.wat()).unwrap();
+        let report = validate_plugin(path.to_str().unwrap());
+        let failures: Vec<_> = report
+            .checks
+            .iter()
+            .filter(|check| check.status != Status::Pass)
+            .map(|check| format!("{}: {}", check.name, check.message))
+            .collect();
+        assert!(failures.is_empty(), "{failures:?}");
+        assert_eq!(status(&report, "leaks"), Some(Status::Pass));
+    }
+}
@@ -0,0 +1,434 @@
+//! The default runtime, [wasmer](https://wasmer.io).
+
+use crate::error::{BackendError, BoxedError, ExecutionError, LinkError, Operation, PluginError};
+use crate::host::{self, HostEnv};
+use crate::plugin::PluginConfig;
+use crate::runtime::{CONFIGURE, GlobalValue, GuestInstance, GuestModule, INIT, SHUTDOWN, Trap};
+use wasmer::sys::{Artifact, EngineBuilder};
+use wasmer::{
+    Engine, Extern, Function, FunctionEnv, FunctionEnvMut, Imports, Instance, Memory, Module,
+    Mutability, RuntimeError, Store, TrapCode, TypedFunction, Value, WasmPtr, imports,
+};
+
+/// Create an engine that compiles plugins with the configured compiler.
+///
+/// With metering enabled or a fuel limit every executed operator costs one point of fuel. Without a compiler the engine can only load precompiled plugins.
+pub fn create_engine(config: &PluginConfig) -> Engine {
+    match config.compiler {
+        #[cfg(feature = "compiler")]
+        Some(compiler) => compiler.engine(config.needs_metering(), config.fuel_limit),
+        _ => EngineBuilder::headless().into(),
+    }
+}
+
+/// Compile a module or load a precompiled one.
+///
+/// Only modules that are marked as `precompiled` are deserialized, see [`PluginSource::precompiled`](crate::plugin::PluginSource::precompiled). Precompiled modules from anywhere else are rejected.
+pub fn compile(
+    engine: &Engine,
+    wasm_bytes: &[u8],
+    precompiled: bool,
+    config: &PluginConfig,
+) -> Result<Module, PluginError> {
+    if precompiled {
+        // SAFETY: Precompiled plugins contain machine code that runs without the checks of the compiler. Only local `.wasmu` files are deserialized, which `wasmlet compile` created from plugins the user trusts, and the archive itself is validated.
+        let module = unsafe { Module::deserialize_checked(engine, wasm_bytes) }
+            .map_err(BackendError::Deserialize)?;
+        if config.needs_metering() && module.exports().all(|e| e.name() != REMAINING_FUEL) {
+            return Err(BackendError::MissingMetering.into());
+        }
+        return Ok(module);
+    }
+    if Artifact::is_deserializable(wasm_bytes) {
+        return Err(BackendError::UntrustedArtifact.into());
+    }
+    if config.compiler.is_none() {
+        return Err(BackendError::NoCompiler.into());
+    }
+    Module::new(engine, wasm_bytes).map_err(|e| PluginError::Compile(e.into()))
+}
+
+/// The globals the metering middleware of wasmer adds to a module. They are accessed directly, so builds without a compiler can meter precompiled plugins.
+const REMAINING_FUEL: &str = "wasmer_metering_remaining_points";
+const FUEL_EXHAUSTED: &str = "wasmer_metering_points_exhausted";
+
+/// The fuel left in the instance. Only works if the module was compiled with metering.
+pub fn remaining_fuel(store: &mut Store, instance: &Instance) -> u64 {
+    let global = |name| {
+        instance
+            .exports
+            .get_global(name)
+            .expect("The module was compiled with metering")
+    };
+    if global(FUEL_EXHAUSTED).get(store).unwrap_i32() != 0 {
+        return 0;
+    }
+    global(REMAINING_FUEL).get(store).unwrap_i64() as u64
+}
+
+pub fn set_remaining_fuel(store: &mut Store, instance: &Instance, fuel: u64) {
+    let global = |name| {
+        instance
+            .exports
+            .get_global(name)
+            .expect("The module was compiled with metering")
+    };
+    global(REMAINING_FUEL)
+        .set(store, Value::I64(fuel as i64))
+        .expect("The metering globals are mutable");
+    global(FUEL_EXHAUSTED)
+        .set(store, Value::I32(0))
+        .expect("The metering globals are mutable");
+}
+
+/// The current size of the exported memory in pages.
+pub fn memory_pages(store: &Store, instance: &Instance) -> Option<u32> {
+    let memory = instance.exports.get_memory("memory").ok()?;
+    Some(memory.view(store).size().0)
+}
+
+/// Convert the value of a global. References can not be restored, so they are skipped.
+fn global_value(value: Value) -> Option<GlobalValue> {
+    match value {
+        Value::I32(value) => Some(GlobalValue::I32(value)),
+        Value::I64(value) => Some(GlobalValue::I64(value)),
+        Value::F32(value) => Some(GlobalValue::F32(value.to_bits())),
+        Value::F64(value) => Some(GlobalValue::F64(value.to_bits())),
+        Value::V128(value) => Some(GlobalValue::V128(value)),
+        _ => None,
+    }
+}
+
+fn wasmer_value(value: GlobalValue) -> Value {
+    match value {
+        GlobalValue::I32(value) => Value::I32(value),
+        GlobalValue::I64(value) => Value::I64(value),
+        GlobalValue::F32(bits) => Value::F32(f32::from_bits(bits)),
+        GlobalValue::F64(bits) => Value::F64(f64::from_bits(bits)),
+        GlobalValue::V128(value) => Value::V128(value),
+    }
+}
+
+/// Convert an error of a wasm call into a trap.
+pub fn trap(error: RuntimeError) -> Trap {
+    let backtrace = error
+        .trace()
+        .iter()
+        .enumerate()
+        .map(|(index, frame)| {
+            host::format_frame(
+                index,
+                frame.function_name(),
+                frame.func_index(),
+                frame.module_name(),
+                frame.module_offset(),
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    Trap {
+        message: error.message(),
+        stack_overflow: error.clone().to_trap() == Some(TrapCode::StackOverflow),
+        backtrace,
+    }
+}
+
+/// The host functions need the memory of the plugin in addition to the shared state.
+struct WasmerHostEnv {
+    env: HostEnv,
+    /// Only available after instantiation.
+    memory: Option<Memory>,
+}
+
+/// Read a string from the guest memory. Invalid utf8 is replaced.
+fn read_guest_string(
+    env: &FunctionEnvMut<WasmerHostEnv>,
+    pointer: WasmPtr<u8>,
+    length: u32,
+) -> Result<String, String> {
+    let memory = env
+        .data()
+        .memory
+        .as_ref()
+        .ok_or("Host functions can not be used during instantiation")?;
+    let view = memory.view(env);
+    let bytes = pointer
+        .slice(&view, length)
+        .and_then(|slice| slice.read_to_vec())
+        .map_err(|e| format!("The plugin passed an invalid string: {e}"))?;
+    Ok(host::guest_string(&bytes))
+}
+
+fn log(
+    env: FunctionEnvMut<WasmerHostEnv>,
+    level: u32,
+    pointer: WasmPtr<u8>,
+    length: u32,
+) -> Result<(), RuntimeError> {
+    env.data()
+        .env
+        .log(level, || read_guest_string(&env, pointer, length))
+        .map_err(RuntimeError::new)
+}
+
+fn panic(
+    mut env: FunctionEnvMut<WasmerHostEnv>,
+    pointer: WasmPtr<u8>,
+    length: u32,
+) -> Result<(), RuntimeError> {
+    let message = read_guest_string(&env, pointer, length).map_err(RuntimeError::new)?;
+    env.data_mut().env.panic(message);
+    Ok(())
+}
+
+fn terminal_width(env: FunctionEnvMut<WasmerHostEnv>) -> u32 {
+    env.data().env.terminal_width()
+}
+
+fn terminal_height(env: FunctionEnvMut<WasmerHostEnv>) -> u32 {
+    env.data().env.terminal_height()
+}
+
+fn terminal_color_depth(env: FunctionEnvMut<WasmerHostEnv>) -> u32 {
+    env.data().env.terminal_color_depth()
+}
+
+fn terminal_is_tty(env: FunctionEnvMut<WasmerHostEnv>) -> u32 {
+    env.data().env.terminal_is_tty()
+}
+
+/// Create the imports for a plugin. They need to match [`host::HOST_FUNCTIONS`].
+fn create_imports(store: &mut Store, env: &FunctionEnv<WasmerHostEnv>) -> Imports {
+    imports! {
+        "wasmlet" => {
+            "log" => Function::new_typed_with_env(store, env, log),
+            "panic" => Function::new_typed_with_env(store, env, panic),
+            "terminal_width" => Function::new_typed_with_env(store, env, terminal_width),
+            "terminal_height" => Function::new_typed_with_env(store, env, terminal_height),
+            "terminal_color_depth" => Function::new_typed_with_env(store, env, terminal_color_depth),
+            "terminal_is_tty" => Function::new_typed_with_env(store, env, terminal_is_tty),
+        }
+    }
+}
+
+/// A WASMlet plugin compiled by wasmer.
+pub struct WasmerModule {
+    engine: Engine,
+    module: Module,
+}
+
+impl WasmerModule {
+    pub fn new(
+        engine: &Engine,
+        wasm_bytes: &[u8],
+        precompiled: bool,
+        config: &PluginConfig,
+    ) -> Result<Self, PluginError> {
+        Ok(WasmerModule {
+            engine: engine.clone(),
+            module: compile(engine, wasm_bytes, precompiled, config)?,
+        })
+    }
+}
+
+impl GuestModule for WasmerModule {
+    fn function_exports(&self) -> Vec<String> {
+        self.module
+            .exports()
+            .functions()
+            .map(|export| export.name().to_string())
+            .collect()
+    }
+
+    fn custom_sections(&self, name: &str) -> Vec<Vec<u8>> {
+        self.module
+            .custom_sections(name)
+            .map(|section| section.to_vec())
+            .collect()
+    }
+
+    fn instantiate(
+        &self,
+        name: &str,
+        process: &str,
+        config: &PluginConfig,
+    ) -> Result<Box<dyn GuestInstance>, PluginError> {
+        let mut store = Store::new(self.engine.clone());
+        let host_env = FunctionEnv::new(
+            &mut store,
+            WasmerHostEnv {
+                env: HostEnv::new(name, config.terminal),
+                memory: None,
+            },
+        );
+        let imports = create_imports(&mut store, &host_env);
+        let instance = Instance::new(&mut store, &self.module, &imports)
+            .map_err(|e| LinkError::Instantiation(Box::new(e)))?;
+
+        let function = |name: &str| {
+            instance
+                .exports
+                .get_typed_function::<u32, u32>(&store, name)
+                .map_err(|e| LinkError::MissingFunction {
+                    name: name.to_string(),
+                    source: e.into(),
+                })
+        };
+        let allocate_shared_buffer = function("allocate_shared_buffer")?;
+        let free_shared_buffer = function("free_shared_buffer")?;
+        let process = function(process)?;
+        let init = instance
+            .exports
+            .get_typed_function::<(), ()>(&store, INIT)
+            .ok();
+        let configure = instance
+            .exports
+            .get_typed_function::<u32, u32>(&store, CONFIGURE)
+            .ok();
+        let shutdown = instance
+            .exports
+            .get_typed_function::<(), ()>(&store, SHUTDOWN)
+            .ok();
+
+        let memory = instance
+            .exports
+            .get_memory("memory")
+            .map_err(|e| LinkError::MissingMemory(e.into()))?
+            .clone();
+        host_env.as_mut(&mut store).memory = Some(memory.clone());
+
+        Ok(Box::new(WasmerInstance {
+            allocate_shared_buffer,
+            free_shared_buffer,
+            process,
+            init,
+            configure,
+            shutdown,
+            store,
+            instance,
+            memory,
+            host_env,
+        }))
+    }
+}
+
+/// An instance of a WASMlet plugin in wasmer.
+struct WasmerInstance {
+    allocate_shared_buffer: TypedFunction<u32, u32>,
+    free_shared_buffer: TypedFunction<u32, u32>,
+    process: TypedFunction<u32, u32>,
+    init: Option<TypedFunction<(), ()>>,
+    configure: Option<TypedFunction<u32, u32>>,
+    shutdown: Option<TypedFunction<(), ()>>,
+    store: Store,
+    instance: Instance,
+    memory: Memory,
+    host_env: FunctionEnv<WasmerHostEnv>,
+}
+
+impl WasmerInstance {
+    fn trap_error(&mut self, error: RuntimeError, operation: Operation) -> ExecutionError {
+        let panic_message = self
+            .host_env
+            .as_mut(&mut self.store)
+            .env
+            .panic_message
+            .take();
+        trap(error).into_error(panic_message, operation)
+    }
+}
+
+impl GuestInstance for WasmerInstance {
+    fn allocate_shared_buffer(&mut self, size: u32) -> Result<u32, ExecutionError> {
+        self.allocate_shared_buffer
+            .call(&mut self.store, size)
+            .map_err(|e| self.trap_error(e, Operation::AllocatingBuffer))
+    }
+
+    fn free_shared_buffer(&mut self, pointer: u32) -> Result<u32, ExecutionError> {
+        self.free_shared_buffer
+            .call(&mut self.store, pointer)
+            .map_err(|e| self.trap_error(e, Operation::FreeingBuffer))
+    }
+
+    fn process(&mut self, input: u32) -> Result<u32, ExecutionError> {
+        self.process
+            .call(&mut self.store, input)
+            .map_err(|e| self.trap_error(e, Operation::Processing))
+    }
+
+    fn init(&mut self) -> Result<(), ExecutionError> {
+        match &self.init {
+            Some(init) => init
+                .call(&mut self.store)
+                .map_err(|e| self.trap_error(e, Operation::Initializing)),
+            None => Ok(()),
+        }
+    }
+
+    fn exports_configure(&self) -> bool {
+        self.configure.is_some()
+    }
+
+    fn configure(&mut self, options: u32) -> Result<u32, ExecutionError> {
+        self.configure
+            .as_ref()
+            .expect("The plugin exports `configure`")
+            .call(&mut self.store, options)
+            .map_err(|e| self.trap_error(e, Operation::Configuring))
+    }
+
+    fn shutdown(&mut self) -> Result<(), ExecutionError> {
+        match &self.shutdown {
+            Some(shutdown) => shutdown
+                .call(&mut self.store)
+                .map_err(|e| self.trap_error(e, Operation::ShuttingDown)),
+            None => Ok(()),
+        }
+    }
+
+    fn memory_size(&mut self) -> u64 {
+        self.memory.view(&self.store).data_size()
+    }
+
+    fn read_memory(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), BoxedError> {
+        Ok(self.memory.view(&self.store).read(offset, buffer)?)
+    }
+
+    fn write_memory(&mut self, offset: u64, data: &[u8]) -> Result<(), BoxedError> {
+        Ok(self.memory.view(&self.store).write(offset, data)?)
+    }
+
+    fn mutable_globals(&mut self) -> Vec<(String, GlobalValue)> {
+        self.instance
+            .exports
+            .iter()
+            .filter_map(|(name, export)| match export {
+                Extern::Global(global)
+                    if ![REMAINING_FUEL, FUEL_EXHAUSTED].contains(&name.as_str())
+                        && global.ty(&self.store).mutability == Mutability::Var =>
+                {
+                    Some((name.clone(), global_value(global.get(&mut self.store))?))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn set_global(&mut self, name: &str, value: GlobalValue) {
+        self.instance
+            .exports
+            .get_global(name)
+            .expect("The global is exported")
+            .set(&mut self.store, wasmer_value(value))
+            .expect("The global is mutable and the value has its type")
+    }
+
+    fn remaining_fuel(&mut self) -> u64 {
+        remaining_fuel(&mut self.store, &self.instance)
+    }
+
+    fn set_remaining_fuel(&mut self, fuel: u64) {
+        set_remaining_fuel(&mut self.store, &self.instance, fuel)
+    }
+}
@@ -0,0 +1,366 @@
+//! The [wasmtime](https://wasmtime.dev) runtime, enabled with the `wasmtime` cargo feature.
+//!
+//! Metering uses the fuel of wasmtime, which charges about one unit per operator like the metering of wasmer. Extism plugins and precompiled plugins need wasmer.
+
+use wasmparser::{Parser, Payload};
+use wasmtime::{
+    Caller, Config, Engine, Instance, Linker, Memory, Module, Mutability, Store,
+    Trap as WasmtimeTrap, TypedFunc, V128, Val, WasmBacktrace,
+};
+
+use crate::error::{BackendError, BoxedError, ExecutionError, LinkError, Operation, PluginError};
+use crate::host::{self, HostEnv};
+use crate::plugin::PluginConfig;
+use crate::runtime::{CONFIGURE, GlobalValue, GuestInstance, GuestModule, INIT, SHUTDOWN, Trap};
+
+/// Create an engine. It only consumes fuel if metering is needed, because that makes plugins slower.
+pub fn create_engine(config: &PluginConfig) -> Engine {
+    let mut wasmtime_config = Config::new();
+    wasmtime_config.consume_fuel(config.needs_metering());
+    Engine::new(&wasmtime_config).expect("The wasmtime configuration is valid")
+}
+
+/// Convert an error of a wasm call into a trap.
+fn trap(error: wasmtime::Error) -> Trap {
+    let backtrace = error
+        .downcast_ref::<WasmBacktrace>()
+        .map(|backtrace| {
+            backtrace
+                .frames()
+                .iter()
+                .enumerate()
+                .map(|(index, frame)| {
+                    host::format_frame(
+                        index,
+                        frame.func_name(),
+                        frame.func_index(),
+                        frame.module().name().unwrap_or("<module>"),
+                        frame.module_offset().unwrap_or(0),
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+        .unwrap_or_default();
+    Trap {
+        message: error.root_cause().to_string(),
+        stack_overflow: error.downcast_ref::<WasmtimeTrap>() == Some(&WasmtimeTrap::StackOverflow),
+        backtrace,
+    }
+}
+
+/// Convert the value of a global. References can not be restored, so they are skipped.
+fn global_value(value: Val) -> Option<GlobalValue> {
+    match value {
+        Val::I32(value) => Some(GlobalValue::I32(value)),
+        Val::I64(value) => Some(GlobalValue::I64(value)),
+        Val::F32(bits) => Some(GlobalValue::F32(bits)),
+        Val::F64(bits) => Some(GlobalValue::F64(bits)),
+        Val::V128(value) => Some(GlobalValue::V128(value.as_u128())),
+        _ => None,
+    }
+}
+
+fn wasmtime_value(value: GlobalValue) -> Val {
+    match value {
+        GlobalValue::I32(value) => Val::I32(value),
+        GlobalValue::I64(value) => Val::I64(value),
+        GlobalValue::F32(bits) => Val::F32(bits),
+        GlobalValue::F64(bits) => Val::F64(bits),
+        GlobalValue::V128(value) => Val::V128(V128::from(value)),
+    }
+}
+
+/// The exported memory of the plugin, which host functions read strings from.
+fn guest_memory(caller: &mut Caller<'_, HostEnv>) -> Option<Memory> {
+    caller
+        .get_export("memory")
+        .and_then(|export| export.into_memory())
+}
+
+/// Read a string from the guest memory. Invalid utf8 is replaced.
+fn read_guest_string(
+    caller: &Caller<'_, HostEnv>,
+    memory: Option<Memory>,
+    pointer: u32,
+    length: u32,
+) -> Result<String, String> {
+    let memory = memory.ok_or("The plugin does not export memory")?;
+    let mut bytes = vec![0; length as usize];
+    memory
+        .read(caller, pointer as usize, &mut bytes)
+        .map_err(|e| format!("The plugin passed an invalid string: {e}"))?;
+    Ok(host::guest_string(&bytes))
+}
+
+/// Create a linker with the host functions. They need to match [`host::HOST_FUNCTIONS`].
+fn create_linker(engine: &Engine) -> wasmtime::Result<Linker<HostEnv>> {
+    let mut linker = Linker::new(engine);
+    let module = host::IMPORT_MODULE;
+    linker.func_wrap(
+        module,
+        "log",
+        |mut caller: Caller<'_, HostEnv>, level: u32, pointer: u32, length: u32| {
+            let memory = guest_memory(&mut caller);
+            caller
+                .data()
+                .log(level, || {
+                    read_guest_string(&caller, memory, pointer, length)
+                })
+                .map_err(wasmtime::Error::msg)
+        },
+    )?;
+    linker.func_wrap(
+        module,
+        "panic",
+        |mut caller: Caller<'_, HostEnv>, pointer: u32, length: u32| {
+            let memory = guest_memory(&mut caller);
+            let message = read_guest_string(&caller, memory, pointer, length)
+                .map_err(wasmtime::Error::msg)?;
+            caller.data_mut().panic(message);
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(module, "terminal_width", |caller: Caller<'_, HostEnv>| {
+        caller.data().terminal_width()
+    })?;
+    linker.func_wrap(module, "terminal_height", |caller: Caller<'_, HostEnv>| {
+        caller.data().terminal_height()
+    })?;
+    linker.func_wrap(
+        module,
+        "terminal_color_depth",
+        |caller: Caller<'_, HostEnv>| caller.data().terminal_color_depth(),
+    )?;
+    linker.func_wrap(module, "terminal_is_tty", |caller: Caller<'_, HostEnv>| {
+        caller.data().terminal_is_tty()
+    })?;
+    Ok(linker)
+}
+
+/// A WASMlet plugin compiled by wasmtime.
+pub struct WasmtimeModule {
+    engine: Engine,
+    module: Module,
+    /// The custom sections of WASMlet. wasmtime does not provide custom sections, so they are read before compiling.
+    custom_sections: Vec<(String, Vec<u8>)>,
+}
+
+impl WasmtimeModule {
+    pub fn new(
+        engine: &Engine,
+        wasm_bytes: &[u8],
+        precompiled: bool,
+        config: &PluginConfig,
+    ) -> Result<Self, PluginError> {
+        if precompiled || wasmer::sys::Artifact::is_deserializable(wasm_bytes) {
+            return Err(BackendError::Unsupported {
+                runtime: config.runtime,
+                feature: "precompiled plugins",
+            }
+            .into());
+        }
+        let module = Module::new(engine, wasm_bytes).map_err(|e| PluginError::Compile(e.into()))?;
+        let custom_sections = Parser::new(0)
+            .parse_all(wasm_bytes)
+            .filter_map(|payload| match payload {
+                Ok(Payload::CustomSection(section)) if section.name().starts_with("wasmlet-") => {
+                    Some((section.name().to_string(), section.data().to_vec()))
+                }
+                _ => None,
+            })
+            .collect();
+        Ok(WasmtimeModule {
+            engine: engine.clone(),
+            module,
+            custom_sections,
+        })
+    }
+}
+
+impl GuestModule for WasmtimeModule {
+    fn function_exports(&self) -> Vec<String> {
+        self.module
+            .exports()
+            .filter(|export| export.ty().func().is_some())
+            .map(|export| export.name().to_string())
+            .collect()
+    }
+
+    fn custom_sections(&self, name: &str) -> Vec<Vec<u8>> {
+        self.custom_sections
+            .iter()
+            .filter(|(section, _)| section == name)
+            .map(|(_, data)| data.clone())
+            .collect()
+    }
+
+    fn instantiate(
+        &self,
+        name: &str,
+        process: &str,
+        config: &PluginConfig,
+    ) -> Result<Box<dyn GuestInstance>, PluginError> {
+        let mut store = Store::new(&self.engine, HostEnv::new(name, config.terminal));
+        if config.needs_metering() {
+            store
+                .set_fuel(config.fuel_limit.unwrap_or(u64::MAX))
+                .expect("The engine consumes fuel");
+        }
+        let linker = create_linker(&self.engine).expect("The host functions have unique names");
+        let instance = linker
+            .instantiate(&mut store, &self.module)
+            .map_err(|e| LinkError::Instantiation(e.into()))?;
+
+        let mut function = |name: &str| {
+            instance
+                .get_typed_func::<u32, u32>(&mut store, name)
+                .map_err(|e| LinkError::MissingFunction {
+                    name: name.to_string(),
+                    source: e.into(),
+                })
+        };
+        let allocate_shared_buffer = function("allocate_shared_buffer")?;
+        let free_shared_buffer = function("free_shared_buffer")?;
+        let process = function(process)?;
+        let init = instance.get_typed_func::<(), ()>(&mut store, INIT).ok();
+        let configure = instance
+            .get_typed_func::<u32, u32>(&mut store, CONFIGURE)
+            .ok();
+        let shutdown = instance.get_typed_func::<(), ()>(&mut store, SHUTDOWN).ok();
+
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| LinkError::MissingMemory("No export named `memory`".into()))?;
+
+        Ok(Box::new(WasmtimeInstance {
+            allocate_shared_buffer,
+            free_shared_buffer,
+            process,
+            init,
+            configure,
+            shutdown,
+            store,
+            instance,
+            memory,
+        }))
+    }
+}
+
+/// An instance of a WASMlet plugin in wasmtime.
+struct WasmtimeInstance {
+    allocate_shared_buffer: TypedFunc<u32, u32>,
+    free_shared_buffer: TypedFunc<u32, u32>,
+    process: TypedFunc<u32, u32>,
+    init: Option<TypedFunc<(), ()>>,
+    configure: Option<TypedFunc<u32, u32>>,
+    shutdown: Option<TypedFunc<(), ()>>,
+    store: Store<HostEnv>,
+    instance: Instance,
+    memory: Memory,
+}
+
+impl WasmtimeInstance {
+    fn trap_error(&mut self, error: wasmtime::Error, operation: Operation) -> ExecutionError {
+        let panic_message = self.store.data_mut().panic_message.take();
+        trap(error).into_error(panic_message, operation)
+    }
+}
+
+impl GuestInstance for WasmtimeInstance {
+    fn allocate_shared_buffer(&mut self, size: u32) -> Result<u32, ExecutionError> {
+        self.allocate_shared_buffer
+            .call(&mut self.store, size)
+            .map_err(|e| self.trap_error(e, Operation::AllocatingBuffer))
+    }
+
+    fn free_shared_buffer(&mut self, pointer: u32) -> Result<u32, ExecutionError> {
+        self.free_shared_buffer
+            .call(&mut self.store, pointer)
+            .map_err(|e| self.trap_error(e, Operation::FreeingBuffer))
+    }
+
+    fn process(&mut self, input: u32) -> Result<u32, ExecutionError> {
+        self.process
+            .call(&mut self.store, input)
+            .map_err(|e| self.trap_error(e, Operation::Processing))
+    }
+
+    fn init(&mut self) -> Result<(), ExecutionError> {
+        match &self.init {
+            Some(init) => init
+                .call(&mut self.store, ())
+                .map_err(|e| self.trap_error(e, Operation::Initializing)),
+            None => Ok(()),
+        }
+    }
+
+    fn exports_configure(&self) -> bool {
+        self.configure.is_some()
+    }
+
+    fn configure(&mut self, options: u32) -> Result<u32, ExecutionError> {
+        self.configure
+            .as_ref()
+            .expect("The plugin exports `configure`")
+            .call(&mut self.store, options)
+            .map_err(|e| self.trap_error(e, Operation::Configuring))
+    }
+
+    fn shutdown(&mut self) -> Result<(), ExecutionError> {
+        match &self.shutdown {
+            Some(shutdown) => shutdown
+                .call(&mut self.store, ())
+                .map_err(|e| self.trap_error(e, Operation::ShuttingDown)),
+            None => Ok(()),
+        }
+    }
+
+    fn memory_size(&mut self) -> u64 {
+        self.memory.data_size(&self.store) as u64
+    }
+
+    fn read_memory(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), BoxedError> {
+        Ok(self.memory.read(&self.store, offset as usize, buffer)?)
+    }
+
+    fn write_memory(&mut self, offset: u64, data: &[u8]) -> Result<(), BoxedError> {
+        Ok(self.memory.write(&mut self.store, offset as usize, data)?)
+    }
+
+    fn mutable_globals(&mut self) -> Vec<(String, GlobalValue)> {
+        let globals: Vec<_> = self
+            .instance
+            .exports(&mut self.store)
+            .filter_map(|export| {
+                let name = export.name().to_string();
+                Some((name, export.into_global()?))
+            })
+            .collect();
+        globals
+            .into_iter()
+            .filter_map(|(name, global)| {
+                if global.ty(&self.store).mutability() != Mutability::Var {
+                    return None;
+                }
+                Some((name, global_value(global.get(&mut self.store))?))
+            })
+            .collect()
+    }
+
+    fn set_global(&mut self, name: &str, value: GlobalValue) {
+        self.instance
+            .get_global(&mut self.store, name)
+            .expect("The global is exported")
+            .set(&mut self.store, wasmtime_value(value))
+            .expect("The global is mutable and the value has its type")
+    }
+
+    fn remaining_fuel(&mut self) -> u64 {
+        self.store.get_fuel().expect("The engine consumes fuel")
+    }
+
+    fn set_remaining_fuel(&mut self, fuel: u64) {
+        self.store.set_fuel(fuel).expect("The engine consumes fuel")
+    }
+}